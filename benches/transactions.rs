@@ -0,0 +1,81 @@
+use chrono::{Duration, TimeZone, Utc};
+use criterion::{criterion_group, criterion_main, Criterion};
+use rusty_store::events::{Event, RecordedEvent};
+use rusty_store::models::{Transaction, TransactionType};
+use rusty_store::store::{Store, StoreData};
+use std::hint::black_box;
+use uuid::Uuid;
+
+const TRANSACTIONS: usize = 1_000_000;
+const PRODUCTS: usize = 1_000;
+const USERS: usize = 10;
+
+// A million sales and purchases, one a minute, spread over a thousand products
+// and ten users. Built by replaying events, as loading a saved store does.
+fn large_store() -> (Store, Uuid) {
+    let start = Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+    let products: Vec<Uuid> = (0..PRODUCTS).map(|_| Uuid::new_v4()).collect();
+    let mut events: Vec<Event> = products.iter()
+        .enumerate()
+        .map(|(i, id)| Event::ProductCreated {
+            id: *id,
+            name: format!("Product {}", i),
+            description: String::new(),
+            price: 10.0,
+            sku: None,
+            opening_stock: None,
+        })
+        .collect();
+    events.extend((0..TRANSACTIONS).map(|i| {
+        let transaction = Transaction {
+            id: Uuid::new_v4(),
+            product_id: products[i % PRODUCTS],
+            quantity: 1,
+            price: 10.0,
+            transaction_type: if i % 3 == 0 { TransactionType::Purchase } else { TransactionType::Sale },
+            timestamp: start + Duration::minutes(i as i64),
+            prev_hash: None,
+            user: Some(format!("user{}", i % USERS)),
+        };
+        Event::stock(transaction)
+    }));
+    let events = events.into_iter()
+        .enumerate()
+        .map(|(i, event)| RecordedEvent { seq: i as u64 + 1, timestamp: start, event })
+        .collect();
+    (Store::from_data(StoreData { events, ..StoreData::default() }), products[0])
+}
+
+fn bench_lookups(c: &mut Criterion) {
+    let (store, product_id) = large_store();
+    let from = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+    let to = from + Duration::days(1);
+
+    let mut group = c.benchmark_group("product history");
+    group.bench_function("indexed", |b| b.iter(|| store.product_transactions(black_box(&product_id)).len()));
+    group.bench_function("scan", |b| b.iter(|| {
+        store.transactions().iter().filter(|t| t.product_id == *black_box(&product_id)).count()
+    }));
+    group.finish();
+
+    let mut group = c.benchmark_group("one day");
+    group.bench_function("indexed", |b| b.iter(|| store.transactions_between(black_box(from), black_box(to)).len()));
+    group.bench_function("scan", |b| b.iter(|| {
+        store.transactions().iter().filter(|t| t.timestamp >= black_box(from) && t.timestamp < black_box(to)).count()
+    }));
+    group.finish();
+
+    let mut group = c.benchmark_group("user history");
+    group.bench_function("indexed", |b| b.iter(|| store.user_transactions(black_box("user3")).len()));
+    group.bench_function("scan", |b| b.iter(|| {
+        store.transactions().iter().filter(|t| t.user.as_deref() == Some(black_box("user3"))).count()
+    }));
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_lookups
+}
+criterion_main!(benches);
@@ -1,48 +1,51 @@
 use crate::models::{User, UserRole};
+use crate::config::Settings;
+use crate::crypto::Cipher;
 use crate::errors::StoreError;
 use bcrypt::{hash, verify, DEFAULT_COST};
 use std::collections::HashMap;
 use uuid::Uuid;
-use serde::{Serialize, Deserialize};
-use std::fs::{File, OpenOptions};
+use crate::storage::Storage;
 
-#[derive(Serialize, Deserialize)]
 pub struct Auth {
     users: HashMap<String, User>,
     current_user: Option<User>,
+    storage: Box<dyn Storage>,
 }
 
 impl Auth {
-    pub fn new() -> Self {
-        let auth = Auth {
+    pub fn new() -> Result<Self, StoreError> {
+        Auth::with_settings(&Settings::default(), None)
+    }
+
+    /// Keeps users in the configured data directory, encrypted with `cipher` if given.
+    pub fn with_settings(settings: &Settings, cipher: Option<Cipher>) -> Result<Self, StoreError> {
+        Auth::with_storage(Box::new(settings.json_storage().with_cipher(cipher)))
+    }
+
+    /// Loads users from `storage`, creating the default admin on a fresh install.
+    /// Unreadable user data is an error rather than a reason to start over.
+    pub fn with_storage(storage: Box<dyn Storage>) -> Result<Self, StoreError> {
+        let mut auth = Auth {
             users: HashMap::new(),
             current_user: None,
+            storage,
         };
 
         // Try to load existing users
-        match File::open("users.json") {
-            Ok(file) => {
-                match serde_json::from_reader(file) {
-                    Ok(users) => {
-                        println!("Loaded existing users");
-                        return Auth {
-                            users,
-                            current_user: None,
-                        };
-                    },
-                    Err(e) => {
-                        println!("Error reading users.json, creating new file: {}", e);
-                    }
-                }
+        match auth.storage.load_users()? {
+            Some(users) => {
+                println!("Loaded existing users");
+                auth.users = users;
+                return Ok(auth);
+            },
+            None => {
+                println!("No saved users found, creating new user list");
             },
-            Err(e) => {
-                println!("No users.json found, creating new file: {}", e);
-            }
         }
 
         // Create default admin user for new installation
-        let mut new_auth = auth;
-        if let Err(e) = new_auth.register(
+        if let Err(e) = auth.register(
             "admin".to_string(),
             "admin123".to_string(),
             UserRole::Manager,
@@ -52,7 +55,7 @@ impl Auth {
             println!("Created default admin user");
         }
 
-        new_auth
+        Ok(auth)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -85,23 +88,12 @@ impl Auth {
         Ok(())
     }
 
-    fn save_users(&self) -> Result<(), StoreError> {
-        println!("Saving users to file...");
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open("users.json")
-            .map_err(|e| {
-                println!("Error creating/opening users.json: {}", e);
-                StoreError::DatabaseError(e.to_string())
-            })?;
-
-        serde_json::to_writer_pretty(file, &self.users)
-            .map_err(|e| {
-                println!("Error writing users to file: {}", e);
-                StoreError::DatabaseError(e.to_string())
-            })?;
+    fn save_users(&mut self) -> Result<(), StoreError> {
+        println!("Saving users...");
+        self.storage.save_users(&self.users).map_err(|e| {
+            println!("Error saving users: {}", e);
+            e
+        })?;
 
         println!("Users saved successfully");
         Ok(())
@@ -109,7 +101,6 @@ impl Auth {
 
     pub fn login(&mut self, username: &str, password: &str) -> Result<(), StoreError> {
         println!("Attempting login for user: {}", username);
-        println!("Available users: {:?}", self.users.keys().collect::<Vec<_>>());
 
         let user = self.users.get(username).ok_or_else(|| {
             println!("User not found: {}", username);
@@ -0,0 +1,100 @@
+use crate::errors::StoreError;
+
+// Code 39 patterns: nine elements alternating bar/space, starting with a bar.
+// A set bit marks a wide element.
+const CODE39: [(char, u16); 44] = [
+    ('0', 0b000110100), ('1', 0b100100001), ('2', 0b001100001), ('3', 0b101100000),
+    ('4', 0b000110001), ('5', 0b100110000), ('6', 0b001110000), ('7', 0b000100101),
+    ('8', 0b100100100), ('9', 0b001100100), ('A', 0b100001001), ('B', 0b001001001),
+    ('C', 0b101001000), ('D', 0b000011001), ('E', 0b100011000), ('F', 0b001011000),
+    ('G', 0b000001101), ('H', 0b100001100), ('I', 0b001001100), ('J', 0b000011100),
+    ('K', 0b100000011), ('L', 0b001000011), ('M', 0b101000010), ('N', 0b000010011),
+    ('O', 0b100010010), ('P', 0b001010010), ('Q', 0b000000111), ('R', 0b100000110),
+    ('S', 0b001000110), ('T', 0b000010110), ('U', 0b110000001), ('V', 0b011000001),
+    ('W', 0b111000000), ('X', 0b010010001), ('Y', 0b110010000), ('Z', 0b011010000),
+    ('-', 0b010000101), ('.', 0b110000100), (' ', 0b011000100), ('$', 0b010101000),
+    ('/', 0b010100010), ('+', 0b010001010), ('%', 0b000101010), ('*', 0b010010100),
+];
+
+const WIDE: usize = 3;
+
+/// A Code 39 barcode expanded into modules, where `true` is a dark bar.
+#[derive(Debug, Clone)]
+pub struct Barcode {
+    pub data: String,
+    modules: Vec<bool>,
+}
+
+impl Barcode {
+    pub fn code39(data: &str) -> Result<Self, StoreError> {
+        let data = data.to_uppercase();
+        if data.is_empty() {
+            return Err(StoreError::InvalidInput("Barcode data cannot be empty".to_string()));
+        }
+        if data.contains('*') {
+            return Err(StoreError::InvalidInput("'*' is reserved in Code 39".to_string()));
+        }
+
+        let mut modules = Vec::new();
+        for (i, c) in std::iter::once('*').chain(data.chars()).chain(std::iter::once('*')).enumerate() {
+            let pattern = CODE39.iter()
+                .find(|(ch, _)| *ch == c)
+                .map(|(_, p)| *p)
+                .ok_or_else(|| StoreError::InvalidInput(format!("Character '{}' cannot be encoded in Code 39", c)))?;
+
+            if i > 0 {
+                modules.push(false);
+            }
+            for element in 0..9 {
+                let wide = pattern & (1 << (8 - element)) != 0;
+                let width = if wide { WIDE } else { 1 };
+                modules.extend(std::iter::repeat_n(element % 2 == 0, width));
+            }
+        }
+
+        Ok(Barcode { data, modules })
+    }
+
+    pub fn modules(&self) -> &[bool] {
+        &self.modules
+    }
+
+    /// Renders the barcode as block characters for display in a terminal.
+    pub fn to_text(&self) -> String {
+        self.modules.iter().map(|&dark| if dark { '█' } else { ' ' }).collect()
+    }
+
+    pub fn to_svg(&self, module_width: u32, height: u32) -> String {
+        let width = self.modules.len() as u32 * module_width;
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+            width, height, width, height
+        );
+        svg.push_str(&format!("<rect width=\"{}\" height=\"{}\" fill=\"#fff\"/>", width, height));
+        for (x, bar) in self.bars() {
+            svg.push_str(&format!(
+                "<rect x=\"{}\" width=\"{}\" height=\"{}\" fill=\"#000\"/>",
+                x as u32 * module_width, bar as u32 * module_width, height
+            ));
+        }
+        svg.push_str("</svg>");
+        svg
+    }
+
+    /// Returns each dark bar as (start module, width in modules).
+    pub fn bars(&self) -> Vec<(usize, usize)> {
+        let mut bars = Vec::new();
+        let mut start = None;
+        for (i, &dark) in self.modules.iter().chain(std::iter::once(&false)).enumerate() {
+            match (dark, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    bars.push((s, i - s));
+                    start = None;
+                },
+                _ => {},
+            }
+        }
+        bars
+    }
+}
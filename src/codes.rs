@@ -0,0 +1,31 @@
+use uuid::Uuid;
+
+// No 0/O or 1/I so codes survive being read out over the counter
+const ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// Generates a random code like `GC-7KQ2-M9XD-R4TB` from the OS random source behind `Uuid::new_v4`.
+pub fn random_code(prefix: &str, groups: usize) -> String {
+    let mut code = String::from(prefix);
+    let mut bytes = Vec::new();
+    for _ in 0..groups {
+        code.push('-');
+        for _ in 0..4 {
+            if bytes.is_empty() {
+                // Bytes 6 and 8 carry the fixed version and variant bits
+                bytes = Uuid::new_v4().as_bytes().iter().enumerate()
+                    .filter(|(i, _)| *i != 6 && *i != 8)
+                    .map(|(_, b)| *b)
+                    .collect();
+            }
+            let byte = bytes.pop().unwrap_or_default();
+            code.push(ALPHABET[byte as usize % ALPHABET.len()] as char);
+        }
+    }
+    code
+}
+
+/// Masks all but the last four characters of a code for printing on receipts.
+pub fn mask(code: &str) -> String {
+    let visible: String = code.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+    format!("****{}", visible)
+}
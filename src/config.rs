@@ -0,0 +1,283 @@
+use crate::crypto::Cipher;
+use crate::integrity::Signer;
+use crate::errors::StoreError;
+use crate::retention::{ArchiveDir, Period, ARCHIVE_DIR};
+use crate::snapshot::StoreFormat;
+use crate::storage::{JsonStorage, DEFAULT_BACKUPS, DEFAULT_USERS_FILE};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_FILE: &str = "rusty_store.toml";
+
+/// Settings that `apply_overrides` looks up, by name.
+const OVERRIDE_KEYS: [&str; 10] = [
+    "data_dir", "store_name", "currency", "timezone", "key_file", "signing_key_file",
+    "store_format", "backups", "retention_days", "retention_period",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupSettings {
+    /// Previous versions kept of each data file; 0 turns backups off.
+    pub keep: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings { keep: DEFAULT_BACKUPS }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    /// Days of transactions kept in the store; older ones are archived by
+    /// `rusty_store archive`. Unset keeps everything.
+    pub keep_days: Option<u32>,
+    /// Periods that archived transactions are summarised by.
+    pub period: Period,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings { keep_days: None, period: Period::Month }
+    }
+}
+
+/// Settings read from `rusty_store.toml`, then overridden by `RUSTY_STORE_*`
+/// environment variables, then by command line flags.
+///
+/// ```toml
+/// data_dir = "/var/lib/rusty-store"
+/// store_name = "Corner Shop"
+/// currency = "£"
+/// timezone = "Europe/London"
+/// key_file = "store.key"
+/// signing_key_file = "signing.key"
+/// store_format = "binary"
+///
+/// [backup]
+/// keep = 10
+///
+/// [retention]
+/// keep_days = 365
+/// period = "month"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Where every data file lives. Relative paths in a config file are taken
+    /// relative to that file, not the current directory.
+    pub data_dir: PathBuf,
+    pub store_name: String,
+    /// Symbol printed before amounts.
+    pub currency: String,
+    /// IANA zone used when showing dates; everything is stored in UTC.
+    pub timezone: Tz,
+    /// Key file for encrypting data files. Like `data_dir`, relative to the config file.
+    pub key_file: Option<PathBuf>,
+    /// Key file for signing the store file so tampering can be detected. Also
+    /// relative to the config file.
+    pub signing_key_file: Option<PathBuf>,
+    /// `binary` keeps the store in `store.bin`, which loads faster than `store.json`.
+    pub store_format: StoreFormat,
+    pub backup: BackupSettings,
+    pub retention: RetentionSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            data_dir: PathBuf::from("."),
+            store_name: "Rusty Store".to_string(),
+            currency: "$".to_string(),
+            timezone: Tz::UTC,
+            key_file: None,
+            signing_key_file: None,
+            store_format: StoreFormat::Json,
+            backup: BackupSettings::default(),
+            retention: RetentionSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads a config file. Unknown keys are rejected so typos do not go unnoticed.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path.display(), e)))?;
+        let mut settings: Settings = toml::from_str(&text)
+            .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path.display(), e)))?;
+
+        if let Some(dir) = path.parent() {
+            settings.data_dir = dir.join(&settings.data_dir);
+            settings.key_file = settings.key_file.map(|k| dir.join(k));
+            settings.signing_key_file = settings.signing_key_file.map(|k| dir.join(k));
+        }
+        Ok(settings)
+    }
+
+    /// Builds the settings for a run: the config file named by `--config` or
+    /// `RUSTY_STORE_CONFIG`, else `./rusty_store.toml`, else the per-user
+    /// config file, else defaults; then environment and command line overrides.
+    pub fn load(args: &[String]) -> Result<Self, StoreError> {
+        let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let explicit = flag_value(args, "--config")?.map(PathBuf::from)
+            .or_else(|| env("RUSTY_STORE_CONFIG").map(PathBuf::from));
+
+        let mut settings = match explicit {
+            Some(path) => Settings::from_file(path)?,
+            None => match default_config_paths().into_iter().find(|p| p.is_file()) {
+                Some(path) => Settings::from_file(path)?,
+                None => Settings::default(),
+            },
+        };
+
+        settings.apply_overrides(|key| env(&format!("RUSTY_STORE_{}", key.to_uppercase())))?;
+        // Read every flag first, so one given without its value is an error
+        let mut flags = std::collections::HashMap::new();
+        for key in OVERRIDE_KEYS {
+            if let Some(value) = flag_value(args, &format!("--{}", key.replace('_', "-")))? {
+                flags.insert(key, value.to_string());
+            }
+        }
+        settings.apply_overrides(|key| flags.get(key).cloned())?;
+        Ok(settings)
+    }
+
+    /// Overrides any setting `lookup` has a value for, keyed by setting name
+    /// (`data_dir`, `store_name`, `currency`, `timezone`, `key_file`, `signing_key_file`,
+    /// `store_format`, `backups`, `retention_days`, `retention_period`).
+    pub fn apply_overrides<F>(&mut self, lookup: F) -> Result<(), StoreError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(dir) = lookup("data_dir") {
+            self.data_dir = PathBuf::from(dir);
+        }
+        if let Some(name) = lookup("store_name") {
+            self.store_name = name;
+        }
+        if let Some(currency) = lookup("currency") {
+            self.currency = currency;
+        }
+        if let Some(timezone) = lookup("timezone") {
+            self.timezone = timezone.parse()
+                .map_err(|_| StoreError::InvalidInput(format!("Unknown timezone '{}'", timezone)))?;
+        }
+        if let Some(key_file) = lookup("key_file") {
+            self.key_file = Some(PathBuf::from(key_file));
+        }
+        if let Some(signing_key_file) = lookup("signing_key_file") {
+            self.signing_key_file = Some(PathBuf::from(signing_key_file));
+        }
+        if let Some(format) = lookup("store_format") {
+            self.store_format = format.parse()?;
+        }
+        if let Some(keep) = lookup("backups") {
+            self.backup.keep = keep.parse()
+                .map_err(|_| StoreError::InvalidInput(format!("Backup count '{}' is not a number", keep)))?;
+        }
+        if let Some(days) = lookup("retention_days") {
+            self.retention.keep_days = Some(days.parse()
+                .map_err(|_| StoreError::InvalidInput(format!("Retention days '{}' is not a number", days)))?);
+        }
+        if let Some(period) = lookup("retention_period") {
+            self.retention.period = period.parse()?;
+        }
+        Ok(())
+    }
+
+    /// Path of a data file inside `data_dir`.
+    pub fn data_file(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.data_dir.join(name)
+    }
+
+    /// The cipher for data files: the configured key file, else a passphrase
+    /// from `RUSTY_STORE_PASSPHRASE`, else none.
+    pub fn cipher(&self) -> Result<Option<Cipher>, StoreError> {
+        if let Some(path) = &self.key_file {
+            return Cipher::from_key_file(path).map(Some);
+        }
+        match std::env::var("RUSTY_STORE_PASSPHRASE") {
+            Ok(passphrase) if !passphrase.is_empty() => Cipher::from_passphrase(&passphrase).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The signer for the store file, if a signing key file is configured.
+    pub fn signer(&self) -> Result<Option<Signer>, StoreError> {
+        self.signing_key_file.as_ref().map(Signer::from_key_file).transpose()
+    }
+
+    /// File storage for the store and users files in `data_dir`, with the store
+    /// file in the configured format. If there is no store file in that format
+    /// but there is one in the other, `store_format` was changed without
+    /// converting the store; that file is used, in its own format, rather than
+    /// starting an empty store.
+    pub fn json_storage(&self) -> JsonStorage {
+        let other = match self.store_format {
+            StoreFormat::Json => StoreFormat::Binary,
+            StoreFormat::Binary => StoreFormat::Json,
+        };
+        if !self.data_file(self.store_format.file_name()).exists() && self.data_file(other.file_name()).exists() {
+            return self.storage_in_format(other);
+        }
+        self.storage_in_format(self.store_format)
+    }
+
+    /// Like `json_storage`, but for the store file in `format`.
+    pub fn storage_in_format(&self, format: StoreFormat) -> JsonStorage {
+        JsonStorage::new(self.data_file(format.file_name()), self.data_file(DEFAULT_USERS_FILE))
+            .with_backups(self.backup.keep)
+            .with_format(format)
+    }
+
+    /// Archive files in `data_dir`, encrypted with `cipher` if given.
+    pub fn archive_dir(&self, cipher: Option<Cipher>) -> ArchiveDir {
+        ArchiveDir::new(self.data_file(ARCHIVE_DIR)).with_cipher(cipher)
+    }
+
+    pub fn format_money(&self, amount: f64) -> String {
+        format_money(&self.currency, amount)
+    }
+
+    pub fn format_time(&self, at: DateTime<Utc>) -> String {
+        format_time(&self.timezone, at)
+    }
+}
+
+pub fn format_money(currency: &str, amount: f64) -> String {
+    if amount < 0.0 {
+        format!("-{}{:.2}", currency, -amount)
+    } else {
+        format!("{}{:.2}", currency, amount)
+    }
+}
+
+pub fn format_time(timezone: &Tz, at: DateTime<Utc>) -> String {
+    at.with_timezone(timezone).format("%Y-%m-%d %H:%M %Z").to_string()
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>, StoreError> {
+    match args.iter().position(|a| a == flag) {
+        Some(i) => args.get(i + 1)
+            .map(|v| Some(v.as_str()))
+            .ok_or_else(|| StoreError::InvalidInput(format!("{} needs a value", flag))),
+        None => Ok(None),
+    }
+}
+
+fn default_config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(DEFAULT_CONFIG_FILE)];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = config_home {
+        paths.push(dir.join("rusty_store").join("config.toml"));
+    }
+    paths
+}
@@ -0,0 +1,192 @@
+use crate::codes;
+use crate::config::format_money;
+use crate::errors::StoreError;
+use crate::models::{Coupon, CouponCode, CouponRedemption, DiscountType, InvoiceLine};
+use crate::store::round_cents;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Most codes generated in one go, so a mistyped count cannot flood the store.
+pub const MAX_GENERATED_CODES: usize = 1000;
+
+/// Coupon definitions, the codes handed out for them, and every redemption.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct CouponBook {
+    coupons: HashMap<Uuid, Coupon>,
+    codes: HashMap<String, CouponCode>,
+    redemptions: Vec<CouponRedemption>,
+}
+
+impl CouponBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn validate(&self, coupon: &Coupon) -> Result<(), StoreError> {
+        match coupon.discount {
+            DiscountType::Percentage(p) if p <= 0.0 || p > 100.0 => {
+                return Err(StoreError::InvalidInput("Percentage must be between 0 and 100".to_string()));
+            },
+            DiscountType::FixedAmount(a) if a <= 0.0 => {
+                return Err(StoreError::InvalidInput("Discount amount must be positive".to_string()));
+            },
+            _ => {},
+        }
+        if let Some(until) = coupon.valid_until {
+            if until <= coupon.valid_from {
+                return Err(StoreError::InvalidInput("Coupon expires before it starts".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&Coupon> {
+        self.coupons.get(id)
+    }
+
+    pub fn coupons(&self) -> impl Iterator<Item = &Coupon> {
+        self.coupons.values()
+    }
+
+    /// Builds a memorable code such as `SUMMER10` for a coupon.
+    pub fn prepare_code(&self, coupon_id: Uuid, code: &str, max_uses: Option<u32>) -> Result<CouponCode, StoreError> {
+        if !self.coupons.contains_key(&coupon_id) {
+            return Err(StoreError::NotFound);
+        }
+        let code = normalize(code);
+        if code.is_empty() || self.codes.contains_key(&code) {
+            return Err(StoreError::InvalidInput(format!("Coupon code '{}' is not available", code)));
+        }
+
+        Ok(CouponCode { code, coupon_id, max_uses })
+    }
+
+    /// Generates `count` random codes that are unique across every coupon.
+    pub fn prepare_codes(&self, coupon_id: Uuid, count: usize, max_uses: Option<u32>) -> Result<Vec<CouponCode>, StoreError> {
+        if !self.coupons.contains_key(&coupon_id) {
+            return Err(StoreError::NotFound);
+        }
+        if count > MAX_GENERATED_CODES {
+            return Err(StoreError::InvalidInput(format!(
+                "Cannot generate more than {} codes at once", MAX_GENERATED_CODES
+            )));
+        }
+
+        let mut generated: HashMap<String, CouponCode> = HashMap::with_capacity(count);
+        while generated.len() < count {
+            let code = codes::random_code("CP", 2);
+            if self.codes.contains_key(&code) || generated.contains_key(&code) {
+                continue;
+            }
+            generated.insert(code.clone(), CouponCode { code, coupon_id, max_uses });
+        }
+        Ok(generated.into_values().collect())
+    }
+
+    pub(crate) fn apply_coupon(&mut self, coupon: Coupon) {
+        self.coupons.insert(coupon.id, coupon);
+    }
+
+    pub(crate) fn apply_code(&mut self, code: CouponCode) {
+        self.codes.insert(code.code.clone(), code);
+    }
+
+    pub(crate) fn apply_redemption(&mut self, redemption: CouponRedemption) {
+        self.redemptions.push(redemption);
+    }
+
+    pub fn get_code(&self, code: &str) -> Option<&CouponCode> {
+        self.codes.get(&normalize(code))
+    }
+
+    /// Works out the discount `code` gives on `lines`, or why it cannot be used.
+    /// `currency` is for the error messages.
+    pub fn evaluate(
+        &self,
+        code: &str,
+        lines: &[InvoiceLine],
+        customer_id: Option<Uuid>,
+        now: DateTime<Utc>,
+        currency: &str,
+    ) -> Result<f64, StoreError> {
+        let code = normalize(code);
+        let coupon_code = self.codes.get(&code)
+            .ok_or_else(|| StoreError::InvalidInput(format!("Unknown coupon code '{}'", code)))?;
+        let coupon = self.coupons.get(&coupon_code.coupon_id).ok_or(StoreError::NotFound)?;
+
+        if now < coupon.valid_from || coupon.valid_until.map(|u| now >= u).unwrap_or(false) {
+            return Err(StoreError::InvalidInput(format!("Coupon '{}' is not valid today", code)));
+        }
+
+        let code_uses = self.redemptions.iter().filter(|r| r.code == code).count() as u32;
+        if coupon_code.max_uses.map(|max| code_uses >= max).unwrap_or(false) {
+            return Err(StoreError::InvalidInput(format!("Coupon '{}' has already been used", code)));
+        }
+
+        let coupon_uses = self.redemptions.iter().filter(|r| r.coupon_id == coupon.id).count() as u32;
+        if coupon.max_redemptions.map(|max| coupon_uses >= max).unwrap_or(false) {
+            return Err(StoreError::InvalidInput(format!("Coupon '{}' is fully redeemed", coupon.name)));
+        }
+
+        if let Some(max) = coupon.max_per_customer {
+            let customer_id = customer_id.ok_or_else(|| {
+                StoreError::InvalidInput(format!("Coupon '{}' requires a customer", coupon.name))
+            })?;
+            let customer_uses = self.redemptions.iter()
+                .filter(|r| r.coupon_id == coupon.id && r.customer_id == Some(customer_id))
+                .count() as u32;
+            if customer_uses >= max {
+                return Err(StoreError::InvalidInput(format!(
+                    "Customer has already used coupon '{}' {} time(s)", coupon.name, customer_uses
+                )));
+            }
+        }
+
+        let subtotal: f64 = lines.iter().map(|l| l.total).sum();
+        if subtotal < coupon.min_spend {
+            return Err(StoreError::InvalidInput(format!(
+                "Coupon '{}' needs a minimum spend of {}", coupon.name, format_money(currency, coupon.min_spend)
+            )));
+        }
+
+        let eligible: f64 = lines.iter()
+            .filter(|l| coupon.product_ids.is_empty() || coupon.product_ids.contains(&l.product_id))
+            .map(|l| l.total)
+            .sum();
+        if eligible <= 0.0 {
+            return Err(StoreError::InvalidInput(format!("Coupon '{}' does not apply to these items", coupon.name)));
+        }
+
+        let discount = match coupon.discount {
+            DiscountType::Percentage(p) => eligible * p / 100.0,
+            DiscountType::FixedAmount(a) => a.min(eligible),
+        };
+        Ok(round_cents(discount))
+    }
+
+    pub fn redemptions(&self) -> &[CouponRedemption] {
+        &self.redemptions
+    }
+
+    pub fn generate_redemption_report(&self, currency: &str) -> String {
+        let mut report = String::from("Coupon Redemption Report\n========================\n\n");
+        let mut coupons: Vec<&Coupon> = self.coupons.values().collect();
+        coupons.sort_by_key(|c| c.valid_from);
+
+        for coupon in coupons {
+            let redemptions: Vec<_> = self.redemptions.iter().filter(|r| r.coupon_id == coupon.id).collect();
+            let issued = self.codes.values().filter(|c| c.coupon_id == coupon.id).count();
+            let total: f64 = redemptions.iter().map(|r| r.discount).sum();
+            report.push_str(&format!("Coupon: {}\nCodes Issued: {}\nRedemptions: {}\nTotal Discount: {}\n\n",
+                coupon.name, issued, redemptions.len(), format_money(currency, total)));
+        }
+        report
+    }
+}
+
+pub(crate) fn normalize(code: &str) -> String {
+    code.trim().to_uppercase()
+}
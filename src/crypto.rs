@@ -0,0 +1,189 @@
+use crate::errors::StoreError;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Every encrypted file starts with this, so plain files are still recognised.
+pub const MAGIC: &[u8; 6] = b"RSENC\x01";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const CHECK_LEN: usize = 8;
+const SALT_END: usize = MAGIC.len() + 1 + SALT_LEN;
+const HEADER_LEN: usize = SALT_END + CHECK_LEN;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum KeyKind {
+    KeyFile = 0,
+    Passphrase = 1,
+}
+
+/// Authenticated encryption for data files: XChaCha20-Poly1305 with a key read
+/// from a key file or derived from a passphrase with Argon2id.
+///
+/// Layout: magic, key kind, salt, key check, nonce, ciphertext. The header is
+/// authenticated along with the data, so any change is caught on decryption.
+/// The key check is a hash of the key, so a file that fails to decrypt under a
+/// key that matches it is known to be damaged rather than opened with the
+/// wrong key.
+#[derive(Clone)]
+pub struct Cipher {
+    kind: KeyKind,
+    passphrase: Option<String>,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+    // Keys derived for salts written by earlier sessions
+    derived: Arc<Mutex<HashMap<[u8; SALT_LEN], [u8; 32]>>>,
+}
+
+impl Cipher {
+    pub fn from_passphrase(passphrase: &str) -> Result<Self, StoreError> {
+        if passphrase.is_empty() {
+            return Err(StoreError::InvalidInput("Passphrase cannot be empty".to_string()));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive(passphrase, &salt)?;
+
+        Ok(Cipher {
+            kind: KeyKind::Passphrase,
+            passphrase: Some(passphrase.to_string()),
+            salt,
+            key,
+            derived: Arc::new(Mutex::new(HashMap::from([(salt, key)]))),
+        })
+    }
+
+    /// Reads a key file holding 64 hex digits, as written by `generate_key_file`.
+    pub fn from_key_file<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path.display(), e)))?;
+        let key = parse_hex_key(text.trim())
+            .ok_or_else(|| StoreError::InvalidInput(format!("{} does not hold a 256-bit hex key", path.display())))?;
+
+        Ok(Cipher {
+            kind: KeyKind::KeyFile,
+            passphrase: None,
+            salt: [0u8; SALT_LEN],
+            key,
+            derived: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let mut out = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plaintext.len() + 16);
+        out.extend_from_slice(MAGIC);
+        out.push(self.kind as u8);
+        out.extend_from_slice(&self.salt);
+        out.extend_from_slice(&key_check(&self.key));
+
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.key.into())
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &out[..HEADER_LEN] })
+            .map_err(|_| StoreError::DatabaseError("Encryption failed".to_string()))?;
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Decrypts data written by `encrypt`. `name` identifies the file in errors.
+    /// Fails with `WrongKey` when the key does not match the file, and with
+    /// `Damaged` when it does but the data fails authentication.
+    pub fn decrypt(&self, data: &[u8], name: &str) -> Result<Vec<u8>, StoreError> {
+        if !is_encrypted(data) || data.len() < HEADER_LEN + NONCE_LEN {
+            return Err(StoreError::DatabaseError(format!("{} is not an encrypted data file", name)));
+        }
+        let kind = match data[MAGIC.len()] {
+            0 => KeyKind::KeyFile,
+            1 => KeyKind::Passphrase,
+            other => return Err(StoreError::DatabaseError(format!("{} uses unknown key type {}", name, other))),
+        };
+        if kind != self.kind {
+            let expected = if kind == KeyKind::Passphrase { "a passphrase" } else { "a key file" };
+            return Err(StoreError::WrongKey(format!("{} was encrypted with {}", name, expected)));
+        }
+
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&data[MAGIC.len() + 1..SALT_END]);
+        let key = self.key_for(&salt)?;
+        if data[SALT_END..HEADER_LEN] != key_check(&key) {
+            return Err(StoreError::WrongKey(name.to_string()));
+        }
+
+        let nonce = XNonce::from_slice(&data[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+        XChaCha20Poly1305::new(&key.into())
+            .decrypt(nonce, Payload { msg: &data[HEADER_LEN + NONCE_LEN..], aad: &data[..HEADER_LEN] })
+            .map_err(|_| StoreError::Damaged(name.to_string()))
+    }
+
+    fn key_for(&self, salt: &[u8; SALT_LEN]) -> Result<[u8; 32], StoreError> {
+        let passphrase = match &self.passphrase {
+            Some(passphrase) => passphrase,
+            None => return Ok(self.key),
+        };
+        let mut derived = self.derived.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key) = derived.get(salt) {
+            return Ok(*key);
+        }
+        let key = derive(passphrase, salt)?;
+        derived.insert(*salt, key);
+        Ok(key)
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Identifies the key without revealing it.
+fn key_check(key: &[u8; 32]) -> [u8; CHECK_LEN] {
+    let digest = Sha256::new()
+        .chain_update(b"rusty-store key check")
+        .chain_update(key)
+        .finalize();
+    let mut check = [0u8; CHECK_LEN];
+    check.copy_from_slice(&digest[..CHECK_LEN]);
+    check
+}
+
+/// Writes a new random key file. Refuses to replace an existing one, since
+/// that would make everything encrypted with it unreadable.
+pub fn generate_key_file<P: AsRef<Path>>(path: P) -> Result<(), StoreError> {
+    let path = path.as_ref();
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, format!("{}\n", hex).as_bytes()))
+        .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path.display(), e)))
+}
+
+fn derive(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<[u8; 32], StoreError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| StoreError::InvalidInput(format!("Could not derive key: {}", e)))?;
+    Ok(key)
+}
+
+pub(crate) fn parse_hex_key(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
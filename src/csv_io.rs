@@ -0,0 +1,249 @@
+use crate::errors::StoreError;
+use crate::models::{Product, TransactionType};
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use uuid::Uuid;
+
+const PRODUCT_FIELDS: [&str; 5] = ["sku", "name", "description", "price", "quantity"];
+
+/// Which CSV header holds each product field. Headers are matched ignoring case
+/// and surrounding spaces. `sku`, `name` and `price` must be present; a missing
+/// `description` or `quantity` column leaves existing values alone and gives new
+/// products an empty description and no stock.
+#[derive(Debug, Clone)]
+pub struct ColumnMapping {
+    columns: HashMap<&'static str, String>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            columns: PRODUCT_FIELDS.iter().map(|f| (*f, f.to_string())).collect(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Parses overrides such as `sku=Item Code,price=Unit Price` on top of the default mapping.
+    pub fn parse(spec: &str) -> Result<Self, StoreError> {
+        let mut mapping = ColumnMapping::default();
+        for pair in spec.split(',').filter(|p| !p.trim().is_empty()) {
+            let (field, header) = pair.split_once('=')
+                .ok_or_else(|| StoreError::InvalidInput(format!("Expected field=header, got '{}'", pair)))?;
+            mapping.set(field.trim(), header.trim())?;
+        }
+        Ok(mapping)
+    }
+
+    pub fn set(&mut self, field: &str, header: &str) -> Result<(), StoreError> {
+        let field = PRODUCT_FIELDS.iter().find(|f| f.eq_ignore_ascii_case(field)).ok_or_else(|| {
+            StoreError::InvalidInput(format!("Unknown field '{}'; expected one of {}", field, PRODUCT_FIELDS.join(", ")))
+        })?;
+        self.columns.insert(field, header.to_string());
+        Ok(())
+    }
+
+    // Position of each mapped field in `headers`.
+    fn resolve(&self, headers: &csv::StringRecord) -> Result<HashMap<&'static str, usize>, StoreError> {
+        let mut positions = HashMap::new();
+        for (field, header) in &self.columns {
+            match headers.iter().position(|h| h.trim().eq_ignore_ascii_case(header.trim())) {
+                Some(i) => {
+                    positions.insert(*field, i);
+                },
+                None if matches!(*field, "description" | "quantity") => {},
+                None => return Err(StoreError::InvalidInput(format!("No '{}' column for {}", header, field))),
+            }
+        }
+        Ok(positions)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RowError {
+    /// Line in the file, counting the header as line 1.
+    pub line: u64,
+    pub sku: Option<String>,
+    pub message: String,
+}
+
+/// Outcome of a product import. Rows with errors are skipped; the rest are saved.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub created: usize,
+    pub updated: usize,
+    pub errors: Vec<RowError>,
+}
+
+impl ImportReport {
+    pub fn generate_report(&self) -> String {
+        let mut report = String::from("Product Import\n==============\n\n");
+        report.push_str(&format!("Created: {}\nUpdated: {}\nRejected: {}\n",
+            self.created, self.updated, self.errors.len()));
+        if !self.errors.is_empty() {
+            report.push('\n');
+        }
+        for error in &self.errors {
+            match &error.sku {
+                Some(sku) => report.push_str(&format!("Line {} ({}): {}\n", error.line, sku, error.message)),
+                None => report.push_str(&format!("Line {}: {}\n", error.line, error.message)),
+            }
+        }
+        report
+    }
+}
+
+/// Adds or updates products from CSV, matching existing products by SKU. Every
+/// row is checked first and the valid ones are saved together as one change.
+pub fn import_products<R: Read>(store: &mut Store, reader: R, mapping: &ColumnMapping) -> Result<ImportReport, StoreError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let headers = reader.headers().map_err(csv_error)?.clone();
+    let columns = mapping.resolve(&headers)?;
+
+    let mut report = ImportReport::default();
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    let mut products = Vec::new();
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map(|p| p.line()).unwrap_or(0);
+                report.errors.push(RowError { line, sku: None, message: e.to_string() });
+                continue;
+            },
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |name: &str| columns.get(name).and_then(|i| record.get(*i)).map(str::trim);
+
+        let sku = field("sku").filter(|s| !s.is_empty()).map(str::to_string);
+        let row = match parse_row(store, &field, sku.as_deref()) {
+            Ok(row) => row,
+            Err(message) => {
+                report.errors.push(RowError { line, sku, message });
+                continue;
+            },
+        };
+        if let Some(first) = seen.insert(row.sku.clone().unwrap_or_default(), line) {
+            report.errors.push(RowError { line, sku, message: format!("SKU repeats line {}", first) });
+            continue;
+        }
+
+        if store.find_product_by_sku(row.sku.as_deref().unwrap_or_default()).is_some() {
+            report.updated += 1;
+        } else {
+            report.created += 1;
+        }
+        products.push(row);
+    }
+
+    if !products.is_empty() {
+        store.save_products(products)?;
+    }
+    Ok(report)
+}
+
+// Builds the product a row describes, starting from the existing one with that SKU.
+fn parse_row<'a, F>(store: &Store, field: &F, sku: Option<&str>) -> Result<Product, String>
+where
+    F: Fn(&str) -> Option<&'a str>,
+{
+    let sku = sku.ok_or("SKU is missing")?;
+    let mut product = store.find_product_by_sku(sku).cloned().unwrap_or_else(|| Product {
+        id: Uuid::new_v4(),
+        name: String::new(),
+        description: String::new(),
+        price: 0.0,
+        quantity: 0,
+        version: 0,
+        sku: Some(sku.to_string()),
+        archived_at: None,
+    });
+    if product.archived_at.is_some() {
+        return Err(format!("{} is archived; restore it first", product.name));
+    }
+
+    product.name = field("name").filter(|n| !n.is_empty()).ok_or("Name is missing")?.to_string();
+    let price = field("price").unwrap_or_default();
+    product.price = match price.parse::<f64>() {
+        Ok(price) if price.is_finite() && price >= 0.0 => price,
+        _ => return Err(format!("Price '{}' is not a valid amount", price)),
+    };
+    if let Some(description) = field("description") {
+        product.description = description.to_string();
+    }
+    if let Some(quantity) = field("quantity").filter(|q| !q.is_empty()) {
+        product.quantity = match quantity.parse::<i32>() {
+            Ok(quantity) if quantity >= 0 => quantity,
+            _ => return Err(format!("Quantity '{}' is not a whole number of at least 0", quantity)),
+        };
+    }
+    Ok(product)
+}
+
+/// Writes every product in the catalog, sorted by SKU and then name. Archived
+/// products are left out.
+pub fn export_products<W: Write>(store: &Store, writer: W) -> Result<usize, StoreError> {
+    let mut products: Vec<&Product> = store.products().collect();
+    products.sort_by(|a, b| (&a.sku, &a.name).cmp(&(&b.sku, &b.name)));
+
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["id", "sku", "name", "description", "price", "quantity"]).map_err(csv_error)?;
+    for product in &products {
+        writer.write_record([
+            product.id.to_string(),
+            product.sku.clone().unwrap_or_default(),
+            product.name.clone(),
+            product.description.clone(),
+            format!("{:.2}", product.price),
+            product.quantity.to_string(),
+        ]).map_err(csv_error)?;
+    }
+    writer.flush().map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+    Ok(products.len())
+}
+
+/// Writes the transactions timestamped in `[from, to)`, oldest first. Either end
+/// may be left open. Timestamps are shown in the configured timezone.
+pub fn export_transactions<W: Write>(
+    store: &Store,
+    writer: W,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<usize, StoreError> {
+    let timezone = store.settings().timezone;
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record(["id", "timestamp", "type", "product_id", "sku", "product", "quantity", "price", "total"])
+        .map_err(csv_error)?;
+
+    let mut count = 0;
+    let from = from.unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let to = to.unwrap_or(DateTime::<Utc>::MAX_UTC);
+    for transaction in store.transactions_between(from, to) {
+        let product = store.get_product(&transaction.product_id);
+        writer.write_record([
+            transaction.id.to_string(),
+            transaction.timestamp.with_timezone(&timezone).to_rfc3339(),
+            match transaction.transaction_type {
+                TransactionType::Sale => "sale".to_string(),
+                TransactionType::Purchase => "purchase".to_string(),
+                TransactionType::Adjustment => "adjustment".to_string(),
+            },
+            transaction.product_id.to_string(),
+            product.and_then(|p| p.sku.clone()).unwrap_or_default(),
+            product.map(|p| p.name.clone()).unwrap_or_default(),
+            transaction.quantity.to_string(),
+            format!("{:.2}", transaction.price),
+            format!("{:.2}", transaction.price * transaction.quantity as f64),
+        ]).map_err(csv_error)?;
+        count += 1;
+    }
+    writer.flush().map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+    Ok(count)
+}
+
+fn csv_error(error: csv::Error) -> StoreError {
+    StoreError::InvalidInput(format!("CSV: {}", error))
+}
@@ -13,4 +13,26 @@ pub enum StoreError {
     InvalidInput(String),
     #[error("Database error: {0}")]
     DatabaseError(String),
+    #[error("Wrong key for {0}: it could not be decrypted")]
+    WrongKey(String),
+    #[error("{0} is damaged: it failed authentication under the right key")]
+    Damaged(String),
+    #[error("{0} is encrypted; a passphrase or key file is required")]
+    KeyRequired(String),
+    #[error("Conflicting change: {0}")]
+    Conflict(String),
+    #[error("{path} is corrupted at line {line}, column {column}: {message} (moved to {quarantined})")]
+    CorruptData {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+        quarantined: String,
+    },
+    #[error("{path} is corrupted: {message} (moved to {quarantined})")]
+    CorruptSnapshot {
+        path: String,
+        message: String,
+        quarantined: String,
+    },
 }
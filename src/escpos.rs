@@ -0,0 +1,226 @@
+use crate::barcode::Barcode;
+use crate::errors::StoreError;
+use crate::models::{Invoice, ShiftSummary};
+use crate::receipt::{self, ReceiptHeader};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+const ESC: u8 = 0x1b;
+const GS: u8 = 0x1d;
+const LF: u8 = 0x0a;
+
+/// Characters per line in font A on 80mm paper.
+pub const LINE_WIDTH: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Builds an ESC/POS command stream for a thermal receipt printer.
+pub struct EscPos {
+    buffer: Vec<u8>,
+}
+
+impl Default for EscPos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EscPos {
+    pub fn new() -> Self {
+        EscPos {
+            buffer: vec![ESC, b'@'],
+        }
+    }
+
+    /// Printers run in a single-byte code page, so anything outside ASCII is replaced.
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.buffer.extend(text.chars().map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' }
+        }));
+        self
+    }
+
+    pub fn line(&mut self, text: &str) -> &mut Self {
+        self.text(text);
+        self.buffer.push(LF);
+        self
+    }
+
+    pub fn bold(&mut self, on: bool) -> &mut Self {
+        self.buffer.extend([ESC, b'E', on as u8]);
+        self
+    }
+
+    pub fn underline(&mut self, on: bool) -> &mut Self {
+        self.buffer.extend([ESC, b'-', on as u8]);
+        self
+    }
+
+    pub fn double_size(&mut self, on: bool) -> &mut Self {
+        self.buffer.extend([GS, b'!', if on { 0x11 } else { 0x00 }]);
+        self
+    }
+
+    pub fn align(&mut self, align: Align) -> &mut Self {
+        let n = match align {
+            Align::Left => 0,
+            Align::Center => 1,
+            Align::Right => 2,
+        };
+        self.buffer.extend([ESC, b'a', n]);
+        self
+    }
+
+    pub fn feed(&mut self, lines: u8) -> &mut Self {
+        self.buffer.extend([ESC, b'd', lines]);
+        self
+    }
+
+    /// Prints a Code 39 barcode with its human-readable text underneath.
+    pub fn barcode(&mut self, data: &str) -> Result<&mut Self, StoreError> {
+        // Validates the payload with the same rules used for on-screen receipts
+        let barcode = Barcode::code39(data)?;
+        self.buffer.extend([GS, b'h', 80]);
+        self.buffer.extend([GS, b'w', 2]);
+        self.buffer.extend([GS, b'H', 2]);
+        self.buffer.extend([GS, b'k', 4]);
+        self.buffer.extend(barcode.data.bytes());
+        self.buffer.push(0);
+        Ok(self)
+    }
+
+    /// Feeds past the tear bar and performs a partial cut.
+    pub fn cut(&mut self) -> &mut Self {
+        self.buffer.extend([GS, b'V', 66, 0]);
+        self
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+fn rule() -> String {
+    "-".repeat(LINE_WIDTH)
+}
+
+fn columns(left: &str, right: &str) -> String {
+    receipt::columns_width(left, right, LINE_WIDTH)
+}
+
+fn header(printer: &mut EscPos, header: &ReceiptHeader) {
+    printer.align(Align::Center)
+        .double_size(true)
+        .bold(true)
+        .line(&receipt::truncate(&header.name, LINE_WIDTH / 2))
+        .bold(false)
+        .double_size(false);
+    for line in &header.address {
+        printer.line(line);
+    }
+    if let Some(phone) = &header.phone {
+        printer.line(&format!("Tel: {}", phone));
+    }
+    printer.align(Align::Left).line(&rule());
+}
+
+pub fn render_receipt(invoice: &Invoice, store_header: &ReceiptHeader) -> Result<Vec<u8>, StoreError> {
+    let mut printer = EscPos::new();
+    header(&mut printer, store_header);
+
+    printer.line(&format!("Invoice: {}", invoice.invoice_id()))
+        .line(&format!("Date: {}", store_header.time(invoice.issued_at)))
+        .line(&rule());
+
+    for line in &invoice.lines {
+        printer.line(&receipt::truncate(&line.description, LINE_WIDTH))
+            .line(&columns(
+                &format!("  {} x {}", line.quantity, store_header.money(line.unit_price)),
+                &store_header.money(line.total),
+            ));
+    }
+
+    printer.line(&rule())
+        .line(&columns("Subtotal", &store_header.money(invoice.subtotal)));
+    if invoice.discount > 0.0 {
+        printer.line(&columns(&receipt::discount_label(invoice), &store_header.money(-invoice.discount)));
+    }
+    if invoice.points_discount > 0.0 {
+        printer.line(&columns(&receipt::points_label(invoice), &store_header.money(-invoice.points_discount)));
+    }
+    printer.line(&columns(
+        &format!("Tax ({:.2}%)", invoice.tax_rate * 100.0),
+        &store_header.money(invoice.tax),
+    ))
+        .bold(true)
+        .line(&columns("TOTAL", &store_header.money(invoice.total)))
+        .bold(false);
+    for payment in &invoice.payments {
+        printer.line(&columns(&receipt::payment_label(&payment.method), &store_header.money(payment.amount)));
+    }
+    if invoice.change_due() > 0.0 {
+        printer.line(&columns("Change", &store_header.money(invoice.change_due())));
+    }
+    if invoice.points_earned > 0 {
+        printer.line(&columns("Points earned", &invoice.points_earned.to_string()));
+    }
+
+    printer.line(&rule()).align(Align::Center);
+    printer.barcode(&invoice.invoice_id())?;
+    printer.feed(4).cut();
+    Ok(printer.into_bytes())
+}
+
+pub fn render_shift_report(summary: &ShiftSummary, store_header: &ReceiptHeader) -> Vec<u8> {
+    let mut printer = EscPos::new();
+    header(&mut printer, store_header);
+
+    printer.align(Align::Center)
+        .bold(true)
+        .underline(true)
+        .line("SHIFT REPORT")
+        .underline(false)
+        .bold(false)
+        .align(Align::Left)
+        .line(&format!("From: {}", store_header.time(summary.from)))
+        .line(&format!("To:   {}", store_header.time(summary.to)))
+        .line(&rule())
+        .line(&columns("Sales", &summary.sales_count.to_string()))
+        .line(&columns("Items sold", &summary.items_sold.to_string()))
+        .line(&columns("Invoices issued", &summary.invoices_issued.to_string()))
+        .line(&columns("Tax collected", &store_header.money(summary.tax_collected)))
+        .bold(true)
+        .line(&columns("Gross sales", &store_header.money(summary.gross_sales)))
+        .bold(false)
+        .line(&rule());
+
+    for (category, amount) in &summary.payments {
+        printer.line(&columns(category, &store_header.money(*amount)));
+    }
+
+    printer.feed(4).cut();
+    printer.into_bytes()
+}
+
+/// Sends raw printer bytes to a device such as `/dev/usb/lp0`, or to a regular file for testing.
+pub fn write_to(path: &str, bytes: &[u8]) -> Result<(), StoreError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(|e| StoreError::DatabaseError(format!("Cannot open printer {}: {}", path, e)))?;
+
+    file.write_all(bytes)
+        .and_then(|_| file.flush())
+        .map_err(|e| StoreError::DatabaseError(format!("Cannot write to printer {}: {}", path, e)))
+}
@@ -0,0 +1,168 @@
+use crate::config::format_money;
+use crate::loyalty::LoyaltyConfig;
+use crate::models::{
+    Coupon, CouponCode, CouponRedemption, Customer, Invoice, LoyaltyEntry, StoredValueAccount, StoredValueEntry,
+    Transaction, TransactionType,
+};
+use crate::store::StoreData;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Something that happened to the store. Events are never changed or removed
+/// once recorded; the current state is whatever replaying them in order gives.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Event {
+    /// A new product. Any starting stock is booked as an adjustment so the
+    /// ledger accounts for every unit.
+    ProductCreated {
+        id: Uuid,
+        name: String,
+        description: String,
+        price: f64,
+        sku: Option<String>,
+        opening_stock: Option<Transaction>,
+    },
+    ProductDetailsChanged {
+        product_id: Uuid,
+        name: String,
+        description: String,
+        sku: Option<String>,
+    },
+    PriceChanged {
+        product_id: Uuid,
+        price: f64,
+    },
+    ProductArchived {
+        product_id: Uuid,
+    },
+    ProductRestored {
+        product_id: Uuid,
+    },
+    /// The product record is gone for good; its ledger entries stay.
+    ProductDeleted {
+        product_id: Uuid,
+    },
+    SaleRecorded(Transaction),
+    PurchaseRecorded(Transaction),
+    /// Stock counted in or written off outside a sale or purchase.
+    StockAdjusted(Transaction),
+    /// Books stock a product already had, e.g. from an imported snapshot whose
+    /// ledger was incomplete. The quantity on hand does not change.
+    StockReconciled(Transaction),
+    InvoiceIssued(Invoice),
+    CustomerAdded(Customer),
+    StoredValueIssued {
+        account: StoredValueAccount,
+        entry: StoredValueEntry,
+    },
+    StoredValueRedeemed(StoredValueEntry),
+    StoredValueVoided(StoredValueEntry),
+    CouponCreated(Coupon),
+    CouponCodeAdded(CouponCode),
+    CouponRedeemed(CouponRedemption),
+    LoyaltyConfigured(LoyaltyConfig),
+    LoyaltyPointsPosted(LoyaltyEntry),
+    /// Marks a point the store can later be wound back to with `Store::restore_to`.
+    RestorePointCreated {
+        name: String,
+    },
+    /// Replaces the whole state, e.g. with a store saved before events were
+    /// recorded or with what `repair` recovered.
+    SnapshotImported(Box<StoreData>),
+}
+
+impl Event {
+    /// The event recording a stock movement of `transaction`'s type.
+    pub fn stock(transaction: Transaction) -> Self {
+        match transaction.transaction_type {
+            TransactionType::Sale => Event::SaleRecorded(transaction),
+            TransactionType::Purchase => Event::PurchaseRecorded(transaction),
+            TransactionType::Adjustment => Event::StockAdjusted(transaction),
+        }
+    }
+
+    /// The product this event is about, if any.
+    pub fn product_id(&self) -> Option<Uuid> {
+        match self {
+            Event::ProductCreated { id, .. } => Some(*id),
+            Event::ProductDetailsChanged { product_id, .. }
+            | Event::PriceChanged { product_id, .. }
+            | Event::ProductArchived { product_id }
+            | Event::ProductRestored { product_id }
+            | Event::ProductDeleted { product_id } => Some(*product_id),
+            _ => self.transaction().map(|t| t.product_id),
+        }
+    }
+
+    /// The sale, purchase or adjustment this event records, if any.
+    pub fn transaction(&self) -> Option<&Transaction> {
+        match self {
+            Event::SaleRecorded(t) | Event::PurchaseRecorded(t) | Event::StockAdjusted(t)
+            | Event::StockReconciled(t) => Some(t),
+            Event::ProductCreated { opening_stock, .. } => opening_stock.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn transaction_mut(&mut self) -> Option<&mut Transaction> {
+        match self {
+            Event::SaleRecorded(t) | Event::PurchaseRecorded(t) | Event::StockAdjusted(t)
+            | Event::StockReconciled(t) => Some(t),
+            Event::ProductCreated { opening_stock, .. } => opening_stock.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// One line for the audit trail.
+    pub fn describe(&self, currency: &str) -> String {
+        match self {
+            Event::ProductCreated { name, price, .. } => {
+                format!("Product created: {} at {}", name, format_money(currency, *price))
+            },
+            Event::ProductDetailsChanged { name, .. } => format!("Product details changed: {}", name),
+            Event::PriceChanged { product_id, price } => {
+                format!("Price of {} changed to {}", product_id, format_money(currency, *price))
+            },
+            Event::ProductArchived { product_id } => format!("Product archived: {}", product_id),
+            Event::ProductRestored { product_id } => format!("Product restored: {}", product_id),
+            Event::ProductDeleted { product_id } => format!("Product deleted: {}", product_id),
+            Event::SaleRecorded(t) => format!("Sale of {} x {}", t.quantity, t.product_id),
+            Event::PurchaseRecorded(t) => format!("Purchase of {} x {} at {}", t.quantity, t.product_id,
+                format_money(currency, t.price)),
+            Event::StockAdjusted(t) => format!("Stock of {} adjusted by {:+}", t.product_id, t.quantity),
+            Event::StockReconciled(t) => format!("Ledger of {} reconciled by {:+}", t.product_id, t.quantity),
+            Event::InvoiceIssued(invoice) => format!("Invoice {} issued for {}", invoice.number,
+                format_money(currency, invoice.total)),
+            Event::CustomerAdded(customer) => format!("Customer added: {}", customer.name),
+            Event::StoredValueIssued { account, .. } => format!("{} issued with {}", account.code,
+                format_money(currency, account.balance)),
+            Event::StoredValueRedeemed(entry) => format!("{} redeemed {}", entry.code, format_money(currency, entry.amount)),
+            Event::StoredValueVoided(entry) => format!("{} voided", entry.code),
+            Event::CouponCreated(coupon) => format!("Coupon created: {}", coupon.name),
+            Event::CouponCodeAdded(code) => format!("Coupon code added: {}", code.code),
+            Event::CouponRedeemed(redemption) => format!("Coupon {} redeemed on invoice {}", redemption.code,
+                redemption.invoice_number),
+            Event::LoyaltyConfigured(_) => "Loyalty program configured".to_string(),
+            Event::LoyaltyPointsPosted(entry) => format!("{:?} of {} loyalty points for {}", entry.kind, entry.points,
+                entry.customer_id),
+            Event::RestorePointCreated { name } => format!("Restore point created: {}", name),
+            Event::SnapshotImported(data) => format!("Imported {} product(s) and {} transaction(s)",
+                data.products.len(), data.transactions.len()),
+        }
+    }
+}
+
+/// An event as stored in the log, numbered from 1 in the order it happened.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedEvent {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub event: Event,
+}
+
+/// A view of the store built by replaying its events in order, e.g. with
+/// `Store::project`. New views need no change to what is stored.
+pub trait Projection {
+    fn apply(&mut self, event: &RecordedEvent);
+}
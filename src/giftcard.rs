@@ -0,0 +1,180 @@
+use crate::codes;
+use crate::config::format_money;
+use crate::errors::StoreError;
+use crate::models::{StoredValueAccount, StoredValueEntry, StoredValueKind, StoredValueOperation};
+use crate::store::round_cents;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Gift card and store credit accounts together with the ledger of every issue, redemption and void.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct StoredValueBook {
+    accounts: HashMap<String, StoredValueAccount>,
+    ledger: Vec<StoredValueEntry>,
+}
+
+impl StoredValueBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a new account and its opening ledger entry without changing the book.
+    pub fn prepare_issue(&self, kind: StoredValueKind, amount: f64) -> Result<(StoredValueAccount, StoredValueEntry), StoreError> {
+        if amount <= 0.0 {
+            return Err(StoreError::InvalidInput("Issued amount must be positive".to_string()));
+        }
+
+        let prefix = match kind {
+            StoredValueKind::GiftCard => "GC",
+            StoredValueKind::StoreCredit => "SC",
+        };
+        let mut code = codes::random_code(prefix, 3);
+        while self.accounts.contains_key(&code) {
+            code = codes::random_code(prefix, 3);
+        }
+
+        let account = StoredValueAccount {
+            code: code.clone(),
+            kind,
+            balance: round_cents(amount),
+            issued_at: chrono::Utc::now(),
+            voided: false,
+        };
+        let entry = entry(&code, StoredValueOperation::Issue, account.balance, None);
+        Ok((account, entry))
+    }
+
+    pub fn get(&self, code: &str) -> Option<&StoredValueAccount> {
+        self.accounts.get(&normalize(code))
+    }
+
+    pub fn balance(&self, code: &str) -> Result<f64, StoreError> {
+        self.get(code).map(|a| a.balance).ok_or(StoreError::NotFound)
+    }
+
+    /// Checks that `amount` could be redeemed from `code` without changing anything.
+    /// `currency` is for the error message.
+    pub fn check_redeemable(&self, code: &str, amount: f64, currency: &str) -> Result<(), StoreError> {
+        let account = self.get(code).ok_or(StoreError::NotFound)?;
+        if account.voided {
+            return Err(StoreError::InvalidInput(format!("{} has been voided", account.code)));
+        }
+        if amount <= 0.0 {
+            return Err(StoreError::InvalidInput("Redeemed amount must be positive".to_string()));
+        }
+        if round_cents(amount) > account.balance {
+            return Err(StoreError::InvalidInput(format!(
+                "{} only has {} available", account.code, format_money(currency, account.balance)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the account as it will be after the redemption, plus the ledger entry.
+    pub fn prepare_redeem(
+        &self,
+        code: &str,
+        amount: f64,
+        invoice_number: Option<u64>,
+        currency: &str,
+    ) -> Result<StoredValueEntry, StoreError> {
+        self.check_redeemable(code, amount, currency)?;
+        let account = self.get(code).ok_or(StoreError::NotFound)?;
+        Ok(entry(&account.code, StoredValueOperation::Redeem, round_cents(amount), invoice_number))
+    }
+
+    /// Cancels an account, writing off whatever balance is left.
+    pub fn prepare_void(&self, code: &str) -> Result<StoredValueEntry, StoreError> {
+        let account = self.get(code).ok_or(StoreError::NotFound)?;
+        if account.voided {
+            return Err(StoreError::InvalidInput(format!("{} is already voided", account.code)));
+        }
+        Ok(entry(&account.code, StoredValueOperation::Void, account.balance, None))
+    }
+
+    pub(crate) fn apply_account(&mut self, account: StoredValueAccount) {
+        self.accounts.insert(account.code.clone(), account);
+    }
+
+    /// Books an entry and its effect on the account's balance. Issues carry no
+    /// effect of their own since the account is created with its opening balance.
+    pub(crate) fn apply_entry(&mut self, entry: StoredValueEntry) {
+        if let Some(account) = self.accounts.get_mut(&entry.code) {
+            match entry.operation {
+                StoredValueOperation::Issue => {},
+                StoredValueOperation::Redeem => account.balance = round_cents(account.balance - entry.amount),
+                StoredValueOperation::Void => {
+                    account.balance = 0.0;
+                    account.voided = true;
+                },
+            }
+        }
+        self.ledger.push(entry);
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &StoredValueAccount> {
+        self.accounts.values()
+    }
+
+    pub fn ledger(&self) -> &[StoredValueEntry] {
+        &self.ledger
+    }
+
+    pub fn history(&self, code: &str) -> Vec<&StoredValueEntry> {
+        let code = normalize(code);
+        self.ledger.iter().filter(|e| e.code == code).collect()
+    }
+
+    /// Outstanding balances are money owed to customers.
+    pub fn total_liability(&self) -> f64 {
+        round_cents(self.accounts.values().filter(|a| !a.voided).map(|a| a.balance).sum())
+    }
+
+    pub fn generate_liability_report(&self, currency: &str) -> String {
+        let mut report = String::from("Gift Card & Store Credit Liability\n==================================\n\n");
+        let mut accounts: Vec<&StoredValueAccount> = self.accounts.values()
+            .filter(|a| !a.voided && a.balance > 0.0)
+            .collect();
+        accounts.sort_by_key(|a| a.issued_at);
+
+        for kind in [StoredValueKind::GiftCard, StoredValueKind::StoreCredit] {
+            let of_kind: Vec<_> = accounts.iter().filter(|a| a.kind == kind).collect();
+            let total: f64 = of_kind.iter().map(|a| a.balance).sum();
+            report.push_str(&format!("{}: {} accounts, {}\n",
+                kind_label(&kind), of_kind.len(), format_money(currency, total)));
+        }
+        report.push('\n');
+
+        for account in accounts {
+            report.push_str(&format!("Code: {}\nType: {}\nIssued: {}\nBalance: {}\n\n",
+                account.code, kind_label(&account.kind),
+                account.issued_at.format("%Y-%m-%d"), format_money(currency, account.balance)));
+        }
+
+        report.push_str(&format!("Total Liability: {}\n", format_money(currency, self.total_liability())));
+        report
+    }
+}
+
+fn entry(code: &str, operation: StoredValueOperation, amount: f64, invoice_number: Option<u64>) -> StoredValueEntry {
+    StoredValueEntry {
+        id: Uuid::new_v4(),
+        code: code.to_string(),
+        operation,
+        amount,
+        invoice_number,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+pub fn kind_label(kind: &StoredValueKind) -> &'static str {
+    match kind {
+        StoredValueKind::GiftCard => "Gift card",
+        StoredValueKind::StoreCredit => "Store credit",
+    }
+}
+
+pub(crate) fn normalize(code: &str) -> String {
+    code.trim().to_uppercase()
+}
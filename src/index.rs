@@ -0,0 +1,71 @@
+use crate::models::{Transaction, TransactionType};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Width of the time buckets, in seconds: one UTC day.
+const BUCKET_SECONDS: i64 = 86_400;
+
+/// Positions in a store's transaction list by product, type, day and user. It
+/// is kept up to date as transactions are added, so lookups only touch the
+/// transactions they return instead of scanning the whole ledger. Positions
+/// are in ledger order within each list.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionIndex {
+    by_product: HashMap<Uuid, Vec<usize>>,
+    by_type: HashMap<TransactionType, Vec<usize>>,
+    by_bucket: BTreeMap<i64, Vec<usize>>,
+    by_user: HashMap<String, Vec<usize>>,
+}
+
+impl TransactionIndex {
+    pub fn build(transactions: &[Transaction]) -> Self {
+        let mut index = TransactionIndex::default();
+        for (position, transaction) in transactions.iter().enumerate() {
+            index.insert(position, transaction);
+        }
+        index
+    }
+
+    /// Adds the transaction at `position`, which must come after every one
+    /// indexed so far.
+    pub fn insert(&mut self, position: usize, transaction: &Transaction) {
+        self.by_product.entry(transaction.product_id).or_default().push(position);
+        self.by_type.entry(transaction.transaction_type.clone()).or_default().push(position);
+        self.by_bucket.entry(bucket(transaction.timestamp)).or_default().push(position);
+        if let Some(user) = &transaction.user {
+            self.by_user.entry(user.clone()).or_default().push(position);
+        }
+    }
+
+    pub fn product(&self, product_id: &Uuid) -> &[usize] {
+        self.by_product.get(product_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn of_type(&self, kind: &TransactionType) -> &[usize] {
+        self.by_type.get(kind).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn user(&self, user: &str) -> &[usize] {
+        self.by_user.get(user).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Positions of the transactions timestamped in `[from, to)`, in ledger
+    /// order. Only the days the range covers are looked at.
+    pub fn between(&self, transactions: &[Transaction], from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<usize> {
+        if from >= to {
+            return Vec::new();
+        }
+        let mut positions: Vec<usize> = self.by_bucket.range(bucket(from)..=bucket(to))
+            .flat_map(|(_, positions)| positions.iter().copied())
+            .filter(|&i| transactions[i].timestamp >= from && transactions[i].timestamp < to)
+            .collect();
+        // Transactions merged from another store can be older than the ones before them
+        positions.sort_unstable();
+        positions
+    }
+}
+
+fn bucket(timestamp: DateTime<Utc>) -> i64 {
+    timestamp.timestamp().div_euclid(BUCKET_SECONDS)
+}
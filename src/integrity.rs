@@ -0,0 +1,236 @@
+use crate::crypto;
+use crate::errors::StoreError;
+use crate::models::{Transaction, TransactionType};
+use crate::store::{Store, StoreData};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// What the first transaction of a ledger points back to.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// SHA-256 of a transaction, including the hash it points back to, so each
+/// hash covers the whole ledger before it. The user is only hashed when set,
+/// so transactions chained before they carried one keep their hashes.
+pub fn transaction_hash(transaction: &Transaction) -> String {
+    let kind = match transaction.transaction_type {
+        TransactionType::Sale => "sale",
+        TransactionType::Purchase => "purchase",
+        TransactionType::Adjustment => "adjustment",
+    };
+    let mut text = format!("{}|{}|{}|{}|{:?}|{}|{}",
+        transaction.prev_hash.as_deref().unwrap_or_default(), transaction.id, transaction.product_id,
+        transaction.quantity, transaction.price, kind,
+        transaction.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true));
+    if let Some(user) = &transaction.user {
+        text.push_str(&format!("|{}", user));
+    }
+    hex(&Sha256::digest(text.as_bytes()))
+}
+
+/// The hash the next transaction should point back to: that of the last one,
+/// else `anchor` (the last archived transaction's), else `GENESIS_HASH`.
+pub fn chain_head(transactions: &[Transaction], anchor: Option<&str>) -> String {
+    match transactions.last() {
+        Some(last) => transaction_hash(last),
+        None => anchor.unwrap_or(GENESIS_HASH).to_string(),
+    }
+}
+
+/// A transaction that does not follow the one before it.
+#[derive(Debug, Clone)]
+pub struct ChainBreak {
+    /// Position in the ledger, from 1.
+    pub position: usize,
+    pub transaction: Transaction,
+    pub message: String,
+}
+
+/// Checks that each transaction points back to the one before it. A modified
+/// transaction breaks the link to the one after it; an inserted or removed one
+/// breaks the link at that position. The chain is only checked from the first
+/// transaction with a `prev_hash`, since earlier ones were recorded before
+/// transactions were chained. `anchor` is what the first one should point back
+/// to when older transactions have been archived; `None` accepts any.
+pub fn verify_chain(transactions: &[Transaction], anchor: Option<&str>) -> Vec<ChainBreak> {
+    let mut breaks = Vec::new();
+    let mut expected = anchor.map(str::to_string);
+    let mut chained = false;
+    for (i, transaction) in transactions.iter().enumerate() {
+        let broken = |message: &str| ChainBreak { position: i + 1, transaction: transaction.clone(), message: message.to_string() };
+        match (&transaction.prev_hash, &expected) {
+            (None, _) if chained => breaks.push(broken("has no hash but follows chained transactions")),
+            (None, _) => {},
+            (Some(prev), Some(expected)) if prev != expected => breaks.push(broken(
+                "does not follow the transaction before it; one of them was modified, or transactions were inserted or removed here",
+            )),
+            (Some(_), _) => {},
+        }
+        chained |= transaction.prev_hash.is_some();
+        expected = Some(transaction_hash(transaction));
+    }
+    breaks
+}
+
+/// An HMAC over a saved store, proving it was written by someone holding the
+/// signing key and not changed since.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SnapshotSignature {
+    pub signed_at: DateTime<Utc>,
+    /// First bytes of the key's SHA-256, to tell which key signed it.
+    pub key_id: String,
+    pub mac: String,
+}
+
+/// Signs and checks snapshots with a key file like the ones `generate-key` writes.
+#[derive(Clone)]
+pub struct Signer {
+    key: [u8; 32],
+}
+
+impl Signer {
+    pub fn from_key_file<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path.display(), e)))?;
+        let key = crypto::parse_hex_key(text.trim())
+            .ok_or_else(|| StoreError::InvalidInput(format!("{} does not hold a 256-bit hex key", path.display())))?;
+        Ok(Signer { key })
+    }
+
+    pub fn key_id(&self) -> String {
+        hex(&Sha256::digest(self.key)[..8])
+    }
+
+    pub fn sign<T: Serialize>(&self, data: &T) -> Result<SnapshotSignature, StoreError> {
+        Ok(SnapshotSignature {
+            signed_at: Utc::now(),
+            key_id: self.key_id(),
+            mac: hex(&self.mac(data)?.finalize().into_bytes()),
+        })
+    }
+
+    /// Whether `data` carries a signature from this key over exactly its contents.
+    pub fn verify(&self, data: &StoreData) -> Result<bool, StoreError> {
+        let Some(signature) = &data.signature else {
+            return Ok(false);
+        };
+        let Some(expected) = unhex(&signature.mac) else {
+            return Ok(false);
+        };
+        Ok(signature.key_id == self.key_id() && self.mac(data)?.verify_slice(&expected).is_ok())
+    }
+
+    // Over the store without its signature, with object keys sorted so the
+    // order products happen to be saved in does not matter.
+    fn mac<T: Serialize>(&self, data: &T) -> Result<Hmac<Sha256>, StoreError> {
+        let mut doc = serde_json::to_value(data).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        if let Some(map) = doc.as_object_mut() {
+            map.remove("signature");
+        }
+        let bytes = serde_json::to_vec(&doc).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key)
+            .map_err(|e| StoreError::InvalidInput(e.to_string()))?;
+        mac.update(&bytes);
+        Ok(mac)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignatureStatus {
+    Valid,
+    /// Signed, but not by this key or not over these contents.
+    Invalid,
+    Unsigned,
+    /// Signed, but no signing key was given to check it with.
+    NotChecked,
+}
+
+/// Outcome of `verify`.
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub transactions: usize,
+    /// Transactions from before chaining, which the chain does not cover.
+    pub unchained: usize,
+    pub breaks: Vec<ChainBreak>,
+    /// Position of the first transaction in the saved list that differs from
+    /// what the event log records.
+    pub log_mismatch: Option<usize>,
+    pub signature: SignatureStatus,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.breaks.is_empty() && self.log_mismatch.is_none() && self.signature != SignatureStatus::Invalid
+    }
+
+    pub fn generate_report(&self) -> String {
+        let mut report = String::from("Ledger Verification\n===================\n\n");
+        report.push_str(&format!("Transactions: {}\n", self.transactions));
+        if self.unchained > 0 {
+            report.push_str(&format!("Recorded before chaining (not covered): {}\n", self.unchained));
+        }
+        report.push_str(&format!("Signature: {}\n", match self.signature {
+            SignatureStatus::Valid => "valid",
+            SignatureStatus::Invalid => "INVALID - the file was changed after it was signed, or signed with another key",
+            SignatureStatus::Unsigned => "none",
+            SignatureStatus::NotChecked => "present but not checked; give the signing key to check it",
+        }));
+        for chain_break in &self.breaks {
+            report.push_str(&format!("\nTransaction {} ({}) {}\n", chain_break.position, chain_break.transaction.id,
+                chain_break.message));
+        }
+        if let Some(position) = self.log_mismatch {
+            report.push_str(&format!("\nTransaction {} differs from the event log\n", position));
+        }
+        report.push_str(if self.is_intact() { "\nLedger intact\n" } else { "\nLedger has been tampered with\n" });
+        report
+    }
+}
+
+/// Checks a saved store: the transaction chain, that the saved transactions
+/// match the ones its event log records, and its signature if `signer` is given.
+pub fn verify(data: &StoreData, signer: Option<&Signer>) -> Result<VerifyReport, StoreError> {
+    let anchor = match data.archives.last() {
+        Some(archive) => archive.head.as_deref(),
+        None => Some(GENESIS_HASH),
+    };
+    let breaks = verify_chain(&data.transactions, anchor);
+
+    // Replayed even if a checksum vouches for the saved state, which is what is being checked
+    let replayed = Store::from_data(StoreData { checksummed: false, ..data.clone() });
+    let hashes = |transactions: &[Transaction]| transactions.iter().map(transaction_hash).collect::<Vec<_>>();
+    let (saved, logged) = (hashes(&data.transactions), hashes(replayed.transactions()));
+    let log_mismatch = (0..saved.len().max(logged.len()))
+        .find(|i| saved.get(*i) != logged.get(*i))
+        .map(|i| i + 1);
+
+    let signature = match (&data.signature, signer) {
+        (None, _) => SignatureStatus::Unsigned,
+        (Some(_), None) => SignatureStatus::NotChecked,
+        (Some(_), Some(signer)) if signer.verify(data)? => SignatureStatus::Valid,
+        (Some(_), Some(_)) => SignatureStatus::Invalid,
+    };
+
+    Ok(VerifyReport {
+        transactions: data.transactions.len(),
+        unchained: data.transactions.iter().take_while(|t| t.prev_hash.is_none()).count(),
+        breaks,
+        log_mismatch,
+        signature,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
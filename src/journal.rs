@@ -0,0 +1,189 @@
+use crate::crypto::Cipher;
+use crate::errors::StoreError;
+use crate::events::RecordedEvent;
+use crate::storage::set_aside;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_JOURNAL_FILE: &str = "store.journal";
+
+/// How many batches may pile up before the store should be checkpointed.
+pub const DEFAULT_COMPACT_EVERY: u64 = 100;
+
+/// Events recorded together by one operation. A batch is either fully on disk or ignored.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JournalBatch {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub events: Vec<RecordedEvent>,
+}
+
+/// Append-only write-ahead log, one JSON batch per line. Each append is synced
+/// to disk before the change is applied in memory. With a cipher each line is
+/// the base64 of an encrypted batch instead.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    cipher: Option<Cipher>,
+    batches: Vec<JournalBatch>,
+    next_seq: u64,
+    compact_every: u64,
+}
+
+impl Journal {
+    /// Opens or creates the journal at `path`. A torn batch left by a crash
+    /// mid-write is cut off so later appends start on a clean line. Only the
+    /// last line can be torn: a bad line with more after it means the file is
+    /// damaged, so it is moved aside and `StoreError::CorruptData` returned
+    /// rather than the later batches being dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StoreError> {
+        Journal::open_with_cipher(path, None)
+    }
+
+    pub fn open_with_cipher<P: AsRef<Path>>(path: P, cipher: Option<Cipher>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+        let name = path.display().to_string();
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)
+            .map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+
+        let mut batches = Vec::new();
+        let mut valid_len = 0u64;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        let (mut line_number, mut bad_line, mut damaged) = (0, None, false);
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+            if read == 0 {
+                break;
+            }
+            line_number += 1;
+            if bad_line.is_some() {
+                damaged = true;
+                break;
+            }
+            if !line.ends_with('\n') {
+                break;
+            }
+            match decode_line(line.trim_end(), cipher.as_ref(), &name)? {
+                Some(batch) => {
+                    batches.push(batch);
+                    valid_len += read as u64;
+                },
+                None => bad_line = Some(line_number),
+            }
+        }
+        drop(reader);
+
+        if let (true, Some(line)) = (damaged, bad_line) {
+            drop(file);
+            let quarantined = set_aside(&path)?;
+            return Err(StoreError::CorruptData {
+                path: name,
+                line,
+                column: 1,
+                message: "a batch cannot be read and later batches follow it".to_string(),
+                quarantined: quarantined.display().to_string(),
+            });
+        }
+
+        if file.metadata().map_err(|e| StoreError::DatabaseError(e.to_string()))?.len() > valid_len {
+            file.set_len(valid_len).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+            file.sync_all().map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        }
+        file.seek(SeekFrom::End(0)).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+
+        let next_seq = batches.last().map(|b| b.seq + 1).unwrap_or(1);
+        Ok(Journal {
+            path,
+            file,
+            cipher,
+            batches,
+            next_seq,
+            compact_every: DEFAULT_COMPACT_EVERY,
+        })
+    }
+
+    pub fn with_compact_every(mut self, batches: u64) -> Self {
+        self.compact_every = batches.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Batches currently in the journal, oldest first.
+    pub fn batches(&self) -> &[JournalBatch] {
+        &self.batches
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Makes sure new batches are numbered after `seq`, e.g. after a checkpoint.
+    pub fn advance_to(&mut self, seq: u64) {
+        self.next_seq = self.next_seq.max(seq + 1);
+    }
+
+    /// Writes a batch and waits for it to reach the disk. Returns its sequence number.
+    pub fn append(&mut self, events: Vec<RecordedEvent>) -> Result<u64, StoreError> {
+        let batch = JournalBatch {
+            seq: self.next_seq,
+            timestamp: Utc::now(),
+            events,
+        };
+        let json = serde_json::to_string(&batch).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        let mut line = match &self.cipher {
+            Some(cipher) => BASE64.encode(cipher.encrypt(json.as_bytes())?),
+            None => json,
+        };
+        line.push('\n');
+
+        self.file.write_all(line.as_bytes()).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        self.file.sync_data().map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+
+        self.next_seq += 1;
+        self.batches.push(batch);
+        Ok(self.next_seq - 1)
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.batches.len() as u64 >= self.compact_every
+    }
+
+    /// Drops every batch once a snapshot covering them has been saved.
+    pub fn truncate(&mut self) -> Result<(), StoreError> {
+        self.file.set_len(0).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        self.file.sync_all().map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        self.batches.clear();
+        Ok(())
+    }
+}
+
+// A line that cannot be decoded, or fails authentication under the right key,
+// is `None`: a torn write if it is the last line, damage otherwise. A line that
+// will not decrypt because the key is wrong is an error, which must not be
+// mistaken for a torn write and truncated away.
+fn decode_line(line: &str, cipher: Option<&Cipher>, name: &str) -> Result<Option<JournalBatch>, StoreError> {
+    if line.starts_with('{') {
+        return Ok(serde_json::from_str(line).ok());
+    }
+    let bytes = match BASE64.decode(line) {
+        Ok(bytes) if crate::crypto::is_encrypted(&bytes) => bytes,
+        _ => return Ok(None),
+    };
+    let cipher = cipher.ok_or_else(|| StoreError::KeyRequired(name.to_string()))?;
+    let json = match cipher.decrypt(&bytes, name) {
+        Ok(json) => json,
+        Err(StoreError::Damaged(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(serde_json::from_slice(&json).ok())
+}
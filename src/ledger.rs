@@ -0,0 +1,150 @@
+use crate::config::Settings;
+use crate::models::{Product, Transaction, TransactionType};
+use crate::retention::{self, PeriodSummary};
+use crate::store::round_cents;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Stock on hand of one product at a point in time.
+#[derive(Debug, Clone)]
+pub struct StockLevel {
+    pub product_id: Uuid,
+    /// `None` for products deleted since.
+    pub name: Option<String>,
+    pub quantity: i32,
+    /// Average purchase cost up to that time, or the current list price for
+    /// products never purchased.
+    pub unit_cost: f64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StockReport {
+    pub as_of: DateTime<Utc>,
+    pub levels: Vec<StockLevel>,
+    pub total_value: f64,
+}
+
+impl StockReport {
+    pub fn generate_report(&self, settings: &Settings) -> String {
+        let title = format!("Stock as of {}", settings.format_time(self.as_of));
+        let mut report = format!("{}\n{}\n\n", title, "=".repeat(title.chars().count()));
+        for level in &self.levels {
+            report.push_str(&format!("Product: {}\nQuantity: {}\nUnit Cost: {}\nValue: {}\n\n",
+                level.name.as_deref().unwrap_or("(deleted product)"), level.quantity,
+                settings.format_money(level.unit_cost), settings.format_money(level.value)));
+        }
+        report.push_str(&format!("Total Value: {}\n", settings.format_money(self.total_value)));
+        report
+    }
+}
+
+/// A product whose recorded quantity disagrees with its ledger.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub product_id: Uuid,
+    pub name: String,
+    pub recorded: i32,
+    pub ledger: i32,
+}
+
+impl Drift {
+    /// Units the ledger is missing; negative if it has too many.
+    pub fn difference(&self) -> i32 {
+        self.recorded - self.ledger
+    }
+}
+
+pub fn generate_drift_report(drift: &[Drift]) -> String {
+    let mut report = String::from("Inventory Consistency Check\n===========================\n\n");
+    if drift.is_empty() {
+        report.push_str("Every product matches its ledger\n");
+    }
+    for d in drift {
+        report.push_str(&format!("Product: {}\nRecorded: {}\nLedger: {}\nDrift: {:+}\n\n",
+            d.name, d.recorded, d.ledger, d.difference()));
+    }
+    report
+}
+
+/// How a transaction moves stock: purchases add, sales remove, adjustments carry their own sign.
+pub fn quantity_change(transaction: &Transaction) -> i32 {
+    match transaction.transaction_type {
+        TransactionType::Sale => -transaction.quantity,
+        TransactionType::Purchase | TransactionType::Adjustment => transaction.quantity,
+    }
+}
+
+/// Replays every transaction up to and including `at`. Products with no stock
+/// and no movement by then are left out. Archived periods count once they have
+/// ended, so a time inside one needs its archived transactions passed instead.
+pub fn stock_as_of(
+    products: &HashMap<Uuid, Product>,
+    transactions: &[Transaction],
+    summaries: &[PeriodSummary],
+    at: DateTime<Utc>,
+) -> StockReport {
+    // quantity, units purchased, cost of units purchased
+    let mut totals: HashMap<Uuid, (i32, i64, f64)> = HashMap::new();
+    for summary in summaries.iter().filter(|s| s.end <= at) {
+        let entry = totals.entry(summary.product_id).or_default();
+        entry.0 += summary.quantity_change() as i32;
+        if summary.transaction_type == TransactionType::Purchase {
+            entry.1 += summary.quantity;
+            entry.2 += summary.value;
+        }
+    }
+    for transaction in transactions.iter().filter(|t| t.timestamp <= at) {
+        let entry = totals.entry(transaction.product_id).or_default();
+        entry.0 += quantity_change(transaction);
+        if transaction.transaction_type == TransactionType::Purchase {
+            entry.1 += transaction.quantity as i64;
+            entry.2 += transaction.price * transaction.quantity as f64;
+        }
+    }
+
+    let mut levels: Vec<StockLevel> = totals.into_iter()
+        .map(|(product_id, (quantity, purchased, cost))| {
+            let product = products.get(&product_id);
+            let unit_cost = match purchased {
+                0 => product.map(|p| p.price).unwrap_or(0.0),
+                _ => cost / purchased as f64,
+            };
+            StockLevel {
+                product_id,
+                name: product.map(|p| p.name.clone()),
+                quantity,
+                unit_cost,
+                value: round_cents(unit_cost * quantity as f64),
+            }
+        })
+        .collect();
+    levels.sort_by(|a, b| a.name.cmp(&b.name).then(a.product_id.cmp(&b.product_id)));
+
+    let total_value = round_cents(levels.iter().map(|l| l.value).sum());
+    StockReport { as_of: at, levels, total_value }
+}
+
+/// Compares each product's quantity with the sum of its ledger, archived
+/// periods included, sorted by name.
+pub fn find_drift(products: &HashMap<Uuid, Product>, transactions: &[Transaction], summaries: &[PeriodSummary]) -> Vec<Drift> {
+    let mut ledger: HashMap<Uuid, i32> = retention::net_quantities(summaries).into_iter()
+        .map(|(id, quantity)| (id, quantity as i32))
+        .collect();
+    for transaction in transactions {
+        *ledger.entry(transaction.product_id).or_default() += quantity_change(transaction);
+    }
+
+    let mut drift: Vec<Drift> = products.values()
+        .map(|p| Drift {
+            product_id: p.id,
+            name: p.name.clone(),
+            recorded: p.quantity,
+            ledger: ledger.get(&p.id).copied().unwrap_or(0),
+        })
+        .filter(|d| d.recorded != d.ledger)
+        .collect();
+    drift.sort_by(|a, b| a.name.cmp(&b.name));
+    drift
+}
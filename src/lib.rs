@@ -2,32 +2,121 @@ pub mod models;
 pub mod errors;
 pub mod auth;
 pub mod store;
+pub mod storage;
+pub mod sqlite;
+pub mod barcode;
+pub mod receipt;
+pub mod escpos;
+pub mod codes;
+pub mod giftcard;
+pub mod coupon;
+pub mod loyalty;
+pub mod journal;
+pub mod events;
+pub mod repair;
+pub mod migrations;
+pub mod crypto;
+pub mod config;
+pub mod lock;
+pub mod csv_io;
+pub mod ledger;
+pub mod retention;
+pub mod sync;
+pub mod snapshot;
+pub mod integrity;
+pub mod index;
+pub mod undo;
 
 #[cfg(test)]
 mod tests {
     use uuid::Uuid;
-    use crate::models::{Product, UserRole, TransactionType};
+    use crate::models::{
+        Checkout, Coupon, Customer, DiscountType, Payment, PaymentMethod, Product, StoredValueKind, UserRole,
+        TransactionType,
+    };
     use crate::store::Store;
     use crate::auth::Auth;
+    use crate::storage::{JsonStorage, MemoryStorage, Rotation, RotationRecovery, Storage};
+    use crate::sqlite::SqliteStorage;
+    use crate::receipt::{self, ReceiptHeader};
+    use crate::escpos;
+    use crate::loyalty::LoyaltyConfig;
+    use crate::journal::Journal;
+    use crate::errors::StoreError;
+    use crate::repair;
+    use crate::migrations::{self, SCHEMA_VERSION};
+    use crate::crypto::{self, Cipher};
+    use crate::config::Settings;
+    use crate::lock::DataLock;
+    use crate::csv_io::{self, ColumnMapping};
+    use crate::events::{Event, Projection, RecordedEvent};
+    use crate::retention::{ArchiveDir, Period};
+    use crate::sync::Changeset;
+    use crate::snapshot::{self, StoreFormat};
+    use crate::integrity::{self, SignatureStatus, Signer};
+    use chrono_tz::Tz;
     use std::fs;
+    use std::io::Write;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
 
-    #[test]
-    fn test_add_product() {
-        let mut store = Store::new();
-        let product = Product {
+    /// A fresh directory for one test's files, removed when it goes out of scope
+    /// even if the test fails.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("rusty-store-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The product most tests use, with a new ID.
+    fn test_product(quantity: i32) -> Product {
+        Product {
             id: Uuid::new_v4(),
             name: "Test Product".to_string(),
             description: "Test Description".to_string(),
             price: 10.0,
-            quantity: 5,
-        };
+            quantity,
+            version: 0,
+            sku: None,
+            archived_at: None,
+        }
+    }
+
+    #[test]
+    fn test_add_product() {
+        let mut store = Store::new();
+        let product = test_product(5);
 
         assert!(store.add_product(product).is_ok());
     }
 
     #[test]
     fn test_authentication() {
-        let mut auth = Auth::new();
+        let mut auth = Auth::with_storage(Box::new(MemoryStorage::new())).unwrap();
 
         assert!(auth.register(
             "test_user".to_string(),
@@ -44,13 +133,7 @@ mod tests {
         let mut store = Store::new();
 
         // Test product CRUD
-        let product = Product {
-            id: Uuid::new_v4(),
-            name: "Test Product".to_string(),
-            description: "Test Description".to_string(),
-            price: 10.0,
-            quantity: 5,
-        };
+        let product = test_product(5);
 
         // Create
         assert!(store.add_product(product.clone()).is_ok());
@@ -66,22 +149,54 @@ mod tests {
         assert!(store.update_product(updated_product.clone()).is_ok());
         assert_eq!(store.get_product(&product.id).unwrap().price, 15.0);
 
-        // Delete
-        assert!(store.delete_product(&product.id).is_ok());
+        // Delete: refused while the opening stock refers to it, unless purged
+        assert!(matches!(store.delete_product(&product.id), Err(StoreError::InvalidInput(_))));
+        assert!(store.purge_product(&product.id).is_ok());
         assert!(store.get_product(&product.id).is_none());
     }
 
+    #[test]
+    fn test_product_archiving() {
+        let mut store = Store::new();
+        let product = Product { sku: Some("A-1".to_string()), ..test_product(5) };
+        store.add_product(product.clone()).unwrap();
+        store.record_sale(product.id, 2).unwrap();
+
+        store.archive_product(&product.id).unwrap();
+        assert!(store.archive_product(&product.id).is_err());
+        assert_eq!(store.products().count(), 0);
+        assert_eq!(store.archived_products().count(), 1);
+        assert!(!store.generate_inventory_report().contains("Test Product"));
+        assert!(store.generate_sales_report().contains("Product: Test Product (archived)"));
+        assert!(matches!(store.record_sale(product.id, 1), Err(StoreError::InvalidInput(_))));
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.price = 12.0;
+        assert!(store.update_product(edited).is_err());
+        // Its SKU stays taken while archived
+        let mut clash = product.clone();
+        clash.id = Uuid::new_v4();
+        assert!(store.add_product(clash).is_err());
+
+        store.restore_product(&product.id).unwrap();
+        assert!(store.restore_product(&product.id).is_err());
+        assert_eq!(store.products().count(), 1);
+        store.record_sale(product.id, 1).unwrap();
+
+        // A product nothing refers to can simply be deleted
+        let unused = Product { id: Uuid::new_v4(), quantity: 0, sku: None, ..product.clone() };
+        store.add_product(unused.clone()).unwrap();
+        store.delete_product(&unused.id).unwrap();
+
+        store.purge_product(&product.id).unwrap();
+        assert!(store.generate_sales_report().contains(&format!("Product: (deleted product {})", product.id)));
+        assert_eq!(store.transactions().len(), 3);
+    }
+
     #[test]
     fn test_transactions() {
         let mut store = Store::new();
         let product_id = Uuid::new_v4();
-        let product = Product {
-            id: product_id,
-            name: "Test Product".to_string(),
-            description: "Test Description".to_string(),
-            price: 10.0,
-            quantity: 5,
-        };
+        let product = Product { id: product_id, ..test_product(5) };
 
         store.add_product(product).unwrap();
 
@@ -103,13 +218,7 @@ mod tests {
     fn test_file_persistence() {
         let mut store = Store::new();
         let product_id = Uuid::new_v4();
-        let product = Product {
-            id: product_id,
-            name: "Test Product".to_string(),
-            description: "Test Description".to_string(),
-            price: 10.0,
-            quantity: 5,
-        };
+        let product = Product { id: product_id, ..test_product(5) };
 
         store.add_product(product).unwrap();
 
@@ -125,16 +234,31 @@ mod tests {
         fs::remove_file("test_store.json").unwrap();
     }
 
+    #[test]
+    fn test_memory_storage() {
+        let mut storage = MemoryStorage::new();
+        let mut store = Store::new();
+        assert!(!store.load(&storage).unwrap());
+
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.record_sale(product_id, 1).unwrap();
+        store.save(&mut storage).unwrap();
+
+        let mut restored = Store::new();
+        assert!(restored.load(&storage).unwrap());
+        assert_eq!(restored.get_product(&product_id).unwrap().quantity, 4);
+        assert_eq!(restored.generate_sales_report(), store.generate_sales_report());
+
+        let mut auth = Auth::with_storage(Box::new(storage)).unwrap();
+        assert!(auth.user_exists("admin"));
+        assert!(auth.login("admin", "admin123").is_ok());
+    }
+
     #[test]
     fn test_report_generation() {
         let mut store = Store::new();
-        let product = Product {
-            id: Uuid::new_v4(),
-            name: "Test Product".to_string(),
-            description: "Test Description".to_string(),
-            price: 10.0,
-            quantity: 5,
-        };
+        let product = test_product(5);
 
         store.add_product(product).unwrap();
         let report = store.generate_inventory_report();
@@ -142,4 +266,1253 @@ mod tests {
         assert!(report.contains("5"));
         assert!(report.contains("$10.00"));
     }
+
+    #[test]
+    fn test_invoice_generation() {
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+
+        let first = store.record_sale(product_id, 2).unwrap();
+        let second = store.record_sale(product_id, 1).unwrap();
+
+        // Underpayment is rejected without consuming an invoice number
+        assert!(store.issue_invoice(Checkout {
+            sale_ids: vec![first.id],
+            tax_rate: 0.1,
+            payments: vec![Payment { method: PaymentMethod::Cash, amount: 5.0 }],
+            ..Default::default()
+        }).is_err());
+
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![first.id],
+            tax_rate: 0.1,
+            payments: vec![Payment { method: PaymentMethod::Cash, amount: 30.0 }],
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(invoice.number, 1);
+        assert_eq!(invoice.total, 22.0);
+        assert_eq!(invoice.change_due(), 8.0);
+
+        // A sale can only be billed once
+        assert!(store.issue_invoice(Checkout {
+            sale_ids: vec![first.id],
+            tax_rate: 0.1,
+            payments: vec![Payment { method: PaymentMethod::Card, amount: 22.0 }],
+            ..Default::default()
+        }).is_err());
+
+        let next = store.issue_invoice(Checkout {
+            sale_ids: vec![second.id],
+            tax_rate: 0.0,
+            payments: vec![Payment { method: PaymentMethod::Card, amount: 10.0 }],
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(next.number, 2);
+
+        let header = ReceiptHeader::default();
+        let text = receipt::render_text(&invoice, &header).unwrap();
+        assert!(text.contains("INV-000001"));
+        assert!(text.contains("$22.00"));
+        assert!(receipt::render_html(&invoice, &header).unwrap().contains("<svg"));
+        let pdf = receipt::render_pdf(&invoice, &header).unwrap();
+        assert!(pdf.starts_with(b"%PDF-1.4"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+    }
+
+    #[test]
+    fn test_escpos_output() {
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+
+        let start = chrono::Utc::now();
+        let sale = store.record_sale(product_id, 2).unwrap();
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            tax_rate: 0.0,
+            payments: vec![Payment { method: PaymentMethod::Cash, amount: 25.0 }],
+            ..Default::default()
+        }).unwrap();
+
+        let header = ReceiptHeader::default();
+        let bytes = escpos::render_receipt(&invoice, &header).unwrap();
+        assert!(bytes.starts_with(&[0x1b, b'@']));
+        assert!(bytes.ends_with(&[0x1d, b'V', 66, 0]));
+        assert!(bytes.windows(4).any(|w| w == [0x1d, b'k', 4, b'I']));
+
+        let summary = store.shift_summary(start, chrono::Utc::now());
+        assert_eq!(summary.items_sold, 2);
+        assert_eq!(summary.payments, vec![("Cash".to_string(), 20.0)]);
+
+        let path = std::env::temp_dir().join(format!("shift-{}.bin", Uuid::new_v4()));
+        let report = escpos::render_shift_report(&summary, &header);
+        escpos::write_to(path.to_str().unwrap(), &report).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), report);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_gift_card_payments() {
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+
+        let card = store.issue_stored_value(StoredValueKind::GiftCard, 25.0).unwrap();
+        let credit = store.issue_stored_value(StoredValueKind::StoreCredit, 5.0).unwrap();
+        assert_ne!(card.code, credit.code);
+        assert_eq!(store.stored_value().total_liability(), 30.0);
+
+        // Balance is insufficient, so nothing is redeemed
+        let sale = store.record_sale(product_id, 3).unwrap();
+        assert!(store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            tax_rate: 0.0,
+            payments: vec![Payment { method: PaymentMethod::StoredValue(credit.code.clone()), amount: 30.0 }],
+            ..Default::default()
+        }).is_err());
+        assert_eq!(store.stored_value().balance(&credit.code).unwrap(), 5.0);
+        let short = store.stored_value().check_redeemable(&credit.code, 30.0, "€").unwrap_err();
+        assert!(short.to_string().contains("only has €5.00 available"));
+
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            tax_rate: 0.0,
+            payments: vec![
+                Payment { method: PaymentMethod::StoredValue(card.code.to_lowercase()), amount: 25.0 },
+                Payment { method: PaymentMethod::StoredValue(credit.code.clone()), amount: 5.0 },
+            ],
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(store.stored_value().balance(&card.code).unwrap(), 0.0);
+        assert_eq!(store.stored_value().history(&card.code).len(), 2);
+        assert_eq!(store.stored_value().history(&card.code)[1].invoice_number, Some(invoice.number));
+        assert_eq!(store.stored_value().total_liability(), 0.0);
+
+        let refund = store.issue_stored_value(StoredValueKind::StoreCredit, 12.5).unwrap();
+        store.void_stored_value(&refund.code).unwrap();
+        assert!(store.stored_value().check_redeemable(&refund.code, 1.0, "$").is_err());
+        assert!(store.stored_value().generate_liability_report("$").contains("Total Liability: $0.00"));
+    }
+
+    #[test]
+    fn test_coupon_redemption() {
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        let other_id = Uuid::new_v4();
+        for (id, name) in [(product_id, "Test Product"), (other_id, "Other Product")] {
+            store.add_product(Product { id, name: name.to_string(), ..test_product(10) }).unwrap();
+        }
+
+        let customer = Customer {
+            id: Uuid::new_v4(),
+            name: "Test Customer".to_string(),
+            email: None,
+            created_at: chrono::Utc::now(),
+        };
+        store.add_customer(customer.clone()).unwrap();
+
+        let coupon = Coupon {
+            id: Uuid::new_v4(),
+            name: "Ten percent off".to_string(),
+            discount: DiscountType::Percentage(10.0),
+            min_spend: 20.0,
+            product_ids: vec![product_id],
+            valid_from: chrono::Utc::now() - chrono::Duration::days(1),
+            valid_until: Some(chrono::Utc::now() + chrono::Duration::days(1)),
+            max_redemptions: Some(10),
+            max_per_customer: Some(1),
+        };
+        store.create_coupon(coupon.clone()).unwrap();
+        let codes = store.generate_coupon_codes(coupon.id, 50, Some(1)).unwrap();
+        assert_eq!(codes.iter().collect::<std::collections::HashSet<_>>().len(), 50);
+        assert!(matches!(store.generate_coupon_codes(coupon.id, 1_000_000, Some(1)), Err(StoreError::InvalidInput(_))));
+
+        let checkout = |sale_ids: Vec<Uuid>, code: &str| Checkout {
+            sale_ids,
+            tax_rate: 0.0,
+            payments: vec![Payment { method: PaymentMethod::Cash, amount: 100.0 }],
+            customer_id: Some(customer.id),
+            coupon_code: Some(code.to_string()),
+            ..Default::default()
+        };
+
+        // Below the minimum spend
+        let small = store.record_sale(product_id, 1).unwrap();
+        assert!(store.issue_invoice(checkout(vec![small.id], &codes[0])).is_err());
+
+        // Only the in-scope product is discounted
+        let scoped = store.record_sale(product_id, 2).unwrap();
+        let unscoped = store.record_sale(other_id, 2).unwrap();
+        let invoice = store.issue_invoice(checkout(vec![scoped.id, unscoped.id], &codes[0])).unwrap();
+        assert_eq!(invoice.discount, 2.0);
+        assert_eq!(invoice.total, 38.0);
+        assert_eq!(store.coupons().redemptions()[0].invoice_number, invoice.number);
+
+        // Single-use code and per-customer limit are both enforced
+        let again = store.record_sale(product_id, 3).unwrap();
+        assert!(store.issue_invoice(checkout(vec![again.id], &codes[0])).is_err());
+        assert!(store.issue_invoice(checkout(vec![again.id], &codes[1])).is_err());
+    }
+
+    #[test]
+    fn test_loyalty_points() {
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(100) }).unwrap();
+        let customer = Customer {
+            id: Uuid::new_v4(),
+            name: "Test Customer".to_string(),
+            email: None,
+            created_at: chrono::Utc::now(),
+        };
+        store.add_customer(customer.clone()).unwrap();
+
+        let mut config = LoyaltyConfig {
+            points_per_unit: 10.0,
+            point_value: 0.05,
+            expiry_days: Some(30),
+            ..LoyaltyConfig::default()
+        };
+        config.product_bonus.insert(product_id, 5);
+        store.set_loyalty_config(config).unwrap();
+
+        // $50 spent earns 500 points plus 5 bonus per item
+        let sale = store.record_sale(product_id, 5).unwrap();
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            payments: vec![Payment { method: PaymentMethod::Card, amount: 50.0 }],
+            customer_id: Some(customer.id),
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(invoice.points_earned, 525);
+
+        // 100 points are worth $5.00 off
+        let sale = store.record_sale(product_id, 1).unwrap();
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            payments: vec![Payment { method: PaymentMethod::Card, amount: 5.0 }],
+            customer_id: Some(customer.id),
+            redeem_points: 100,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(invoice.points_discount, 5.0);
+        assert_eq!(invoice.total, 5.0);
+        assert_eq!(invoice.points_earned, 55);
+
+        // Offering more points than the bill is worth only redeems what it needs
+        let sale = store.record_sale(product_id, 1).unwrap();
+        let invoice = store.issue_invoice(Checkout {
+            sale_ids: vec![sale.id],
+            customer_id: Some(customer.id),
+            redeem_points: 400,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!((invoice.points_redeemed, invoice.points_discount, invoice.total), (200, 10.0, 0.0));
+
+        let now = chrono::Utc::now();
+        assert_eq!(store.loyalty().balance(&customer.id, now), 285);
+
+        let later = now + chrono::Duration::days(31);
+        assert_eq!(store.loyalty().balance(&customer.id, later), 0);
+        let expired = store.expire_loyalty_points(later).unwrap();
+        assert_eq!(expired[0].points, 285);
+
+        let statement = store.loyalty().generate_statement(&customer.id, &customer.name, later, "$");
+        assert!(statement.contains("Total Earned: 585"));
+        assert!(statement.contains("Total Redeemed: 300"));
+        assert!(statement.contains("Total Expired: 285"));
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let mut storage = SqliteStorage::open_in_memory().unwrap();
+        let mut store = Store::new();
+        assert!(!store.load(&storage).unwrap());
+
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.save(&mut storage).unwrap();
+
+        let start = chrono::Utc::now();
+        let sale = store.record_sale(product_id, 2).unwrap();
+        store.write_through(&mut storage, &sale.id).unwrap();
+
+        let mut restored = Store::new();
+        assert!(restored.load(&storage).unwrap());
+        assert_eq!(restored.get_product(&product_id).unwrap().quantity, 3);
+        // Opening stock and the sale
+        assert_eq!(storage.product_history(&product_id).unwrap().len(), 2);
+        let sales = storage.transactions_between(TransactionType::Sale, start, chrono::Utc::now()).unwrap();
+        assert_eq!(sales[0].id, sale.id);
+        assert_eq!(storage.sales_by_product(start, chrono::Utc::now()).unwrap(), vec![(product_id, 2, 20.0)]);
+
+        // What was written through is not taken for another session's change
+        store.save(&mut storage).unwrap();
+        assert!(!store.is_dirty());
+    }
+
+    #[test]
+    fn test_sqlite_import_from_json() {
+        let dir = TempDir::new();
+        let mut json = JsonStorage::new(dir.join("store.json"), dir.join("users.json"));
+
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.record_purchase(product_id, 3, 8.0).unwrap();
+        store.save(&mut json).unwrap();
+        Auth::with_storage(Box::new(JsonStorage::new(dir.join("store.json"), dir.join("users.json")))).unwrap();
+
+        let mut sqlite = SqliteStorage::open(dir.join("store.db")).unwrap();
+        let summary = sqlite.import_json(&json).unwrap();
+        assert_eq!((summary.products, summary.transactions, summary.users), (1, 2, 1));
+
+        let mut auth = Auth::with_storage(Box::new(sqlite)).unwrap();
+        assert!(auth.login("admin", "admin123").is_ok());
+    }
+
+    #[test]
+    fn test_journal_recovery() {
+        let dir = TempDir::new();
+        let journal_path = dir.join("store.journal");
+        let mut storage = JsonStorage::store_only(dir.join("store.json"));
+
+        let mut store = Store::new();
+        store.attach_journal(Journal::open(&journal_path).unwrap()).unwrap();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.checkpoint(&mut storage).unwrap();
+        store.record_sale(product_id, 2).unwrap();
+        store.record_purchase(product_id, 4, 8.0).unwrap();
+        drop(store);
+
+        // Simulate a crash halfway through writing the next batch
+        let mut file = fs::OpenOptions::new().append(true).open(&journal_path).unwrap();
+        file.write_all(b"{\"seq\":4,\"timestamp\":").unwrap();
+        drop(file);
+
+        let mut recovered = Store::new();
+        assert!(recovered.load(&storage).unwrap());
+        assert_eq!(recovered.attach_journal(Journal::open(&journal_path).unwrap()).unwrap(), 2);
+        assert_eq!(recovered.get_product(&product_id).unwrap().quantity, 7);
+        assert_eq!(recovered.generate_sales_report().matches("Sale ID").count(), 1);
+
+        // New batches continue the sequence and survive a checkpoint
+        recovered.record_sale(product_id, 1).unwrap();
+        assert_eq!(recovered.journal().unwrap().last_seq(), 4);
+        recovered.checkpoint(&mut storage).unwrap();
+        assert!(recovered.journal().unwrap().batches().is_empty());
+
+        let mut restarted = Store::new();
+        restarted.load(&storage).unwrap();
+        assert_eq!(restarted.attach_journal(Journal::open(&journal_path).unwrap()).unwrap(), 0);
+        assert_eq!(restarted.get_product(&product_id).unwrap().quantity, 6);
+
+        // A bad line with batches after it is damage, not a torn write: nothing is cut off
+        restarted.record_sale(product_id, 1).unwrap();
+        restarted.record_sale(product_id, 1).unwrap();
+        drop(restarted);
+        let text = fs::read_to_string(&journal_path).unwrap();
+        let damaged = text.replacen("{\"seq\"", "{\"sqe\"", 1);
+        fs::write(&journal_path, &damaged).unwrap();
+        match Journal::open(&journal_path) {
+            Err(StoreError::CorruptData { line, quarantined, .. }) => {
+                assert_eq!(line, 1);
+                assert_eq!(fs::read_to_string(quarantined).unwrap(), damaged);
+            },
+            other => panic!("expected corrupt data error, got {:?}", other.err().map(|e| e.to_string())),
+        }
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn test_atomic_save_and_backups() {
+        let dir = TempDir::new();
+        let mut storage = JsonStorage::store_only(dir.join("store.json")).with_backups(2);
+
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.save(&mut storage).unwrap();
+        assert!(storage.backups().unwrap().is_empty());
+
+        for _ in 0..3 {
+            store.record_sale(product_id, 1).unwrap();
+            store.save(&mut storage).unwrap();
+        }
+
+        // Only the two most recent versions are kept, and no temp file is left behind
+        let backups = storage.backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(backups[0].taken_at >= backups[1].taken_at);
+        assert!(!dir.join("store.json.tmp").exists());
+
+        let previous = storage.read_backup(&backups[0]).unwrap();
+        assert_eq!(previous.products[&product_id].quantity, 3);
+        storage.save_store(&previous.view()).unwrap();
+
+        let mut restored = Store::new();
+        restored.load(&storage).unwrap();
+        assert_eq!(restored.get_product(&product_id).unwrap().quantity, 3);
+    }
+
+    #[test]
+    fn test_corrupt_file_repair() {
+        let dir = TempDir::new();
+        let store_path = dir.join("store.json");
+        let users_path = dir.join("users.json");
+        let mut storage = JsonStorage::new(&store_path, &users_path);
+
+        let mut store = Store::new();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, ..test_product(5) }).unwrap();
+        store.record_sale(product_id, 2).unwrap();
+        store.record_purchase(product_id, 4, 8.0).unwrap();
+        store.save(&mut storage).unwrap();
+        Auth::with_storage(Box::new(JsonStorage::new(&store_path, &users_path))).unwrap();
+
+        // Cut the file off part-way through the last transaction
+        let text = fs::read_to_string(&store_path).unwrap();
+        let cut = text[..text.find("\"invoices\"").unwrap()].rfind("\"timestamp\"").unwrap();
+        fs::write(&store_path, &text[..cut]).unwrap();
+        fs::write(&users_path, "{\"admin\": {\"id\": ").unwrap();
+
+        let mut damaged = Store::new();
+        match damaged.load_from_file(store_path.to_str().unwrap()) {
+            Err(StoreError::CorruptData { line, column, .. }) => assert_eq!((line, column), (1, cut)),
+            other => panic!("expected corrupt data error, got {:?}", other.err()),
+        }
+        assert!(!store_path.exists());
+        assert_eq!(storage.quarantined_store_files().unwrap().len(), 1);
+        assert!(matches!(
+            Auth::with_storage(Box::new(JsonStorage::new(&store_path, &users_path))),
+            Err(StoreError::CorruptData { .. })
+        ));
+
+        let report = repair::repair(&mut storage).unwrap();
+        assert_eq!((report.products, report.transactions, report.users), (1, 2, 0));
+
+        let mut repaired = Store::new();
+        assert!(repaired.load(&storage).unwrap());
+        assert_eq!(repaired.get_product(&product_id).unwrap().quantity, 7);
+        assert_eq!(repaired.generate_sales_report().matches("Sale ID").count(), 1);
+        let mut auth = Auth::with_storage(Box::new(JsonStorage::new(&store_path, &users_path))).unwrap();
+        assert!(auth.login("admin", "admin123").is_ok());
+    }
+
+    #[test]
+    fn test_schema_migration() {
+        let dir = TempDir::new();
+        let store_path = dir.join("store.json");
+        let mut storage = JsonStorage::store_only(&store_path);
+
+        // A file from before invoices and versioning existed
+        let product_id = Uuid::new_v4();
+        let legacy = format!(
+            "{{\"products\":{{\"{0}\":{{\"id\":\"{0}\",\"name\":\"Widget\",\"description\":\"\",\"price\":2.5,\"quantity\":4}}}},\"transactions\":[]}}",
+            product_id
+        );
+        fs::write(&store_path, &legacy).unwrap();
+
+        let report = storage.plan_migration().unwrap().unwrap();
+        assert_eq!((report.from_version, report.to_version), (0, SCHEMA_VERSION));
+        assert!(report.generate_report().contains("added empty invoices section"));
+        assert!(report.generate_report().contains("recorded the existing state as event 1"));
+        assert_eq!(fs::read_to_string(&store_path).unwrap(), legacy);
+
+        let mut store = Store::new();
+        assert!(store.load(&storage).unwrap());
+        assert_eq!(store.get_product(&product_id).unwrap().quantity, 4);
+        // Nothing changed since loading, so only a full save rewrites it
+        store.save_all(&mut storage).unwrap();
+
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&store_path).unwrap()).unwrap();
+        assert_eq!(migrations::schema_version(&saved), SCHEMA_VERSION);
+        assert!(storage.plan_migration().unwrap().unwrap().is_current());
+
+        // Files from a newer version are refused rather than misread
+        fs::write(&store_path, "{\"schema_version\":999,\"products\":{},\"transactions\":[]}").unwrap();
+        assert!(store.load(&storage).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let dir = TempDir::new();
+        let store_path = dir.join("store.json");
+        let users_path = dir.join("users.json");
+        crypto::generate_key_file(dir.join("store.key")).unwrap();
+        crypto::generate_key_file(dir.join("other.key")).unwrap();
+        assert!(crypto::generate_key_file(dir.join("store.key")).is_err());
+        let key = Cipher::from_key_file(dir.join("store.key")).unwrap();
+        let open = |cipher: Option<Cipher>| JsonStorage::new(&store_path, &users_path).with_cipher(cipher);
+
+        let mut storage = open(Some(key.clone()));
+        let mut store = Store::new();
+        store.attach_journal(Journal::open_with_cipher(dir.join("store.journal"), Some(key.clone())).unwrap()).unwrap();
+        let product_id = Uuid::new_v4();
+        store.add_product(Product { id: product_id, name: "Secret Sauce".to_string(), ..test_product(5) }).unwrap();
+        store.save(&mut storage).unwrap();
+        store.record_sale(product_id, 1).unwrap();
+        Auth::with_storage(Box::new(open(Some(key.clone())))).unwrap();
+        for file in ["store.json", "users.json", "store.journal"] {
+            let bytes = fs::read(dir.join(file)).unwrap();
+            assert!(!String::from_utf8_lossy(&bytes).contains("Secret Sauce"));
+            assert!(!String::from_utf8_lossy(&bytes).contains("admin"));
+        }
+
+        let mut restored = Store::new();
+        restored.load(&open(Some(key.clone()))).unwrap();
+        restored.attach_journal(Journal::open_with_cipher(dir.join("store.journal"), Some(key.clone())).unwrap()).unwrap();
+        assert_eq!(restored.get_product(&product_id).unwrap().quantity, 4);
+
+        let other = Cipher::from_key_file(dir.join("other.key")).unwrap();
+        assert!(matches!(restored.load(&open(Some(other.clone()))), Err(StoreError::WrongKey(_))));
+        assert!(matches!(restored.load(&open(None)), Err(StoreError::KeyRequired(_))));
+        assert!(matches!(
+            Journal::open_with_cipher(dir.join("store.journal"), Some(other)),
+            Err(StoreError::WrongKey(_))
+        ));
+
+        // Rotating to a passphrase re-encrypts both files
+        assert_eq!(storage.rotate_key(Some(Cipher::from_passphrase("correct horse").unwrap()), &[]).unwrap(), 2);
+        let rotated = open(Some(Cipher::from_passphrase("correct horse").unwrap()));
+        assert!(rotated.load_store().unwrap().is_some());
+        let mut auth = Auth::with_storage(Box::new(rotated)).unwrap();
+        assert!(auth.login("admin", "admin123").is_ok());
+        assert!(matches!(open(Some(key)).load_store(), Err(StoreError::WrongKey(_))));
+
+        // A rotation cut short before every file was written is thrown away...
+        let marker = dir.join("store.json.rotation");
+        let rotating = dir.join("store.json.rotating");
+        let rotation = |written| serde_json::to_vec(&Rotation { files: vec![store_path.clone(), users_path.clone()], written }).unwrap();
+        let before = fs::read(&store_path).unwrap();
+        fs::write(&rotating, b"partial").unwrap();
+        fs::write(&marker, rotation(false)).unwrap();
+        assert_eq!(open(None).recover_rotation().unwrap(), Some(RotationRecovery::RolledBack));
+        assert_eq!(fs::read(&store_path).unwrap(), before);
+        assert!(!rotating.exists() && !marker.exists());
+        assert_eq!(open(None).recover_rotation().unwrap(), None);
+
+        // ...and one cut short after is finished, whichever files were already moved
+        let passphrase = Cipher::from_passphrase("correct horse").unwrap();
+        let rewritten = passphrase.encrypt(&passphrase.decrypt(&before, "store").unwrap()).unwrap();
+        fs::write(&rotating, &rewritten).unwrap();
+        fs::write(&marker, rotation(true)).unwrap();
+        assert_eq!(open(None).recover_rotation().unwrap(), Some(RotationRecovery::Finished));
+        assert_eq!(fs::read(&store_path).unwrap(), rewritten);
+        assert!(!rotating.exists() && !marker.exists());
+
+        // A damaged file is told apart from a wrong key and moved aside
+        let mut bytes = fs::read(&store_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&store_path, &bytes).unwrap();
+        assert!(matches!(open(Some(Cipher::from_key_file(dir.join("other.key")).unwrap())).load_store(),
+            Err(StoreError::WrongKey(_))));
+        assert!(store_path.exists());
+        assert!(matches!(
+            open(Some(Cipher::from_passphrase("correct horse").unwrap())).load_store(),
+            Err(StoreError::CorruptSnapshot { .. })
+        ));
+        assert!(!store_path.exists());
+    }
+
+    #[test]
+    fn test_settings() {
+        let dir = TempDir::new();
+        let config_path = dir.join("rusty_store.toml");
+        fs::write(&config_path, concat!(
+            "data_dir = \"data\"\n",
+            "store_name = \"Corner Shop\"\n",
+            "currency = \"€\"\n",
+            "timezone = \"Europe/Berlin\"\n",
+            "[backup]\nkeep = 2\n",
+        )).unwrap();
+
+        // Relative paths are resolved against the config file
+        let mut settings = Settings::from_file(&config_path).unwrap();
+        assert_eq!(settings.data_dir, dir.join("data"));
+        assert_eq!(settings.timezone, Tz::Europe__Berlin);
+        assert_eq!(settings.backup.keep, 2);
+        assert_eq!(settings.json_storage().store_path(), dir.join("data").join("store.json"));
+
+        // Overrides win over the file, and bad values are rejected
+        settings.apply_overrides(|key| match key {
+            "currency" => Some("£".to_string()),
+            "backups" => Some("7".to_string()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(settings.currency, "£");
+        assert_eq!(settings.backup.keep, 7);
+        assert!(settings.apply_overrides(|key| (key == "timezone").then(|| "Mars/Olympus".to_string())).is_err());
+        let args = |extra: &[&str]| -> Vec<String> {
+            ["--config", config_path.to_str().unwrap()].iter().chain(extra).map(|a| a.to_string()).collect()
+        };
+        assert_eq!(Settings::load(&args(&["--currency", "¥"])).unwrap().currency, "¥");
+        assert!(Settings::load(&args(&["--currency"])).is_err());
+        fs::write(dir.join("typo.toml"), "curency = \"$\"\n").unwrap();
+        assert!(Settings::from_file(dir.join("typo.toml")).is_err());
+
+        // The store and receipts format with the configured currency and zone
+        fs::create_dir_all(&settings.data_dir).unwrap();
+        let mut store = Store::with_settings(settings.clone());
+        store.add_product(Product {
+            name: "Bread".to_string(),
+            description: "Rye".to_string(),
+            price: 3.5,
+            ..test_product(4)
+        }).unwrap();
+        assert!(store.generate_inventory_report().contains("Price: £3.50"));
+        assert!(store.load(&MemoryStorage::new()).is_ok());
+        assert_eq!(store.settings().currency, "£");
+
+        let header = ReceiptHeader::from_settings(store.settings());
+        assert_eq!(header.name, "Corner Shop");
+        let winter = chrono::DateTime::parse_from_rfc3339("2024-01-15T12:00:00Z").unwrap().with_timezone(&chrono::Utc);
+        assert_eq!(header.time(winter), "2024-01-15 13:00 CET");
+        assert_eq!(header.money(-1.5), "-£1.50");
+
+        // Auth keeps its users in the data directory
+        let auth = Auth::with_settings(&settings, None).unwrap();
+        assert!(auth.user_exists("admin"));
+        assert!(settings.data_file("users.json").exists());
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let dir = TempDir::new();
+
+        // Only one session may hold the data directory at a time
+        let lock = DataLock::acquire(&dir).unwrap();
+        assert!(matches!(DataLock::acquire(&dir), Err(StoreError::Conflict(_))));
+        drop(lock);
+        assert!(DataLock::acquire(&dir).is_ok());
+
+        // An edit made from a stale copy is refused
+        let mut storage = MemoryStorage::new();
+        let mut first = Store::new();
+        let product = test_product(5);
+        first.add_product(product.clone()).unwrap();
+        first.record_sale(product.id, 1).unwrap();
+        let mut stale = product.clone();
+        stale.price = 12.0;
+        assert!(matches!(first.update_product(stale), Err(StoreError::Conflict(_))));
+        let mut fresh = first.get_product(&product.id).unwrap().clone();
+        fresh.price = 12.0;
+        first.update_product(fresh).unwrap();
+        assert_eq!(first.get_product(&product.id).unwrap().version, 2);
+        first.save(&mut storage).unwrap();
+
+        // Two sessions load the same data; the second to save is stopped
+        let mut second = Store::new();
+        second.load(&storage).unwrap();
+        first.record_sale(product.id, 1).unwrap();
+        second.record_sale(product.id, 2).unwrap();
+        first.save(&mut storage).unwrap();
+        assert!(matches!(second.save(&mut storage), Err(StoreError::Conflict(_))));
+        assert_eq!(storage.load_store().unwrap().unwrap().products[&product.id].quantity, 3);
+
+        // After reloading it can save again
+        second.load(&storage).unwrap();
+        second.record_sale(product.id, 2).unwrap();
+        second.save(&mut storage).unwrap();
+        assert_eq!(storage.load_store().unwrap().unwrap().products[&product.id].quantity, 1);
+
+        // A file written by another session is noticed without comparing its contents
+        let path = dir.join("store.json");
+        let (mut first_file, mut second_file) = (JsonStorage::store_only(&path), JsonStorage::store_only(&path));
+        first.save_all(&mut first_file).unwrap();
+        second.load(&second_file).unwrap();
+        assert_eq!(first_file.revision().unwrap(), second_file.revision().unwrap());
+        first.record_sale(product.id, 1).unwrap();
+        first.save(&mut first_file).unwrap();
+        second.record_sale(product.id, 1).unwrap();
+        assert!(matches!(second.save(&mut second_file), Err(StoreError::Conflict(_))));
+    }
+
+    #[test]
+    fn test_csv_import_export() {
+        let mut store = Store::new();
+        let existing_id = Uuid::new_v4();
+        store.add_product(Product {
+            id: existing_id,
+            name: "Old Name".to_string(),
+            description: "Kept".to_string(),
+            price: 1.0,
+            sku: Some("A-1".to_string()),
+            ..test_product(3)
+        }).unwrap();
+
+        let csv = "Item Code,Title,Unit Price,Stock\n\
+                   A-1,Apples,2.50,\n\
+                   B-2,\"Bread, rye\",3.00,10\n\
+                   C-3,Cheese,free,1\n\
+                   ,Nameless,1.00,1\n\
+                   B-2,Bread again,3.10,4\n\
+                   D-4,Dates,4.00,-2\n";
+        let mapping = ColumnMapping::parse("sku=item code, name=title, price=Unit Price, quantity=stock").unwrap();
+        let report = csv_io::import_products(&mut store, csv.as_bytes(), &mapping).unwrap();
+        assert_eq!((report.created, report.updated), (1, 1));
+        let lines: Vec<u64> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![4, 5, 6, 7]);
+        assert!(report.generate_report().contains("Line 6 (B-2): SKU repeats line 3"));
+
+        // Upserted by SKU, keeping columns the file does not have
+        let apples = store.get_product(&existing_id).unwrap();
+        assert_eq!((apples.name.as_str(), apples.price, apples.quantity), ("Apples", 2.5, 3));
+        assert_eq!(apples.description, "Kept");
+        assert_eq!(store.find_product_by_sku("B-2").unwrap().name, "Bread, rye");
+        assert!(store.find_product_by_sku("C-3").is_none());
+
+        // A missing required column fails the whole import
+        assert!(csv_io::import_products(&mut store, "sku,price\nX,1\n".as_bytes(), &ColumnMapping::default()).is_err());
+        assert!(ColumnMapping::parse("colour=Color").is_err());
+
+        let mut out = Vec::new();
+        assert_eq!(csv_io::export_products(&store, &mut out).unwrap(), 2);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("id,sku,name,description,price,quantity\n"));
+        assert!(text.contains("\"Bread, rye\""));
+
+        // Exported products import back unchanged
+        let mut copy = Store::new();
+        let report = csv_io::import_products(&mut copy, text.as_bytes(), &ColumnMapping::default()).unwrap();
+        assert_eq!((report.created, report.errors.len()), (2, 0));
+        assert_eq!(copy.find_product_by_sku("A-1").unwrap().price, 2.5);
+
+        let bread = store.find_product_by_sku("B-2").unwrap().id;
+        store.record_sale(bread, 2).unwrap();
+        store.record_purchase(existing_id, 5, 1.5).unwrap();
+        let now = chrono::Utc::now();
+        let mut out = Vec::new();
+        let exported = csv_io::export_transactions(&store, &mut out, Some(now - chrono::Duration::hours(1)), None).unwrap();
+        // Two opening stock adjustments, the sale and the purchase
+        assert_eq!(exported, 4);
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(",sale,") && text.contains(",B-2,") && text.contains(",6.00\n"));
+        let mut out = Vec::new();
+        assert_eq!(csv_io::export_transactions(&store, &mut out, None, Some(now - chrono::Duration::hours(1))).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stock_history() {
+        let pause = || std::thread::sleep(std::time::Duration::from_millis(2));
+        let mut store = Store::new();
+        let product = test_product(5);
+        let before = chrono::Utc::now();
+        pause();
+        store.add_product(product.clone()).unwrap();
+        store.record_purchase(product.id, 5, 4.0).unwrap();
+        pause();
+        let opened = chrono::Utc::now();
+        pause();
+        store.record_sale(product.id, 3).unwrap();
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.quantity = 6;
+        store.update_product(edited).unwrap();
+
+        assert!(store.stock_as_of(before).levels.is_empty());
+        let report = store.stock_as_of(opened);
+        assert_eq!(report.levels[0].quantity, 10);
+        assert_eq!((report.levels[0].unit_cost, report.total_value), (4.0, 40.0));
+        assert_eq!(store.stock_as_of(chrono::Utc::now()).levels[0].quantity, 6);
+        assert!(report.generate_report(store.settings()).contains("Total Value: $40.00"));
+        assert!(store.check_inventory().is_empty());
+
+        // Stock recorded before opening balances were kept shows up as drift
+        let mut data = store.to_data();
+        data.events.clear();
+        data.products.get_mut(&product.id).unwrap().quantity = 9;
+        let mut legacy = Store::from_data(data);
+        let drift = legacy.check_inventory();
+        assert_eq!((drift[0].recorded, drift[0].ledger, drift[0].difference()), (9, 6, 3));
+
+        let adjustments = legacy.reconcile_inventory().unwrap();
+        assert_eq!(adjustments[0].quantity, 3);
+        assert!(legacy.check_inventory().is_empty());
+        assert_eq!(legacy.get_product(&product.id).unwrap().quantity, 9);
+    }
+
+    #[test]
+    fn test_event_sourcing() {
+        let dir = TempDir::new();
+        let mut storage = JsonStorage::store_only(dir.join("store.json"));
+
+        let mut store = Store::new();
+        let product = test_product(5);
+        store.add_product(product.clone()).unwrap();
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.price = 12.0;
+        edited.quantity = 8;
+        store.update_product(edited).unwrap();
+        store.record_sale(product.id, 2).unwrap();
+
+        let kinds: Vec<&str> = store.product_events(&product.id).iter()
+            .map(|e| match e.event {
+                Event::ProductCreated { .. } => "created",
+                Event::PriceChanged { .. } => "price",
+                Event::StockAdjusted(_) => "adjusted",
+                Event::SaleRecorded(_) => "sale",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(kinds, ["created", "price", "adjusted", "sale"]);
+        assert_eq!(store.get_product(&product.id).unwrap().quantity, 6);
+        assert!(store.check_inventory().is_empty());
+        assert!(store.generate_audit_report(10).contains("changed to $12.00"));
+
+        // A new view built from the same log
+        #[derive(Default)]
+        struct Revenue(f64);
+        impl Projection for Revenue {
+            fn apply(&mut self, event: &RecordedEvent) {
+                if let Event::SaleRecorded(sale) = &event.event {
+                    self.0 += sale.price * sale.quantity as f64;
+                }
+            }
+        }
+        let mut revenue = Revenue::default();
+        store.project(&mut revenue);
+        assert_eq!(revenue.0, 24.0);
+
+        // State always comes from the log, never from the saved copy of it
+        store.rebuild();
+        assert_eq!(store.get_product(&product.id).unwrap().quantity, 6);
+        let mut data = store.to_data();
+        data.products.get_mut(&product.id).unwrap().quantity = 99;
+        storage.save_store(&data.view()).unwrap();
+        let mut loaded = Store::new();
+        assert!(loaded.load(&storage).unwrap());
+        assert_eq!(loaded.get_product(&product.id).unwrap().quantity, 6);
+        assert_eq!(loaded.events().len(), 4);
+    }
+
+    #[test]
+    fn test_transaction_retention() {
+        let dir = TempDir::new();
+        let mut storage = JsonStorage::store_only(dir.join("store.json"));
+
+        let mut store = Store::new();
+        store.attach_archives(ArchiveDir::new(dir.join("archive")));
+        let product = test_product(10);
+        let start = chrono::Utc::now();
+        store.add_product(product.clone()).unwrap();
+        store.record_purchase(product.id, 5, 4.0).unwrap();
+        let after_purchase = chrono::Utc::now();
+        store.record_sale(product.id, 3).unwrap();
+
+        // Nothing is older than a cutoff in the past
+        let past = start - chrono::Duration::days(1);
+        assert!(store.archive_transactions(past, Period::Day, &mut storage).unwrap().is_none());
+
+        let cutoff = chrono::Utc::now() + chrono::Duration::hours(1);
+        let archive = store.archive_transactions(cutoff, Period::Day, &mut storage).unwrap().unwrap();
+        assert_eq!(archive.transactions, 3);
+        assert!(dir.join("archive").join(&archive.file).exists());
+        assert_eq!(store.summaries().len(), 3);
+        assert!(matches!(store.events()[0].event, Event::SnapshotImported(_)));
+
+        // Totals and stock still add up from the summaries
+        assert_eq!(store.get_product(&product.id).unwrap().quantity, 12);
+        assert!(store.check_inventory().is_empty());
+        assert!(store.generate_sales_report().contains("Archived Period:"));
+        assert!(store.generate_sales_report().contains("Total Sales: $30.00"));
+        assert!(store.generate_purchase_report().contains("Total Purchases: $20.00"));
+
+        // The raw records come back from the archive on demand
+        assert_eq!(store.archived_transactions(start, cutoff).unwrap().len(), 3);
+        assert!(store.product_transactions(&product.id).is_empty());
+        assert!(matches!(store.delete_product(&product.id), Err(StoreError::InvalidInput(_))));
+        let stock = store.stock_as_of_archived(after_purchase).unwrap();
+        assert_eq!(stock.levels[0].quantity, 15);
+
+        store.record_sale(product.id, 1).unwrap();
+        store.save(&mut storage).unwrap();
+        let mut loaded = Store::new();
+        assert!(loaded.load(&storage).unwrap());
+        assert_eq!(loaded.get_product(&product.id).unwrap().quantity, 11);
+        assert_eq!(loaded.archives().len(), 1);
+        assert!(loaded.check_inventory().is_empty());
+
+        // Reloading (as a login does) keeps the attached archive directory
+        assert!(store.load(&storage).unwrap());
+        assert_eq!(store.archived_transactions(start, cutoff).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_offline_sync() {
+        let product = |name: &str, sku: &str, price: f64, quantity: i32| Product {
+            name: name.to_string(),
+            description: String::new(),
+            price,
+            sku: Some(sku.to_string()),
+            ..test_product(quantity)
+        };
+        let mut home = Store::new();
+        let apples = product("Apples", "A-1", 10.0, 10);
+        let pears = product("Pears", "P-1", 5.0, 10);
+        home.add_product(apples.clone()).unwrap();
+        home.add_product(pears.clone()).unwrap();
+
+        // The stall starts the day with a copy of the home store
+        let mut stall = Store::from_data(home.to_data());
+        stall.record_sale(apples.id, 3).unwrap();
+        let mut edited = stall.get_product(&apples.id).unwrap().clone();
+        edited.price = 11.0;
+        stall.update_product(edited).unwrap();
+        let cherries = product("Cherries", "C-1", 2.0, 5);
+        stall.add_product(cherries.clone()).unwrap();
+        stall.record_sale(cherries.id, 1).unwrap();
+
+        home.record_sale(apples.id, 2).unwrap();
+        let mut edited = home.get_product(&apples.id).unwrap().clone();
+        edited.price = 12.0;
+        home.update_product(edited).unwrap();
+        let mut edited = home.get_product(&pears.id).unwrap().clone();
+        edited.price = 6.0;
+        home.update_product(edited).unwrap();
+        home.add_product(product("Cherry Tomatoes", "C-1", 3.0, 0)).unwrap();
+
+        let mut file = Vec::new();
+        stall.export_changes(0).write(&mut file).unwrap();
+        let changeset = Changeset::read(file.as_slice()).unwrap();
+        let report = home.merge_changes(&changeset).unwrap();
+        assert_eq!(report.products_added, 1);
+        assert_eq!(report.transactions_added, 2);
+        assert_eq!(report.conflicts.len(), 2);
+        assert!(report.generate_report().contains("kept $12.00 over $11.00"));
+        assert!(report.generate_report().contains("SKU C-1 is already used by Cherry Tomatoes"));
+
+        // Both days' sales count, the later price wins and untouched edits survive
+        assert_eq!(home.get_product(&apples.id).unwrap().quantity, 5);
+        assert_eq!(home.get_product(&apples.id).unwrap().price, 12.0);
+        assert_eq!(home.get_product(&pears.id).unwrap().price, 6.0);
+        assert_eq!(home.get_product(&cherries.id).unwrap().quantity, 4);
+        assert_eq!(home.get_product(&cherries.id).unwrap().sku, None);
+        assert!(home.check_inventory().is_empty());
+
+        // Merging the same changes again adds nothing
+        let events = home.events().len();
+        let again = home.merge_changes(&changeset).unwrap();
+        assert_eq!((again.products_added, again.transactions_added), (0, 0));
+        assert_eq!(home.events().len(), events);
+        assert_eq!(home.get_product(&apples.id).unwrap().quantity, 5);
+    }
+
+    #[test]
+    fn test_binary_snapshot() {
+        let dir = TempDir::new();
+        let path = dir.join(snapshot::BINARY_STORE_FILE);
+        let mut storage = JsonStorage::store_only(&path).with_format(StoreFormat::Binary);
+
+        let mut store = Store::new();
+        let product = Product { sku: Some("TP-1".to_string()), ..test_product(5) };
+        store.add_product(product.clone()).unwrap();
+        store.record_sale(product.id, 2).unwrap();
+        store.save(&mut storage).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let header = snapshot::Header::parse(&bytes).unwrap();
+        assert_eq!((header.format_version, header.schema_version), (snapshot::FORMAT_VERSION, SCHEMA_VERSION));
+        let mut loaded = Store::new();
+        assert!(loaded.load(&storage).unwrap());
+        assert_eq!(loaded.get_product(&product.id).unwrap().quantity, 3);
+        assert_eq!(loaded.events().len(), store.events().len());
+
+        // Configuring JSON before converting keeps using the binary file, not an empty store
+        let settings = Settings { data_dir: dir.to_path_buf(), ..Settings::default() };
+        assert_eq!(settings.store_format, StoreFormat::Json);
+        assert_eq!(settings.json_storage().format(), StoreFormat::Binary);
+        assert_eq!(settings.json_storage().load_store().unwrap().unwrap().products, store.to_data().products);
+
+        // Converting to JSON and back gives the same store
+        let mut json = JsonStorage::store_only(dir.join("store.json"));
+        json.save_store(&storage.load_store().unwrap().unwrap().view()).unwrap();
+        storage.save_store(&json.load_store().unwrap().unwrap().view()).unwrap();
+        assert_eq!(storage.load_store().unwrap().unwrap().products, store.to_data().products);
+
+        // The compact encoding is well under the size of the JSON
+        assert!(fs::read(&path).unwrap().len() * 2 < fs::read(dir.join("store.json")).unwrap().len());
+
+        // A snapshot that passes its checksum is loaded as it is, not replayed
+        let mut state = store.to_data();
+        state.products.get_mut(&product.id).unwrap().quantity = 42;
+        fs::write(&path, snapshot::encode(&state.view()).unwrap()).unwrap();
+        assert!(loaded.load(&storage).unwrap());
+        assert_eq!(loaded.get_product(&product.id).unwrap().quantity, 42);
+        assert_eq!(loaded.product_transactions(&product.id).len(), 2);
+
+        // A snapshot from another schema is refused but left in place
+        let mut legacy = store.to_data();
+        legacy.schema_version = 1;
+        fs::write(&path, snapshot::encode(&legacy.view()).unwrap()).unwrap();
+        assert!(matches!(storage.load_store(), Err(StoreError::InvalidInput(_))));
+        assert!(path.exists());
+
+        // A damaged payload fails its checksum and is set aside
+        let mut damaged = fs::read(&path).unwrap();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        fs::write(&path, damaged).unwrap();
+        assert!(matches!(storage.load_store(), Err(StoreError::CorruptSnapshot { .. })));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_ledger_integrity() {
+        let dir = TempDir::new();
+        crypto::generate_key_file(dir.join("signing.key")).unwrap();
+        crypto::generate_key_file(dir.join("other.key")).unwrap();
+        let signer = Signer::from_key_file(dir.join("signing.key")).unwrap();
+        let mut storage = JsonStorage::store_only(dir.join("store.json")).with_signer(Some(signer.clone()));
+
+        let mut store = Store::new();
+        let product = test_product(0);
+        store.add_product(product.clone()).unwrap();
+        store.record_purchase(product.id, 10, 4.0).unwrap();
+        store.record_sale(product.id, 2).unwrap();
+        let sale = store.record_sale(product.id, 3).unwrap();
+        assert_eq!(sale.prev_hash, store.transactions().last().unwrap().prev_hash);
+        assert!(sale.prev_hash.is_some());
+        store.save(&mut storage).unwrap();
+
+        // Each transaction points back to the one before it
+        let data = storage.load_store().unwrap().unwrap();
+        assert_eq!(data.transactions[0].prev_hash.as_deref(), Some(integrity::GENESIS_HASH));
+        assert_eq!(data.transactions[1].prev_hash, Some(integrity::transaction_hash(&data.transactions[0])));
+        let report = integrity::verify(&data, Some(&signer)).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.signature, SignatureStatus::Valid);
+
+        // Editing, removing or inserting a transaction breaks the chain
+        let mut edited = data.clone();
+        edited.transactions[1].quantity = 1;
+        assert_eq!(integrity::verify_chain(&edited.transactions, Some(integrity::GENESIS_HASH))[0].position, 3);
+        let report = integrity::verify(&edited, Some(&signer)).unwrap();
+        assert_eq!((report.log_mismatch, report.signature), (Some(2), SignatureStatus::Invalid));
+        let mut reassigned = data.clone();
+        reassigned.transactions[1].user = Some("mallory".to_string());
+        assert_eq!(integrity::verify_chain(&reassigned.transactions, Some(integrity::GENESIS_HASH))[0].position, 3);
+        let mut removed = data.clone();
+        removed.transactions.remove(1);
+        assert_eq!(integrity::verify_chain(&removed.transactions, Some(integrity::GENESIS_HASH))[0].position, 2);
+        let mut inserted = data.clone();
+        inserted.transactions.insert(1, data.transactions[2].clone());
+        assert!(!integrity::verify(&inserted, None).unwrap().is_intact());
+
+        // A binary snapshot's ledger is still checked against its event log
+        let binary = dir.join(snapshot::BINARY_STORE_FILE);
+        fs::write(&binary, snapshot::encode(&edited.view()).unwrap()).unwrap();
+        let loaded = JsonStorage::store_only(&binary).with_format(StoreFormat::Binary).load_store().unwrap().unwrap();
+        assert!(loaded.checksummed);
+        assert_eq!(integrity::verify(&loaded, None).unwrap().log_mismatch, Some(2));
+
+        // A file signed with another key, or not signed, is reported as such
+        let other = Signer::from_key_file(dir.join("other.key")).unwrap();
+        assert_eq!(integrity::verify(&data, Some(&other)).unwrap().signature, SignatureStatus::Invalid);
+        assert_eq!(integrity::verify(&data, None).unwrap().signature, SignatureStatus::NotChecked);
+        assert_eq!(integrity::verify(&store.to_data(), Some(&signer)).unwrap().signature, SignatureStatus::Unsigned);
+    }
+
+    #[test]
+    fn test_incremental_saves() {
+        let dir = TempDir::new();
+        let mut storage = SqliteStorage::open(dir.join("store.db")).unwrap();
+
+        let mut store = Store::new();
+        let product = test_product(5);
+        store.add_product(product.clone()).unwrap();
+        assert!(store.is_dirty() && store.changes().is_none());
+        store.save(&mut storage).unwrap();
+        assert!(!store.is_dirty());
+
+        // Only what changed since is handed to the backend
+        let unsold = Product { id: Uuid::new_v4(), name: "Unsold".to_string(), quantity: 0, ..product.clone() };
+        store.add_product(unsold.clone()).unwrap();
+        store.save(&mut storage).unwrap();
+        store.record_sale(product.id, 2).unwrap();
+        store.delete_product(&unsold.id).unwrap();
+        store.add_customer(Customer {
+            id: Uuid::new_v4(),
+            name: "Ada".to_string(),
+            email: None,
+            created_at: chrono::Utc::now(),
+        }).unwrap();
+        let changes = store.changes().unwrap();
+        assert_eq!(changes.products.iter().map(|p| p.id).collect::<Vec<_>>(), vec![product.id]);
+        assert_eq!(changes.deleted_products, vec![unsold.id]);
+        assert_eq!(changes.transactions.len(), 1);
+        assert_eq!(changes.events.len(), 3);
+        assert!(changes.documents.is_some());
+        store.save(&mut storage).unwrap();
+
+        let mut loaded = Store::new();
+        assert!(loaded.load(&storage).unwrap());
+        assert!(!loaded.is_dirty());
+        assert_eq!(loaded.get_product(&product.id).unwrap().quantity, 3);
+        assert!(loaded.get_product(&unsold.id).is_none());
+        assert_eq!(loaded.customers().count(), 1);
+        assert_eq!(loaded.transactions().iter().map(|t| t.id).collect::<Vec<_>>(),
+            store.transactions().iter().map(|t| t.id).collect::<Vec<_>>());
+        assert_eq!(loaded.events().len(), store.events().len());
+
+        // A clean store does not write at all
+        let path = dir.join("store.json");
+        let mut json = JsonStorage::store_only(&path);
+        loaded.save(&mut json).unwrap();
+        assert!(!path.exists());
+        loaded.save_all(&mut json).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_transaction_index() {
+        let mut store = Store::new();
+        let product = test_product(5);
+        let other = Product { id: Uuid::new_v4(), name: "Other Product".to_string(), ..product.clone() };
+        store.add_product(product.clone()).unwrap();
+        store.add_product(other.clone()).unwrap();
+
+        store.set_user(Some("alice".to_string()));
+        let start = chrono::Utc::now();
+        let sale = store.record_sale(product.id, 2).unwrap();
+        store.set_user(Some("bob".to_string()));
+        store.record_purchase(other.id, 3, 4.0).unwrap();
+        store.record_sale(other.id, 1).unwrap();
+        let end = chrono::Utc::now() + chrono::Duration::seconds(1);
+
+        // Each lookup returns the same transactions, in the same order, as a scan would
+        assert_eq!(store.product_transactions(&product.id).len(), 2);
+        assert_eq!(store.product_transactions(&other.id).len(), 3);
+        assert_eq!(store.transactions_of_type(&TransactionType::Sale).iter().map(|t| t.id).collect::<Vec<_>>(),
+            store.transactions().iter().filter(|t| t.transaction_type == TransactionType::Sale).map(|t| t.id).collect::<Vec<_>>());
+        assert_eq!(store.transactions_between(start, end).len(), 3);
+        assert!(store.transactions_between(end, start).is_empty());
+        assert_eq!(store.user_transactions("alice").iter().map(|t| t.id).collect::<Vec<_>>(), vec![sale.id]);
+        assert_eq!(store.user_transactions("bob").len(), 2);
+
+        // Who recorded what survives a reload, and the index is rebuilt from it
+        let mut storage = MemoryStorage::new();
+        store.save(&mut storage).unwrap();
+        let mut loaded = Store::new();
+        loaded.load(&storage).unwrap();
+        assert_eq!(loaded.user_transactions("bob").len(), 2);
+        loaded.rebuild();
+        assert_eq!(loaded.product_transactions(&other.id).len(), 3);
+        assert!(loaded.delete_product(&other.id).is_err());
+    }
+
+    #[test]
+    fn test_undo_and_restore_points() {
+        let mut store = Store::new();
+        let product = test_product(5);
+        store.set_user(Some("alice".to_string()));
+        store.add_product(product.clone()).unwrap();
+        let sale = store.record_sale(product.id, 2).unwrap();
+        store.set_user(Some("bob".to_string()));
+        store.record_purchase(product.id, 3, 4.0).unwrap();
+
+        // Each user sees only their own actions, newest first
+        let actions = store.undoable_actions(Some("alice"));
+        assert_eq!(actions.len(), 2);
+        assert!(actions[0].description.starts_with("Sale of 2"));
+        assert_eq!(store.undoable_actions(Some("bob")).len(), 1);
+
+        // Undoing the sale books a reversing sale rather than removing it
+        let first_seq = actions[0].first_seq;
+        let transactions = store.transactions().len();
+        store.undo(first_seq).unwrap();
+        assert_eq!(store.get_product(&product.id).unwrap().quantity, 8);
+        assert_eq!(store.transactions().len(), transactions + 1);
+        assert!(store.transactions().iter().any(|t| t.id == sale.id));
+        let sales: f64 = store.transactions_of_type(&TransactionType::Sale).iter().map(|t| t.price * t.quantity as f64).sum();
+        assert_eq!(sales, 0.0);
+        assert!(store.undo(first_seq).is_err());
+
+        // A purchase whose stock has been sold since cannot be undone
+        let purchase_seq = store.undoable_actions(Some("bob"))[0].first_seq;
+        store.record_sale(product.id, 7).unwrap();
+        assert!(matches!(store.undo(purchase_seq), Err(StoreError::InsufficientInventory)));
+
+        // Nor can a price change that was changed again later
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.price = 12.0;
+        store.update_product(edited).unwrap();
+        let price_seq = store.undoable_actions(Some("bob"))[0].first_seq;
+        store.set_user(Some("alice".to_string()));
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.price = 15.0;
+        store.update_product(edited).unwrap();
+        store.set_user(Some("bob".to_string()));
+        assert!(matches!(store.undo(price_seq), Err(StoreError::Conflict(_))));
+
+        // Restoring reverses everything since the point
+        store.create_restore_point("before changes").unwrap();
+        assert!(store.create_restore_point("before changes").is_err());
+        let mut edited = store.get_product(&product.id).unwrap().clone();
+        edited.price = 20.0;
+        edited.name = "Renamed".to_string();
+        store.update_product(edited).unwrap();
+        store.record_purchase(product.id, 10, 4.0).unwrap();
+        store.restore_to("before changes").unwrap();
+        let restored = store.get_product(&product.id).unwrap();
+        assert_eq!((restored.name.as_str(), restored.price, restored.quantity), ("Test Product", 15.0, 1));
+
+        // Anything that cannot be reversed stops the restore before it changes anything
+        store.add_customer(Customer {
+            id: Uuid::new_v4(),
+            name: "Ada".to_string(),
+            email: None,
+            created_at: chrono::Utc::now(),
+        }).unwrap();
+        let events = store.events().len();
+        assert!(store.restore_to("before changes").is_err());
+        assert_eq!(store.events().len(), events);
+
+        // The ledger stays intact and restore points are kept with the log
+        assert!(integrity::verify(&store.to_data(), None).unwrap().is_intact());
+        let mut storage = MemoryStorage::new();
+        store.save(&mut storage).unwrap();
+        let mut loaded = Store::new();
+        loaded.load(&storage).unwrap();
+        assert_eq!(loaded.restore_points()[0].name, "before changes");
+
+        // Undo entries point into the log they were made in, so a reload drops them
+        assert!(!store.undoable_actions(Some("alice")).is_empty());
+        store.load(&storage).unwrap();
+        assert!(store.undoable_actions(Some("alice")).is_empty());
+    }
+
+    #[test]
+    fn test_undo_invoiced_sale() {
+        let mut store = Store::new();
+        let product = test_product(5);
+        store.add_product(product.clone()).unwrap();
+        let invoiced = store.record_sale(product.id, 2).unwrap();
+        let undone = store.record_sale(product.id, 1).unwrap();
+        let actions = store.undoable_actions(None);
+        let (undone_seq, invoiced_seq) = (actions[0].first_seq, actions[1].first_seq);
+        store.issue_invoice(Checkout {
+            sale_ids: vec![invoiced.id],
+            payments: vec![Payment { method: PaymentMethod::Cash, amount: 20.0 }],
+            ..Default::default()
+        }).unwrap();
+
+        // An invoiced sale has to be voided or refunded, not undone
+        let events = store.events().len();
+        assert!(matches!(store.undo(invoiced_seq), Err(StoreError::InvalidInput(_))));
+        assert_eq!(store.events().len(), events);
+        assert_eq!(store.get_product(&product.id).unwrap().quantity, 2);
+
+        // The reversing sale of an undone one cannot be invoiced
+        store.undo(undone_seq).unwrap();
+        let reversal = store.transactions().last().unwrap().clone();
+        assert_ne!(reversal.id, undone.id);
+        assert!(reversal.quantity < 0);
+        assert!(store.draft_invoice(&Checkout {
+            sale_ids: vec![reversal.id],
+            ..Default::default()
+        }).is_err());
+    }
 }
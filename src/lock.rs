@@ -0,0 +1,54 @@
+use crate::errors::StoreError;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE: &str = "rusty_store.lock";
+
+/// Exclusive advisory lock on a data directory, so two sessions never write the
+/// same files. The lock is released when this is dropped or the process exits,
+/// including after a crash, so a stale lock file never needs cleaning up.
+pub struct DataLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl DataLock {
+    /// Takes the lock, or fails with `StoreError::Conflict` naming the process
+    /// that holds it.
+    pub fn acquire<P: AsRef<Path>>(dir: P) -> Result<Self, StoreError> {
+        let path = dir.as_ref().join(LOCK_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(|e| StoreError::DatabaseError(format!("{}: {}", path.display(), e)))?;
+
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => String::new(),
+                    pid => format!(" (process {})", pid),
+                };
+                return Err(StoreError::Conflict(format!(
+                    "{} is in use by another session{}", dir.as_ref().display(), holder
+                )));
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(StoreError::DatabaseError(format!("{}: {}", path.display(), e)));
+            },
+        }
+
+        // Only informational: the lock itself is what keeps other sessions out
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", std::process::id()))
+            .map_err(|e| StoreError::DatabaseError(format!("{}: {}", path.display(), e)))?;
+
+        Ok(DataLock { path, _file: file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
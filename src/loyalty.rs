@@ -0,0 +1,241 @@
+use crate::config::format_money;
+use crate::errors::StoreError;
+use crate::models::{Invoice, LoyaltyEntry, LoyaltyEntryKind};
+use crate::store::round_cents;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoyaltyConfig {
+    /// Points earned for every whole currency unit spent, after discounts and before tax.
+    pub points_per_unit: f64,
+    /// Extra points per item sold for specific products.
+    pub product_bonus: HashMap<Uuid, u64>,
+    /// Currency value of one point when redeemed.
+    pub point_value: f64,
+    /// Days before earned points lapse; `None` keeps them forever.
+    pub expiry_days: Option<i64>,
+}
+
+impl Default for LoyaltyConfig {
+    fn default() -> Self {
+        LoyaltyConfig {
+            points_per_unit: 1.0,
+            product_bonus: HashMap::new(),
+            point_value: 0.01,
+            expiry_days: Some(365),
+        }
+    }
+}
+
+// A batch of earned points and how many of them are still unspent.
+struct Lot {
+    remaining: u64,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Lot {
+    fn expired_at(&self, at: DateTime<Utc>) -> bool {
+        self.expires_at.map(|e| e <= at).unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct LoyaltyProgram {
+    config: LoyaltyConfig,
+    ledger: Vec<LoyaltyEntry>,
+}
+
+impl LoyaltyProgram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(&self) -> &LoyaltyConfig {
+        &self.config
+    }
+
+    pub fn validate_config(&self, config: &LoyaltyConfig) -> Result<(), StoreError> {
+        if config.points_per_unit < 0.0 || config.point_value < 0.0 {
+            return Err(StoreError::InvalidInput("Loyalty rates cannot be negative".to_string()));
+        }
+        if config.expiry_days.map(|d| d <= 0).unwrap_or(false) {
+            return Err(StoreError::InvalidInput("Points must be valid for at least a day".to_string()));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_config(&mut self, config: LoyaltyConfig) {
+        self.config = config;
+    }
+
+    pub(crate) fn apply_entry(&mut self, entry: LoyaltyEntry) {
+        self.ledger.push(entry);
+    }
+
+    /// Points a customer earns for `invoice`.
+    pub fn points_for(&self, invoice: &Invoice) -> u64 {
+        let spend = (invoice.subtotal - invoice.discount - invoice.points_discount).max(0.0);
+        let base = (spend.floor() * self.config.points_per_unit).floor() as u64;
+        let bonus: u64 = invoice.lines.iter()
+            .map(|l| self.config.product_bonus.get(&l.product_id).copied().unwrap_or(0) * l.quantity.max(0) as u64)
+            .sum();
+        base + bonus
+    }
+
+    /// Converts points to their discount value.
+    pub fn value_of(&self, points: u64) -> f64 {
+        round_cents(points as f64 * self.config.point_value)
+    }
+
+    /// The fewest points worth at least `amount`. Points worth nothing cover any amount.
+    pub fn points_to_cover(&self, amount: f64) -> u64 {
+        if self.config.point_value <= 0.0 {
+            return u64::MAX;
+        }
+        let mut points = (amount.max(0.0) / self.config.point_value).ceil() as u64;
+        // Step back over float error, e.g. 5.00 / 0.05 coming out just above 100
+        while points > 0 && self.value_of(points - 1) >= amount {
+            points -= 1;
+        }
+        points
+    }
+
+    pub fn balance(&self, customer_id: &Uuid, now: DateTime<Utc>) -> u64 {
+        self.lots(customer_id).iter()
+            .filter(|lot| !lot.expired_at(now))
+            .map(|lot| lot.remaining)
+            .sum()
+    }
+
+    pub fn prepare_earn(&self, customer_id: Uuid, points: u64, invoice_number: Option<u64>, now: DateTime<Utc>) -> Option<LoyaltyEntry> {
+        if points == 0 {
+            return None;
+        }
+        let expires_at = self.config.expiry_days.map(|d| now + Duration::days(d));
+        Some(entry(customer_id, LoyaltyEntryKind::Earned, points, invoice_number, now, expires_at))
+    }
+
+    pub fn check_redeemable(&self, customer_id: &Uuid, points: u64, now: DateTime<Utc>) -> Result<(), StoreError> {
+        let balance = self.balance(customer_id, now);
+        if points > balance {
+            return Err(StoreError::InvalidInput(format!(
+                "Customer only has {} points available", balance
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn prepare_redeem(&self, customer_id: Uuid, points: u64, invoice_number: Option<u64>, now: DateTime<Utc>) -> Result<LoyaltyEntry, StoreError> {
+        if points == 0 {
+            return Err(StoreError::InvalidInput("Nothing to redeem".to_string()));
+        }
+        self.check_redeemable(&customer_id, points, now)?;
+        Ok(entry(customer_id, LoyaltyEntryKind::Redeemed, points, invoice_number, now, None))
+    }
+
+    /// Ledger entries writing off every lot that has lapsed by `now`.
+    pub fn prepare_expiry(&self, now: DateTime<Utc>) -> Vec<LoyaltyEntry> {
+        let customers: HashSet<Uuid> = self.ledger.iter().map(|e| e.customer_id).collect();
+        let mut expired = Vec::new();
+        for customer_id in customers {
+            let lapsed: u64 = self.lots(&customer_id).iter()
+                .filter(|lot| lot.expired_at(now))
+                .map(|lot| lot.remaining)
+                .sum();
+            if lapsed > 0 {
+                expired.push(entry(customer_id, LoyaltyEntryKind::Expired, lapsed, None, now, None));
+            }
+        }
+        expired
+    }
+
+    pub fn ledger(&self) -> &[LoyaltyEntry] {
+        &self.ledger
+    }
+
+    pub fn generate_statement(&self, customer_id: &Uuid, customer_name: &str, now: DateTime<Utc>, currency: &str) -> String {
+        let mut report = format!("Loyalty Statement: {}\n==================\n\n", customer_name);
+        let mut earned = 0;
+        let mut redeemed = 0;
+        let mut expired = 0;
+
+        for entry in self.ledger.iter().filter(|e| e.customer_id == *customer_id) {
+            let (label, sign) = match entry.kind {
+                LoyaltyEntryKind::Earned => {
+                    earned += entry.points;
+                    ("Earned", "+")
+                },
+                LoyaltyEntryKind::Redeemed => {
+                    redeemed += entry.points;
+                    ("Redeemed", "-")
+                },
+                LoyaltyEntryKind::Expired => {
+                    expired += entry.points;
+                    ("Expired", "-")
+                },
+            };
+            report.push_str(&format!("{} {:<8} {}{}", entry.timestamp.format("%Y-%m-%d"), label, sign, entry.points));
+            if let Some(number) = entry.invoice_number {
+                report.push_str(&format!(" (INV-{:06})", number));
+            }
+            report.push('\n');
+        }
+
+        let balance = self.balance(customer_id, now);
+        report.push_str(&format!(
+            "\nTotal Earned: {}\nTotal Redeemed: {}\nTotal Expired: {}\nBalance: {} points ({})\n",
+            earned, redeemed, expired, balance, format_money(currency, self.value_of(balance))
+        ));
+        report
+    }
+
+    // Replays the ledger, spending the soonest-expiring points first.
+    fn lots(&self, customer_id: &Uuid) -> Vec<Lot> {
+        let mut lots: Vec<Lot> = Vec::new();
+        for entry in self.ledger.iter().filter(|e| e.customer_id == *customer_id) {
+            match entry.kind {
+                LoyaltyEntryKind::Earned => lots.push(Lot { remaining: entry.points, expires_at: entry.expires_at }),
+                LoyaltyEntryKind::Redeemed | LoyaltyEntryKind::Expired => {
+                    let lapsed = entry.kind == LoyaltyEntryKind::Expired;
+                    let mut candidates: Vec<&mut Lot> = lots.iter_mut()
+                        .filter(|lot| lot.remaining > 0 && lot.expired_at(entry.timestamp) == lapsed)
+                        .collect();
+                    candidates.sort_by_key(|lot| lot.expires_at.unwrap_or(DateTime::<Utc>::MAX_UTC));
+
+                    let mut owed = entry.points;
+                    for lot in candidates {
+                        let taken = owed.min(lot.remaining);
+                        lot.remaining -= taken;
+                        owed -= taken;
+                        if owed == 0 {
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+        lots
+    }
+}
+
+fn entry(
+    customer_id: Uuid,
+    kind: LoyaltyEntryKind,
+    points: u64,
+    invoice_number: Option<u64>,
+    timestamp: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+) -> LoyaltyEntry {
+    LoyaltyEntry {
+        id: Uuid::new_v4(),
+        customer_id,
+        kind,
+        points,
+        invoice_number,
+        timestamp,
+        expires_at,
+    }
+}
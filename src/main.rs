@@ -1,22 +1,234 @@
 use rusty_store::{
-    models::{Product, UserRole},
+    config::Settings,
+    coupon::MAX_GENERATED_CODES,
+    crypto::{self, Cipher},
+    csv_io::{self, ColumnMapping},
+    errors::StoreError,
+    giftcard,
+    integrity::{self, Signer},
+    journal::{Journal, DEFAULT_JOURNAL_FILE},
+    ledger,
+    lock::DataLock,
+    models::{Checkout, Coupon, Customer, DiscountType, Payment, PaymentMethod, Product, StoredValueKind, Transaction},
     auth,
+    escpos,
+    receipt::{self, ReceiptHeader},
+    repair,
+    retention,
+    snapshot::StoreFormat,
+    sync::Changeset,
+    sqlite::SqliteStorage,
+    storage::{JsonStorage, RotationRecovery, Storage},
     store::Store
 };
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::fs::File;
 use std::io::{self, Write};
+use std::path::PathBuf;
 use uuid::Uuid;
 
 fn main() {
-    let mut store = Store::new();
-    let mut auth = auth::Auth::new();  // This will handle admin creation internally
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // rusty_store generate-key <path>
+    if args.first().map(String::as_str) == Some("generate-key") {
+        let path = args.get(1).map(String::as_str).unwrap_or("store.key");
+        match crypto::generate_key_file(path) {
+            Ok(_) => println!("Key written to {}. Keep a copy somewhere safe; data encrypted with it cannot be read without it", path),
+            Err(e) => println!("Error generating key: {}", e),
+        }
+        return;
+    }
+
+    // rusty_store.toml, then RUSTY_STORE_* variables, then --data-dir and friends
+    let settings = match Settings::load(&args) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("Error loading configuration: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = std::fs::create_dir_all(&settings.data_dir) {
+        println!("Error creating data directory {}: {}", settings.data_dir.display(), e);
+        return;
+    }
+    // Held until exit so a second session cannot overwrite this one's changes
+    let _lock = match DataLock::acquire(&settings.data_dir) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+
+    // Files are encrypted when a key file or RUSTY_STORE_PASSPHRASE is given
+    let cipher = match settings.cipher() {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            println!("Error loading key: {}", e);
+            return;
+        }
+    };
+
+    // The store file is signed at every save when a signing key file is given
+    let signer = match settings.signer() {
+        Ok(signer) => signer,
+        Err(e) => {
+            println!("Error loading signing key: {}", e);
+            return;
+        }
+    };
+
+    // The store file is only written in a new format once it has been converted
+    let format = settings.json_storage().format();
+    if format != settings.store_format && args.first().map(String::as_str) != Some("convert-store") {
+        println!("Note: store_format is {} but the store is still {}; run `rusty_store convert-store {}` to switch",
+            settings.store_format.name(), format.name(), settings.store_format.name());
+    }
+
+    // A key rotation that was cut short is finished or undone before anything is read
+    match json_storage(&settings, &cipher, &signer).recover_rotation() {
+        Ok(Some(RotationRecovery::Finished)) => {
+            println!("Finished an interrupted key rotation; the files are now under the new key")
+        },
+        Ok(Some(RotationRecovery::RolledBack)) => {
+            println!("Rolled back an interrupted key rotation; the files are still under the old key")
+        },
+        Ok(None) => {},
+        Err(e) => {
+            println!("Error recovering key rotation: {}", e);
+            return;
+        }
+    }
+
+    // rusty_store verify
+    if args.first().map(String::as_str) == Some("verify") {
+        verify_store(json_storage(&settings, &cipher, &signer), signer.as_ref());
+        return;
+    }
+
+    // rusty_store rotate-key [--new-key-file <path> | --decrypt]
+    if args.first().map(String::as_str) == Some("rotate-key") {
+        rotate_key(&args, &settings, cipher, &signer);
+        return;
+    }
+
+    // One-shot migration: rusty_store import-json <database>
+    if args.first().map(String::as_str) == Some("import-json") {
+        let path = args.get(1).filter(|a| !a.starts_with("--")).map(PathBuf::from)
+            .unwrap_or_else(|| settings.data_file("store.db"));
+        match SqliteStorage::open(&path).and_then(|mut db| db.import_json(&json_storage(&settings, &cipher, &signer))) {
+            Ok(summary) => println!("Imported {} products, {} transactions and {} users into {}",
+                summary.products, summary.transactions, summary.users, path.display()),
+            Err(e) => println!("Import failed: {}", e),
+        }
+        return;
+    }
+
+    // rusty_store repair
+    if args.first().map(String::as_str) == Some("repair") {
+        repair_files(json_storage(&settings, &cipher, &signer));
+        return;
+    }
+
+    // rusty_store migrate [--dry-run]
+    if args.first().map(String::as_str) == Some("migrate") {
+        migrate_store(json_storage(&settings, &cipher, &signer), args.iter().any(|a| a == "--dry-run"));
+        return;
+    }
+
+    // rusty_store restore [number]
+    if args.first().map(String::as_str) == Some("restore") {
+        restore_backup(&settings, &cipher, &signer, args.get(1).map(String::as_str));
+        return;
+    }
+
+    // rusty_store convert-store <json|binary>
+    if args.first().map(String::as_str) == Some("convert-store") {
+        convert_store(&settings, &cipher, &signer, args.get(1).map(String::as_str));
+        return;
+    }
 
-    // Initialize store from file
-    if let Err(e) = store.load_from_file("store.json") {
-        println!("Creating new store: {}", e);
+    let sqlite_path = args.iter().position(|a| a == "--sqlite")
+        .map(|i| args.get(i + 1).map(PathBuf::from).unwrap_or_else(|| settings.data_file("store.db")));
+    // The database is not encrypted, so a configured key would silently protect nothing
+    if sqlite_path.is_some() && cipher.is_some() {
+        println!("Error: the SQLite backend does not support encryption; remove the key file or passphrase, or use the JSON files");
+        return;
+    }
+    let open_storage = || -> Result<Box<dyn Storage>, StoreError> {
+        match &sqlite_path {
+            Some(path) => Ok(Box::new(SqliteStorage::open(path)?)),
+            None => Ok(Box::new(json_storage(&settings, &cipher, &signer))),
+        }
+    };
+    let (mut storage, user_storage) = match (open_storage(), open_storage()) {
+        (Ok(storage), Ok(user_storage)) => (storage, user_storage),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Error opening storage: {}", e);
+            return;
+        }
+    };
+
+    let mut auth = match auth::Auth::with_storage(user_storage) {  // This will handle admin creation internally
+        Ok(auth) => auth,
+        Err(e) => {
+            report_load_error("users", &e);
+            return;
+        }
+    };
+    let mut store = Store::with_settings(settings.clone());
+    if let Err(e) = store.load(storage.as_ref()) {
+        report_load_error("store", &e);
+        return;
+    }
+    // Anything journaled since the last checkpoint is replayed here
+    let journal_path = settings.data_file(DEFAULT_JOURNAL_FILE);
+    match Journal::open_with_cipher(journal_path, cipher.clone()).and_then(|journal| store.attach_journal(journal)) {
+        Ok(0) => {},
+        Ok(replayed) => println!("Recovered {} unsaved change(s) from the journal", replayed),
+        Err(e) => {
+            println!("Error opening journal: {}", e);
+            return;
+        }
+    }
+    store.attach_archives(settings.archive_dir(cipher.clone()));
+
+    // rusty_store archive
+    if args.first().map(String::as_str) == Some("archive") {
+        archive_transactions(&mut store, storage.as_mut());
+        return;
+    }
+
+    // rusty_store import-products <file> [--map sku=Item Code,...]
+    // rusty_store export-products <file>
+    // rusty_store export-transactions <file> [--from YYYY-MM-DD] [--to YYYY-MM-DD]
+    // rusty_store export-changes <file> [--since <seq>]
+    // rusty_store merge-changes <file>
+    if let Some(command @ ("import-products" | "export-products" | "export-transactions" | "export-changes"
+        | "merge-changes")) = args.first().map(String::as_str) {
+        let path = match args.get(1).filter(|a| !a.starts_with("--")) {
+            Some(path) => path,
+            None => {
+                println!("Usage: rusty_store {} <file>", command);
+                return;
+            }
+        };
+        match command {
+            "import-products" => import_products(&mut store, path, &args),
+            "export-products" => export_products(&store, path),
+            "export-changes" => export_changes(&store, path, &args),
+            "merge-changes" => merge_changes(&mut store, path),
+            _ => export_transactions(&store, path, &args),
+        }
+        if let Err(e) = store.checkpoint(storage.as_mut()) {
+            println!("Error saving store: {}", e);
+        }
+        return;
     }
 
     loop {
-        println!("\nRusty Store Management System");
+        println!("\n{} Management System", settings.store_name);
         println!("1. Login");
         println!("2. Exit");
         print!("> ");
@@ -26,19 +238,339 @@ fn main() {
         io::stdin().read_line(&mut choice).unwrap();
 
         match choice.trim() {
-            "1" => handle_login(&mut auth),
+            "1" => handle_login(&mut auth, &mut store, storage.as_mut()),
             "2" => break,
             _ => println!("Invalid choice"),
         }
     }
 
-    // Save store state before exit
-    if let Err(e) = store.save_to_file("store.json") {
+    if let Err(e) = store.checkpoint(storage.as_mut()) {
         println!("Error saving store: {}", e);
+        if matches!(e, StoreError::Conflict(_)) {
+            println!("Your changes are kept in the journal and will be replayed at the next start");
+        }
+    }
+}
+
+fn archive_transactions(store: &mut Store, storage: &mut dyn Storage) {
+    let retention = store.settings().retention.clone();
+    let Some(keep_days) = retention.keep_days else {
+        println!("No retention period is set; set keep_days under [retention] in rusty_store.toml");
+        return;
+    };
+    let cutoff = retention::cutoff(keep_days, retention.period, &store.settings().timezone, Utc::now());
+    match store.archive_transactions(cutoff, retention.period, storage) {
+        Ok(Some(archive)) => println!("Archived {} transaction(s) from before {} to {}",
+            archive.transactions, store.settings().format_time(cutoff), archive.file),
+        Ok(None) => println!("Nothing from before {} to archive", store.settings().format_time(cutoff)),
+        Err(e) => println!("Archiving failed: {}", e),
     }
 }
 
-fn handle_login(auth: &mut auth::Auth) {
+fn import_products(store: &mut Store, path: &str, args: &[String]) {
+    let mapping = match args.iter().position(|a| a == "--map") {
+        Some(i) => ColumnMapping::parse(args.get(i + 1).map(String::as_str).unwrap_or_default()),
+        None => Ok(ColumnMapping::default()),
+    };
+    let imported = mapping.and_then(|mapping| {
+        let file = File::open(path).map_err(|e| StoreError::InvalidInput(format!("{}: {}", path, e)))?;
+        csv_io::import_products(store, file, &mapping)
+    });
+    match imported {
+        Ok(report) => println!("\n{}", report.generate_report()),
+        Err(e) => println!("Import failed: {}", e),
+    }
+}
+
+fn export_products(store: &Store, path: &str) {
+    let exported = File::create(path)
+        .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path, e)))
+        .and_then(|file| csv_io::export_products(store, file));
+    match exported {
+        Ok(count) => println!("Exported {} products to {}", count, path),
+        Err(e) => println!("Export failed: {}", e),
+    }
+}
+
+fn export_changes(store: &Store, path: &str, args: &[String]) {
+    let since = match args.iter().position(|a| a == "--since").and_then(|i| args.get(i + 1)) {
+        Some(seq) => match seq.parse() {
+            Ok(seq) => seq,
+            Err(_) => {
+                println!("--since needs an event number, got '{}'", seq);
+                return;
+            }
+        },
+        None => 0,
+    };
+    let changeset = store.export_changes(since);
+    let exported = File::create(path)
+        .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path, e)))
+        .and_then(|file| changeset.write(file));
+    match exported {
+        Ok(_) => println!("Exported {} event(s) to {}; next time use --since {} to export only later changes",
+            changeset.events.len(), path, changeset.through()),
+        Err(e) => println!("Export failed: {}", e),
+    }
+}
+
+fn merge_changes(store: &mut Store, path: &str) {
+    let merged = File::open(path)
+        .map_err(|e| StoreError::InvalidInput(format!("{}: {}", path, e)))
+        .and_then(Changeset::read)
+        .and_then(|changeset| store.merge_changes(&changeset));
+    match merged {
+        Ok(report) => println!("\n{}", report.generate_report()),
+        Err(e) => println!("Merge failed: {}", e),
+    }
+}
+
+fn export_transactions(store: &Store, path: &str, args: &[String]) {
+    // Whole days in the configured timezone; --to includes the day given
+    let day = |flag: &str, days_after: i64| -> Result<Option<DateTime<Utc>>, StoreError> {
+        let value = match args.iter().position(|a| a == flag) {
+            Some(i) => args.get(i + 1).ok_or_else(|| StoreError::InvalidInput(format!("{} needs a date", flag)))?,
+            None => return Ok(None),
+        };
+        let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|_| StoreError::InvalidInput(format!("'{}' is not a YYYY-MM-DD date", value)))?;
+        let midnight = (date + chrono::Duration::days(days_after)).and_time(NaiveTime::MIN);
+        store.settings().timezone.from_local_datetime(&midnight).earliest()
+            .map(|t| Some(t.with_timezone(&Utc)))
+            .ok_or_else(|| StoreError::InvalidInput(format!("{} does not exist in {}", value, store.settings().timezone)))
+    };
+
+    let exported = day("--from", 0).and_then(|from| Ok((from, day("--to", 1)?))).and_then(|(from, to)| {
+        let file = File::create(path).map_err(|e| StoreError::InvalidInput(format!("{}: {}", path, e)))?;
+        csv_io::export_transactions(store, file, from, to)
+    });
+    match exported {
+        Ok(count) => println!("Exported {} transactions to {}", count, path),
+        Err(e) => println!("Export failed: {}", e),
+    }
+}
+
+fn json_storage(settings: &Settings, cipher: &Option<Cipher>, signer: &Option<Signer>) -> JsonStorage {
+    settings.json_storage().with_cipher(cipher.clone()).with_signer(signer.clone())
+}
+
+// A key file named by `flag`, else a passphrase from the `env` variable, else no encryption.
+fn cipher_from(args: &[String], flag: &str, env: &str) -> Result<Option<Cipher>, StoreError> {
+    if let Some(i) = args.iter().position(|a| a == flag) {
+        let path = args.get(i + 1)
+            .ok_or_else(|| StoreError::InvalidInput(format!("{} needs a path", flag)))?;
+        return Cipher::from_key_file(path).map(Some);
+    }
+    match std::env::var(env) {
+        Ok(passphrase) if !passphrase.is_empty() => Cipher::from_passphrase(&passphrase).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn rotate_key(args: &[String], settings: &Settings, cipher: Option<Cipher>, signer: &Option<Signer>) {
+    let new_cipher = if args.iter().any(|a| a == "--decrypt") {
+        None
+    } else {
+        match cipher_from(args, "--new-key-file", "RUSTY_STORE_NEW_PASSPHRASE") {
+            Ok(Some(new_cipher)) => Some(new_cipher),
+            Ok(None) => {
+                println!("Give the new key with --new-key-file or RUSTY_STORE_NEW_PASSPHRASE, or --decrypt to remove encryption");
+                return;
+            },
+            Err(e) => {
+                println!("Error loading new key: {}", e);
+                return;
+            }
+        }
+    };
+
+    // Empty the journal first so nothing is left under the old key
+    let mut storage = json_storage(settings, &cipher, signer);
+    let mut store = Store::new();
+    let checkpointed = store.load(&storage)
+        .and_then(|_| Journal::open_with_cipher(settings.data_file(DEFAULT_JOURNAL_FILE), cipher.clone()))
+        .and_then(|journal| store.attach_journal(journal))
+        .and_then(|_| store.checkpoint(&mut storage));
+    if let Err(e) = checkpointed {
+        println!("Error saving current store: {}", e);
+        return;
+    }
+
+    let archives = settings.archive_dir(cipher);
+    let archive_files: Vec<PathBuf> = store.archives().iter().map(|a| archives.file_path(a)).collect();
+    match storage.rotate_key(new_cipher, &archive_files) {
+        Ok(count) => println!("Rewrote {} file(s) under the new key", count),
+        Err(e) => println!("Key rotation failed: {}", e),
+    }
+}
+
+fn report_load_error(what: &str, error: &StoreError) {
+    println!("Error loading {}: {}", what, error);
+    match error {
+        StoreError::CorruptData { .. } => println!(
+            "Run `rusty_store repair` to recover what is still readable, or `rusty_store restore` to go back to a backup"
+        ),
+        StoreError::CorruptSnapshot { .. } => println!("Run `rusty_store restore` to go back to a backup"),
+        _ => {},
+    }
+}
+
+fn convert_store(settings: &Settings, cipher: &Option<Cipher>, signer: &Option<Signer>, target: Option<&str>) {
+    let target: StoreFormat = match target.map(str::parse) {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => {
+            println!("{}", e);
+            return;
+        },
+        None => {
+            println!("Usage: rusty_store convert-store <json|binary>");
+            return;
+        }
+    };
+    let source = match target {
+        StoreFormat::Json => StoreFormat::Binary,
+        StoreFormat::Binary => StoreFormat::Json,
+    };
+
+    let data = match settings.storage_in_format(source).with_cipher(cipher.clone()).load_store() {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            println!("No {} to convert", source.file_name());
+            return;
+        },
+        Err(e) => {
+            println!("Error reading {}: {}", source.file_name(), e);
+            return;
+        }
+    };
+    match settings.storage_in_format(target).with_cipher(cipher.clone()).with_signer(signer.clone()).save_store(&data.view()) {
+        Ok(_) => {
+            println!("Wrote {} with {} products and {} transactions", target.file_name(),
+                data.products.len(), data.transactions.len());
+            if settings.store_format != target {
+                println!("Set store_format = \"{}\" in rusty_store.toml to use it", target.name());
+            }
+        },
+        Err(e) => println!("Error writing {}: {}", target.file_name(), e),
+    }
+}
+
+fn verify_store(storage: JsonStorage, signer: Option<&Signer>) {
+    let data = match storage.load_store() {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            println!("No store to verify");
+            return;
+        },
+        Err(e) => {
+            report_load_error("store", &e);
+            return;
+        }
+    };
+    match integrity::verify(&data, signer) {
+        Ok(report) => println!("{}", report.generate_report()),
+        Err(e) => println!("Verification failed: {}", e),
+    }
+}
+
+fn repair_files(mut storage: JsonStorage) {
+    match repair::repair(&mut storage) {
+        Ok(report) => {
+            match &report.store_source {
+                Some(source) => println!("Recovered {} products, {} transactions and {} events from {}",
+                    report.products, report.transactions, report.events, source.display()),
+                None => println!("No quarantined store file found"),
+            }
+            match &report.users_source {
+                Some(source) => println!("Recovered {} users from {}", report.users, source.display()),
+                None => println!("No quarantined users file found"),
+            }
+        },
+        Err(e) => println!("Repair failed: {}", e),
+    }
+}
+
+fn migrate_store(mut storage: JsonStorage, dry_run: bool) {
+    let report = match storage.plan_migration() {
+        Ok(Some(report)) => report,
+        Ok(None) => {
+            println!("No store file found");
+            return;
+        },
+        Err(e) => {
+            println!("Error reading store: {}", e);
+            return;
+        }
+    };
+    println!("\n{}", report.generate_report());
+    if dry_run || report.is_current() {
+        return;
+    }
+
+    // Loading migrates in memory; saving keeps the old file as a backup
+    match storage.load_store().and_then(|data| storage.save_store(&data.unwrap_or_default().view())) {
+        Ok(_) => println!("Store upgraded to schema version {}", report.to_version),
+        Err(e) => println!("Migration failed: {}", e),
+    }
+}
+
+fn restore_backup(settings: &Settings, cipher: &Option<Cipher>, signer: &Option<Signer>, choice: Option<&str>) {
+    let mut storage = json_storage(settings, cipher, signer);
+    let backups = match storage.backups() {
+        Ok(backups) => backups,
+        Err(e) => {
+            println!("Error listing backups: {}", e);
+            return;
+        }
+    };
+    if backups.is_empty() {
+        println!("No backups found");
+        return;
+    }
+
+    let chosen = choice
+        .and_then(|c| c.parse::<usize>().ok())
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| backups.get(i));
+    let backup = match chosen {
+        Some(backup) => backup,
+        None => {
+            println!("Available backups:");
+            for (i, backup) in backups.iter().enumerate() {
+                println!("{}. {} ({})", i + 1, backup.taken_at.format("%Y-%m-%d %H:%M:%S"), backup.path.display());
+            }
+            println!("Run `rusty_store restore <number>` to restore one");
+            return;
+        }
+    };
+
+    // Read it before saving anything, since saving may rotate it away
+    let data = match storage.read_backup(backup) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Error reading backup: {}", e);
+            return;
+        }
+    };
+
+    // Fold unsaved journal entries into the current file first, so it is backed up in full
+    let mut store = Store::new();
+    let checkpointed = store.load(&storage)
+        .and_then(|_| Journal::open_with_cipher(settings.data_file(DEFAULT_JOURNAL_FILE), cipher.clone()))
+        .and_then(|journal| store.attach_journal(journal))
+        .and_then(|_| store.checkpoint(&mut storage));
+    if let Err(e) = checkpointed {
+        println!("Error saving current store: {}", e);
+        return;
+    }
+
+    match storage.save_store(&data.view()) {
+        Ok(_) => println!("Restored backup from {}", backup.taken_at.format("%Y-%m-%d %H:%M:%S")),
+        Err(e) => println!("Error restoring backup: {}", e),
+    }
+}
+
+fn handle_login(auth: &mut auth::Auth, store: &mut Store, storage: &mut dyn Storage) {
     print!("Username: ");
     io::stdout().flush().unwrap();
     let mut username = String::new();
@@ -52,30 +584,43 @@ fn handle_login(auth: &mut auth::Auth) {
     match auth.login(username.trim(), password.trim()) {
         Ok(_) => {
             println!("Login successful!");
-            let mut store = Store::new();
-            if let Err(e) = store.load_from_file("store.json") {
-                println!("Error loading store: {}", e);
-                return;
+            // Reload so each session starts from the latest saved state
+            match store.load(storage) {
+                Ok(true) => {},
+                Ok(false) => println!("Creating new store"),
+                Err(e) => {
+                    report_load_error("store", &e);
+                    auth.logout();
+                    return;
+                }
             }
-            handle_main_menu(auth, &mut store);
+            store.set_user(Some(username.trim().to_string()));
+            handle_main_menu(auth, store, storage);
             // Save store state after operations
-            if let Err(e) = store.save_to_file("store.json") {
+            if let Err(e) = store.checkpoint(storage) {
                 println!("Error saving store: {}", e);
             }
+            store.set_user(None);
+            auth.logout();
         },
         Err(_) => println!("Login failed! Invalid username or password"),
     }
 }
 
-fn handle_main_menu(auth: &auth::Auth, store: &mut Store) {
+fn handle_main_menu(auth: &auth::Auth, store: &mut Store, storage: &mut dyn Storage) {
     loop {
         println!("\nMain Menu");
         println!("1. View Inventory");
-        println!("2. Add Product");
+        println!("2. Manage Products");
         println!("3. Record Sale");
         println!("4. Record Purchase");
         println!("5. View Reports");
-        println!("6. Logout");
+        println!("6. Gift Cards & Store Credit");
+        println!("7. Customers");
+        println!("8. Coupons");
+        println!("9. Undo My Recent Actions");
+        println!("10. Restore Points");
+        println!("11. Logout");
         print!("> ");
         io::stdout().flush().unwrap();
 
@@ -86,17 +631,109 @@ fn handle_main_menu(auth: &auth::Auth, store: &mut Store) {
             "1" => show_inventory(store),
             "2" => {
                 if auth.is_manager() {
-                    add_product(store)
+                    manage_products(store)
                 } else {
                     println!("Permission denied: Manager access required");
                 }
             },
-            "3" => record_sale(store),
-            "4" => record_purchase(store),
-            "5" => show_reports(store),
-            "6" => break,
+            "3" => record_sale(store, storage),
+            "4" => record_purchase(store, storage),
+            "5" => show_reports(auth, store),
+            "6" => manage_stored_value(auth, store),
+            "7" => manage_customers(auth, store),
+            "8" => {
+                if auth.is_manager() {
+                    manage_coupons(store)
+                } else {
+                    println!("Permission denied: Manager access required");
+                }
+            },
+            "9" => undo_actions(store),
+            "10" => {
+                if auth.is_manager() {
+                    manage_restore_points(store)
+                } else {
+                    println!("Permission denied: Manager access required");
+                }
+            },
+            "11" => break,
             _ => println!("Invalid choice"),
         }
+
+        if store.needs_checkpoint() {
+            if let Err(e) = store.checkpoint(storage) {
+                println!("Error saving store: {}", e);
+            }
+        }
+    }
+}
+
+fn undo_actions(store: &mut Store) {
+    let user = store.user().map(str::to_string);
+    let actions: Vec<(u64, String)> = store.undoable_actions(user.as_deref()).iter()
+        .map(|entry| (entry.first_seq, format!("{} {}", store.settings().format_time(entry.at), entry.description)))
+        .collect();
+    if actions.is_empty() {
+        println!("Nothing to undo");
+        return;
+    }
+    println!("\nYour recent actions, newest first:");
+    for (i, (_, description)) in actions.iter().enumerate() {
+        println!("{}. {}", i + 1, description);
+    }
+    println!("Enter the number to undo (blank to cancel): ");
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    let chosen = choice.trim().parse::<usize>().ok()
+        .and_then(|n| n.checked_sub(1))
+        .and_then(|i| actions.get(i));
+    match chosen.map(|(seq, _)| store.undo(*seq)) {
+        Some(Ok(entry)) => println!("Undone: {}", entry.description),
+        Some(Err(e)) => println!("Error undoing action: {}", e),
+        None => println!("Nothing undone"),
+    }
+}
+
+fn manage_restore_points(store: &mut Store) {
+    println!("\nRestore Points");
+    println!("1. List Restore Points");
+    println!("2. Create Restore Point");
+    println!("3. Restore to a Point");
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    match choice.trim() {
+        "1" => {
+            if store.restore_points().is_empty() {
+                println!("No restore points");
+            }
+            for point in store.restore_points() {
+                println!("{}  {} (after event #{})", store.settings().format_time(point.created_at), point.name, point.seq);
+            }
+        },
+        "2" => {
+            println!("Enter a name for the restore point: ");
+            let mut name = String::new();
+            io::stdin().read_line(&mut name).unwrap();
+            match store.create_restore_point(&name) {
+                Ok(_) => println!("Restore point '{}' created", name.trim()),
+                Err(e) => println!("Error creating restore point: {}", e),
+            }
+        },
+        "3" => {
+            println!("Enter the restore point name: ");
+            let mut name = String::new();
+            io::stdin().read_line(&mut name).unwrap();
+            match store.restore_to(&name) {
+                Ok(reversed) => println!("Reversed {} change(s) made since '{}'", reversed, name.trim()),
+                Err(e) => println!("Error restoring: {}", e),
+            }
+        },
+        _ => println!("Invalid choice"),
     }
 }
 
@@ -104,7 +741,72 @@ fn show_inventory(store: &Store) {
     println!("\n{}", store.generate_inventory_report());
 }
 
+fn manage_products(store: &mut Store) {
+    println!("\nProducts");
+    println!("1. Add Product");
+    println!("2. Archive Product");
+    println!("3. Restore Archived Product");
+    println!("4. Delete Product");
+    println!("5. Purge Product");
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    let choice = choice.trim();
+    if choice == "1" {
+        add_product(store);
+        return;
+    }
+    if !matches!(choice, "2" | "3" | "4" | "5") {
+        println!("Invalid choice");
+        return;
+    }
+
+    let listed: Vec<&Product> = match choice {
+        "3" => store.archived_products().collect(),
+        _ => store.products().collect(),
+    };
+    for product in listed {
+        println!("{}  {}", product.id, product.name);
+    }
+    println!("Enter product ID: ");
+    let mut id = String::new();
+    io::stdin().read_line(&mut id).unwrap();
+    let product_id = match Uuid::parse_str(id.trim()) {
+        Ok(id) => id,
+        Err(_) => {
+            println!("Invalid product ID");
+            return;
+        }
+    };
+
+    let result = match choice {
+        "2" => store.archive_product(&product_id).map(|_| "Product archived"),
+        "3" => store.restore_product(&product_id).map(|_| "Product restored"),
+        "4" => store.delete_product(&product_id).map(|_| "Product deleted"),
+        _ => {
+            println!("Purging keeps the product's transactions but reports will only show its ID. Continue? (y/N): ");
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer).unwrap();
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return;
+            }
+            store.purge_product(&product_id).map(|_| "Product purged")
+        },
+    };
+    match result {
+        Ok(message) => println!("{}", message),
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
 fn add_product(store: &mut Store) {
+    println!("Enter SKU (blank for none): ");
+    let mut sku = String::new();
+    io::stdin().read_line(&mut sku).unwrap();
+
     println!("Enter product name: ");
     let mut name = String::new();
     io::stdin().read_line(&mut name).unwrap();
@@ -130,6 +832,9 @@ fn add_product(store: &mut Store) {
         description: description.trim().to_string(),
         price,
         quantity,
+        version: 0,
+        sku: Some(sku.trim().to_string()).filter(|s| !s.is_empty()),
+        archived_at: None,
     };
 
     match store.add_product(product) {
@@ -138,7 +843,7 @@ fn add_product(store: &mut Store) {
     }
 }
 
-fn record_sale(store: &mut Store) {
+fn record_sale(store: &mut Store, storage: &mut dyn Storage) {
     println!("\nAvailable Products:");
     println!("{}", store.generate_inventory_report());
 
@@ -159,13 +864,141 @@ fn record_sale(store: &mut Store) {
     io::stdin().read_line(&mut quantity).unwrap();
     let quantity: i32 = quantity.trim().parse().unwrap_or(0);
 
-    match store.record_sale(product_id, quantity) {
-        Ok(_) => println!("Sale recorded successfully"),
-        Err(e) => println!("Error recording sale: {}", e),
+    let sale = match store.record_sale(product_id, quantity) {
+        Ok(sale) => {
+            println!("Sale recorded successfully");
+            persist_transaction(store, storage, &sale);
+            sale
+        },
+        Err(e) => {
+            println!("Error recording sale: {}", e);
+            return;
+        }
+    };
+
+    issue_receipt(store, sale.id);
+}
+
+fn issue_receipt(store: &mut Store, sale_id: Uuid) {
+    println!("Customer ID (blank for none): ");
+    let mut customer = String::new();
+    io::stdin().read_line(&mut customer).unwrap();
+    let customer_id = Uuid::parse_str(customer.trim()).ok();
+
+    println!("Coupon code (blank for none): ");
+    let mut coupon_code = String::new();
+    io::stdin().read_line(&mut coupon_code).unwrap();
+    let coupon_code = Some(coupon_code.trim().to_string()).filter(|c| !c.is_empty());
+
+    let mut redeem_points = 0;
+    if let Some(customer_id) = customer_id {
+        let balance = store.loyalty().balance(&customer_id, chrono::Utc::now());
+        if balance > 0 {
+            println!("Customer has {} points ({}). Points to redeem (blank for none): ",
+                balance, store.settings().format_money(store.loyalty().value_of(balance)));
+            let mut points = String::new();
+            io::stdin().read_line(&mut points).unwrap();
+            redeem_points = points.trim().parse().unwrap_or(0);
+        }
     }
+
+    let mut checkout = Checkout {
+        sale_ids: vec![sale_id],
+        tax_rate: 0.0,
+        payments: Vec::new(),
+        customer_id,
+        coupon_code,
+        redeem_points,
+    };
+    let amount_due = match store.draft_invoice(&checkout) {
+        Ok(draft) => {
+            if draft.points_redeemed < redeem_points {
+                println!("Only {} points are needed to cover this sale", draft.points_redeemed);
+            }
+            draft.total
+        },
+        Err(e) => {
+            println!("Error pricing sale: {}", e);
+            return;
+        }
+    };
+
+    println!("Amount due: {}", store.settings().format_money(amount_due));
+    println!("Payment method (1. Cash, 2. Card, 3. Gift card/store credit): ");
+    let mut method = String::new();
+    io::stdin().read_line(&mut method).unwrap();
+    let method = match method.trim() {
+        "2" => PaymentMethod::Card,
+        "3" => {
+            println!("Enter code: ");
+            let mut code = String::new();
+            io::stdin().read_line(&mut code).unwrap();
+            PaymentMethod::StoredValue(code.trim().to_string())
+        },
+        _ => PaymentMethod::Cash,
+    };
+
+    let amount = if method == PaymentMethod::Cash {
+        println!("Amount tendered: ");
+        let mut tendered = String::new();
+        io::stdin().read_line(&mut tendered).unwrap();
+        tendered.trim().parse().unwrap_or(amount_due)
+    } else {
+        amount_due
+    };
+    checkout.payments.push(Payment { method, amount });
+
+    let header = ReceiptHeader::from_settings(store.settings());
+    let invoice = match store.issue_invoice(checkout) {
+        Ok(invoice) => invoice,
+        Err(e) => {
+            println!("Error issuing receipt: {}", e);
+            return;
+        }
+    };
+
+    match receipt::render_text(&invoice, &header) {
+        Ok(text) => println!("\n{}", text),
+        Err(e) => println!("Error rendering receipt: {}", e),
+    }
+
+    if let Some(printer) = prompt_printer() {
+        match escpos::render_receipt(&invoice, &header).and_then(|bytes| escpos::write_to(&printer, &bytes)) {
+            Ok(_) => println!("Receipt sent to {}", printer),
+            Err(e) => println!("Error printing receipt: {}", e),
+        }
+    }
+}
+
+fn prompt_printer() -> Option<String> {
+    println!("Printer device or file (blank to skip): ");
+    let mut path = String::new();
+    io::stdin().read_line(&mut path).unwrap();
+    let path = path.trim();
+    if path.is_empty() { None } else { Some(path.to_string()) }
 }
 
-fn record_purchase(store: &mut Store) {
+fn print_shift_report(store: &Store) {
+    println!("Shift length in hours: ");
+    let mut hours = String::new();
+    io::stdin().read_line(&mut hours).unwrap();
+    let hours: i64 = hours.trim().parse().unwrap_or(8);
+
+    let to = chrono::Utc::now();
+    let summary = store.shift_summary(to - chrono::Duration::hours(hours), to);
+    let printer = match prompt_printer() {
+        Some(printer) => printer,
+        None => return,
+    };
+
+    let bytes = escpos::render_shift_report(&summary, &ReceiptHeader::from_settings(store.settings()));
+    match escpos::write_to(&printer, &bytes) {
+        Ok(_) => println!("Shift report sent to {}", printer),
+        Err(e) => println!("Error printing shift report: {}", e),
+    }
+}
+
+fn record_purchase(store: &mut Store, storage: &mut dyn Storage) {
     println!("\nAvailable Products:");
     println!("{}", store.generate_inventory_report());
 
@@ -191,16 +1024,241 @@ fn record_purchase(store: &mut Store) {
     let price: f64 = price.trim().parse().unwrap_or(0.0);
 
     match store.record_purchase(product_id, quantity, price) {
-        Ok(_) => println!("Purchase recorded successfully"),
+        Ok(purchase) => {
+            println!("Purchase recorded successfully");
+            persist_transaction(store, storage, &purchase);
+        },
         Err(e) => println!("Error recording purchase: {}", e),
     }
 }
 
-fn show_reports(store: &Store) {
+fn persist_transaction(store: &mut Store, storage: &mut dyn Storage, transaction: &Transaction) {
+    if let Err(e) = store.write_through(storage, &transaction.id) {
+        println!("Error saving transaction: {}", e);
+    }
+}
+
+fn manage_stored_value(auth: &auth::Auth, store: &mut Store) {
+    println!("\nGift Cards & Store Credit");
+    println!("1. Sell Gift Card");
+    println!("2. Issue Store Credit");
+    println!("3. Check Balance");
+    println!("4. Void Account");
+    println!("5. Liability Report");
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    match choice.trim() {
+        "1" | "2" => {
+            let kind = if choice.trim() == "1" { StoredValueKind::GiftCard } else { StoredValueKind::StoreCredit };
+            println!("Enter amount: ");
+            let mut amount = String::new();
+            io::stdin().read_line(&mut amount).unwrap();
+            let amount: f64 = amount.trim().parse().unwrap_or(0.0);
+
+            match store.issue_stored_value(kind, amount) {
+                Ok(account) => println!("{} issued: {} ({})",
+                    giftcard::kind_label(&account.kind), account.code, store.settings().format_money(account.balance)),
+                Err(e) => println!("Error issuing account: {}", e),
+            }
+        },
+        "3" => {
+            println!("Enter code: ");
+            let mut code = String::new();
+            io::stdin().read_line(&mut code).unwrap();
+            match store.stored_value().balance(&code) {
+                Ok(balance) => println!("Balance: {}", store.settings().format_money(balance)),
+                Err(e) => println!("Error looking up balance: {}", e),
+            }
+        },
+        "4" | "5" if !auth.is_manager() => println!("Permission denied: Manager access required"),
+        "4" => {
+            println!("Enter code: ");
+            let mut code = String::new();
+            io::stdin().read_line(&mut code).unwrap();
+            match store.void_stored_value(&code) {
+                Ok(entry) => println!("Voided {}, {} written off", entry.code, store.settings().format_money(entry.amount)),
+                Err(e) => println!("Error voiding account: {}", e),
+            }
+        },
+        "5" => println!("\n{}", store.stored_value().generate_liability_report(&store.settings().currency)),
+        _ => println!("Invalid choice"),
+    }
+}
+
+fn manage_customers(auth: &auth::Auth, store: &mut Store) {
+    println!("\nCustomers");
+    println!("1. List Customers");
+    println!("2. Add Customer");
+    println!("3. Loyalty Statement");
+    println!("4. Expire Lapsed Points");
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    match choice.trim() {
+        "1" => {
+            for customer in store.customers() {
+                println!("{}  {}", customer.id, customer.name);
+            }
+        },
+        "2" => {
+            println!("Enter customer name: ");
+            let mut name = String::new();
+            io::stdin().read_line(&mut name).unwrap();
+
+            println!("Enter email (blank for none): ");
+            let mut email = String::new();
+            io::stdin().read_line(&mut email).unwrap();
+
+            let customer = Customer {
+                id: Uuid::new_v4(),
+                name: name.trim().to_string(),
+                email: Some(email.trim().to_string()).filter(|e| !e.is_empty()),
+                created_at: chrono::Utc::now(),
+            };
+            let id = customer.id;
+            match store.add_customer(customer) {
+                Ok(_) => println!("Customer added with ID {}", id),
+                Err(e) => println!("Error adding customer: {}", e),
+            }
+        },
+        "3" => {
+            println!("Enter customer ID: ");
+            let mut id = String::new();
+            io::stdin().read_line(&mut id).unwrap();
+            match Uuid::parse_str(id.trim()).ok().and_then(|id| store.get_customer(&id)) {
+                Some(customer) => println!("\n{}", store.loyalty()
+                    .generate_statement(&customer.id, &customer.name, chrono::Utc::now(), &store.settings().currency)),
+                None => println!("Customer not found"),
+            }
+        },
+        "4" => {
+            if auth.is_manager() {
+                match store.expire_loyalty_points(chrono::Utc::now()) {
+                    Ok(expired) => println!("Expired {} points across {} customers",
+                        expired.iter().map(|e| e.points).sum::<u64>(), expired.len()),
+                    Err(e) => println!("Error expiring points: {}", e),
+                }
+            } else {
+                println!("Permission denied: Manager access required");
+            }
+        },
+        _ => println!("Invalid choice"),
+    }
+}
+
+fn manage_coupons(store: &mut Store) {
+    println!("\nCoupons");
+    println!("1. Create Coupon");
+    println!("2. Generate Codes");
+    println!("3. Redemption Report");
+    print!("> ");
+    io::stdout().flush().unwrap();
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice).unwrap();
+
+    match choice.trim() {
+        "1" => create_coupon(store),
+        "2" => {
+            for coupon in store.coupons().coupons() {
+                println!("{}  {}", coupon.id, coupon.name);
+            }
+            println!("Enter coupon ID: ");
+            let mut id = String::new();
+            io::stdin().read_line(&mut id).unwrap();
+            let coupon_id = match Uuid::parse_str(id.trim()) {
+                Ok(id) => id,
+                Err(_) => {
+                    println!("Invalid coupon ID");
+                    return;
+                }
+            };
+
+            println!("How many codes (up to {}): ", MAX_GENERATED_CODES);
+            let mut count = String::new();
+            io::stdin().read_line(&mut count).unwrap();
+            let count: usize = count.trim().parse().unwrap_or(0);
+
+            println!("Uses per code (blank for unlimited): ");
+            let mut uses = String::new();
+            io::stdin().read_line(&mut uses).unwrap();
+            let max_uses: Option<u32> = uses.trim().parse().ok();
+
+            match store.generate_coupon_codes(coupon_id, count, max_uses) {
+                Ok(codes) => codes.iter().for_each(|c| println!("{}", c)),
+                Err(e) => println!("Error generating codes: {}", e),
+            }
+        },
+        "3" => println!("\n{}", store.coupons().generate_redemption_report(&store.settings().currency)),
+        _ => println!("Invalid choice"),
+    }
+}
+
+fn create_coupon(store: &mut Store) {
+    println!("Enter coupon name: ");
+    let mut name = String::new();
+    io::stdin().read_line(&mut name).unwrap();
+
+    println!("Discount (e.g. 10% or 5.00): ");
+    let mut discount = String::new();
+    io::stdin().read_line(&mut discount).unwrap();
+    let discount = match discount.trim().strip_suffix('%') {
+        Some(percent) => DiscountType::Percentage(percent.trim().parse().unwrap_or(0.0)),
+        None => DiscountType::FixedAmount(discount.trim().parse().unwrap_or(0.0)),
+    };
+
+    println!("Minimum spend: ");
+    let mut min_spend = String::new();
+    io::stdin().read_line(&mut min_spend).unwrap();
+
+    println!("Valid for how many days (blank for no expiry): ");
+    let mut days = String::new();
+    io::stdin().read_line(&mut days).unwrap();
+
+    println!("Maximum redemptions (blank for unlimited): ");
+    let mut max_redemptions = String::new();
+    io::stdin().read_line(&mut max_redemptions).unwrap();
+
+    println!("Maximum per customer (blank for unlimited): ");
+    let mut max_per_customer = String::new();
+    io::stdin().read_line(&mut max_per_customer).unwrap();
+
+    let now = chrono::Utc::now();
+    let coupon = Coupon {
+        id: Uuid::new_v4(),
+        name: name.trim().to_string(),
+        discount,
+        min_spend: min_spend.trim().parse().unwrap_or(0.0),
+        product_ids: Vec::new(),
+        valid_from: now,
+        valid_until: days.trim().parse().ok().map(|d| now + chrono::Duration::days(d)),
+        max_redemptions: max_redemptions.trim().parse().ok(),
+        max_per_customer: max_per_customer.trim().parse().ok(),
+    };
+    let id = coupon.id;
+    match store.create_coupon(coupon) {
+        Ok(_) => println!("Coupon created with ID {}", id),
+        Err(e) => println!("Error creating coupon: {}", e),
+    }
+}
+
+fn show_reports(auth: &auth::Auth, store: &mut Store) {
     println!("\nReports Menu");
     println!("1. Inventory Report");
     println!("2. Sales Report");
     println!("3. Purchase Report");
+    println!("4. Print Shift Report");
+    println!("5. Stock As Of Date");
+    println!("6. Inventory Consistency Check");
+    println!("7. Audit Trail");
+    println!("8. Archived Transactions");
     print!("> ");
     io::stdout().flush().unwrap();
 
@@ -211,6 +1269,69 @@ fn show_reports(store: &Store) {
         "1" => println!("\n{}", store.generate_inventory_report()),
         "2" => println!("\n{}", store.generate_sales_report()),
         "3" => println!("\n{}", store.generate_purchase_report()),
+        "4" => print_shift_report(store),
+        "5" => show_stock_as_of(store),
+        "6" => check_inventory(auth, store),
+        "7" => println!("\n{}", store.generate_audit_report(20)),
+        "8" => show_archived_transactions(store),
         _ => println!("Invalid choice"),
     }
 }
+
+// Local midnight starting `date` plus `days_after` days, in the store's timezone.
+fn start_of_day(store: &Store, date: &str, days_after: i64) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").ok()? + chrono::Duration::days(days_after);
+    store.settings().timezone.from_local_datetime(&date.and_time(NaiveTime::MIN)).earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn show_archived_transactions(store: &Store) {
+    println!("From (YYYY-MM-DD): ");
+    let mut from = String::new();
+    io::stdin().read_line(&mut from).unwrap();
+    println!("To (YYYY-MM-DD, inclusive): ");
+    let mut to = String::new();
+    io::stdin().read_line(&mut to).unwrap();
+
+    match (start_of_day(store, &from, 0), start_of_day(store, &to, 1)) {
+        (Some(from), Some(to)) => match store.generate_archived_report(from, to) {
+            Ok(report) => println!("\n{}", report),
+            Err(e) => println!("Error reading archives: {}", e),
+        },
+        _ => println!("Invalid date"),
+    }
+}
+
+fn show_stock_as_of(store: &Store) {
+    println!("Date (YYYY-MM-DD, end of day): ");
+    let mut date = String::new();
+    io::stdin().read_line(&mut date).unwrap();
+    match start_of_day(store, &date, 1) {
+        Some(t) => {
+            let at = t - chrono::Duration::microseconds(1);
+            match store.stock_as_of_archived(at) {
+                Ok(stock) => println!("\n{}", stock.generate_report(store.settings())),
+                Err(e) => println!("Error reading archives: {}", e),
+            }
+        },
+        None => println!("Invalid date"),
+    }
+}
+
+fn check_inventory(auth: &auth::Auth, store: &mut Store) {
+    let drift = store.check_inventory();
+    println!("\n{}", ledger::generate_drift_report(&drift));
+    if drift.is_empty() || !auth.is_manager() {
+        return;
+    }
+
+    println!("Record adjustments so the ledger matches the quantities on hand? (y/N): ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    if answer.trim().eq_ignore_ascii_case("y") {
+        match store.reconcile_inventory() {
+            Ok(adjustments) => println!("Recorded {} adjustment(s)", adjustments.len()),
+            Err(e) => println!("Error recording adjustments: {}", e),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use crate::coupon::CouponBook;
+use crate::errors::StoreError;
+use crate::events::{Event, RecordedEvent};
+use crate::giftcard::StoredValueBook;
+use crate::loyalty::LoyaltyProgram;
+use crate::store::StoreData;
+use serde_json::{json, Map, Value};
+
+/// Version written into every saved store file. Bump it together with a new
+/// entry in `MIGRATIONS` whenever the persisted shape changes.
+pub const SCHEMA_VERSION: u32 = 2;
+
+// Upgrades a document from `to - 1` to `to`, returning a line per change made.
+struct Migration {
+    to: u32,
+    description: &'static str,
+    apply: fn(&mut Map<String, Value>) -> Vec<String>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        to: 1,
+        description: "Add sections missing from files written before they existed",
+        apply: add_missing_sections,
+    },
+    Migration {
+        to: 2,
+        description: "Start the event log with the existing state",
+        apply: record_snapshot_event,
+    },
+];
+
+#[derive(Debug, Clone)]
+pub struct MigrationStep {
+    pub version: u32,
+    pub description: String,
+    pub changes: Vec<String>,
+}
+
+/// What upgrading a document did, or would do in a dry run.
+#[derive(Debug, Clone)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub steps: Vec<MigrationStep>,
+}
+
+impl MigrationReport {
+    pub fn is_current(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn generate_report(&self) -> String {
+        let mut report = String::from("Schema Migration\n================\n\n");
+        if self.is_current() {
+            report.push_str(&format!("Already at schema version {}\n", self.to_version));
+            return report;
+        }
+
+        report.push_str(&format!("Version {} -> {}\n\n", self.from_version, self.to_version));
+        for step in &self.steps {
+            report.push_str(&format!("{}: {}\n", step.version, step.description));
+            if step.changes.is_empty() {
+                report.push_str("  - no changes needed\n");
+            }
+            for change in &step.changes {
+                report.push_str(&format!("  - {}\n", change));
+            }
+        }
+        report
+    }
+}
+
+/// Schema version of a stored document. Files from before versioning count as 0.
+pub fn schema_version(doc: &Value) -> u32 {
+    doc.get("schema_version").and_then(Value::as_u64).unwrap_or(0) as u32
+}
+
+/// Upgrades `doc` in place to `SCHEMA_VERSION`, one migration at a time.
+pub fn migrate(doc: &mut Value) -> Result<MigrationReport, StoreError> {
+    let from_version = schema_version(doc);
+    if from_version > SCHEMA_VERSION {
+        return Err(StoreError::DatabaseError(format!(
+            "Store file uses schema version {} but this version only understands up to {}",
+            from_version, SCHEMA_VERSION
+        )));
+    }
+    let map = doc.as_object_mut()
+        .ok_or_else(|| StoreError::DatabaseError("Store file is not a JSON object".to_string()))?;
+
+    let mut steps = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.to > from_version) {
+        let changes = (migration.apply)(map);
+        map.insert("schema_version".to_string(), json!(migration.to));
+        steps.push(MigrationStep {
+            version: migration.to,
+            description: migration.description.to_string(),
+            changes,
+        });
+    }
+
+    Ok(MigrationReport { from_version, to_version: SCHEMA_VERSION, steps })
+}
+
+/// Reports what `migrate` would change without touching `doc`.
+pub fn dry_run(doc: &Value) -> Result<MigrationReport, StoreError> {
+    migrate(&mut doc.clone())
+}
+
+fn add_missing_sections(doc: &mut Map<String, Value>) -> Vec<String> {
+    let sections = [
+        ("products", json!({})),
+        ("transactions", json!([])),
+        ("invoices", json!([])),
+        ("stored_value", serde_json::to_value(StoredValueBook::new()).unwrap_or_default()),
+        ("customers", json!({})),
+        ("coupons", serde_json::to_value(CouponBook::new()).unwrap_or_default()),
+        ("loyalty", serde_json::to_value(LoyaltyProgram::new()).unwrap_or_default()),
+    ];
+
+    let mut changes = Vec::new();
+    for (name, empty) in sections {
+        if !doc.contains_key(name) {
+            doc.insert(name.to_string(), empty);
+            changes.push(format!("added empty {} section", name));
+        }
+    }
+    changes
+}
+
+fn record_snapshot_event(doc: &mut Map<String, Value>) -> Vec<String> {
+    if doc.contains_key("events") {
+        return Vec::new();
+    }
+    let data = match serde_json::from_value::<StoreData>(Value::Object(doc.clone())) {
+        Ok(data) if !data.is_empty() => data,
+        // Unreadable sections are left for loading to report
+        _ => {
+            doc.insert("events".to_string(), json!([]));
+            return vec!["added empty events section".to_string()];
+        },
+    };
+    let event = RecordedEvent {
+        seq: 1,
+        timestamp: chrono::Utc::now(),
+        event: Event::SnapshotImported(Box::new(data)),
+    };
+    doc.insert("events".to_string(), json!([serde_json::to_value(event).unwrap_or_default()]));
+    vec!["recorded the existing state as event 1".to_string()]
+}
@@ -2,13 +2,23 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Product {
     pub id: Uuid,
     pub name: String,
     pub description: String,
     pub price: f64,
     pub quantity: i32,
+    /// Bumped on every change, so an edit made from a stale copy can be refused.
+    #[serde(default)]
+    pub version: u64,
+    /// Stock keeping unit, the catalog's own code for the product. Unique when set.
+    #[serde(default)]
+    pub sku: Option<String>,
+    /// When the product was archived. Archived products leave the catalog but
+    /// stay resolvable, so reports can still name them.
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -19,12 +29,23 @@ pub struct Transaction {
     pub price: f64,
     pub transaction_type: TransactionType,
     pub timestamp: DateTime<Utc>,
+    /// Hash of the transaction before it in the ledger (see `integrity`), so
+    /// editing, inserting or removing one breaks the chain. `None` for
+    /// transactions recorded before the ledger was chained.
+    #[serde(default)]
+    pub prev_hash: Option<String>,
+    /// Username of whoever was logged in when it was recorded.
+    #[serde(default)]
+    pub user: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum TransactionType {
     Sale,
     Purchase,
+    /// A correction to stock outside sales and purchases: opening stock, edits,
+    /// counts. Unlike the others its quantity is signed.
+    Adjustment,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -40,3 +61,199 @@ pub enum UserRole {
     Manager,
     Employee,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum PaymentMethod {
+    Cash,
+    Card,
+    /// A gift card or store credit account, identified by its code.
+    StoredValue(String),
+}
+
+impl PaymentMethod {
+    pub fn category(&self) -> &'static str {
+        match self {
+            PaymentMethod::Cash => "Cash",
+            PaymentMethod::Card => "Card",
+            PaymentMethod::StoredValue(_) => "Gift card/credit",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Payment {
+    pub method: PaymentMethod,
+    pub amount: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceLine {
+    pub transaction_id: Uuid,
+    pub product_id: Uuid,
+    pub description: String,
+    pub quantity: i32,
+    pub unit_price: f64,
+    pub total: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invoice {
+    pub number: u64,
+    pub lines: Vec<InvoiceLine>,
+    pub subtotal: f64,
+    #[serde(default)]
+    pub discount: f64,
+    #[serde(default)]
+    pub coupon_code: Option<String>,
+    #[serde(default)]
+    pub customer_id: Option<Uuid>,
+    #[serde(default)]
+    pub points_redeemed: u64,
+    #[serde(default)]
+    pub points_discount: f64,
+    #[serde(default)]
+    pub points_earned: u64,
+    pub tax_rate: f64,
+    pub tax: f64,
+    pub total: f64,
+    pub payments: Vec<Payment>,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl Invoice {
+    pub fn invoice_id(&self) -> String {
+        format!("INV-{:06}", self.number)
+    }
+
+    pub fn amount_paid(&self) -> f64 {
+        self.payments.iter().map(|p| p.amount).sum()
+    }
+
+    pub fn change_due(&self) -> f64 {
+        (self.amount_paid() - self.total).max(0.0)
+    }
+}
+
+/// Input for `Store::issue_invoice`: the committed sales to bill and how they were paid.
+#[derive(Debug, Clone, Default)]
+pub struct Checkout {
+    pub sale_ids: Vec<Uuid>,
+    pub tax_rate: f64,
+    pub payments: Vec<Payment>,
+    pub customer_id: Option<Uuid>,
+    pub coupon_code: Option<String>,
+    /// Loyalty points to spend as a discount; requires `customer_id`.
+    pub redeem_points: u64,
+}
+
+/// Totals for a till session, built by `Store::shift_summary`.
+#[derive(Debug, Clone)]
+pub struct ShiftSummary {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub sales_count: usize,
+    pub items_sold: i32,
+    pub gross_sales: f64,
+    pub invoices_issued: usize,
+    pub tax_collected: f64,
+    pub payments: Vec<(String, f64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum StoredValueKind {
+    GiftCard,
+    StoreCredit,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredValueAccount {
+    pub code: String,
+    pub kind: StoredValueKind,
+    pub balance: f64,
+    pub issued_at: DateTime<Utc>,
+    pub voided: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum StoredValueOperation {
+    Issue,
+    Redeem,
+    Void,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredValueEntry {
+    pub id: Uuid,
+    pub code: String,
+    pub operation: StoredValueOperation,
+    pub amount: f64,
+    pub invoice_number: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Customer {
+    pub id: Uuid,
+    pub name: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum DiscountType {
+    /// Percentage off, e.g. `10.0` for 10%.
+    Percentage(f64),
+    FixedAmount(f64),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Coupon {
+    pub id: Uuid,
+    pub name: String,
+    pub discount: DiscountType,
+    pub min_spend: f64,
+    /// Products the discount applies to; empty means the whole basket.
+    pub product_ids: Vec<Uuid>,
+    pub valid_from: DateTime<Utc>,
+    pub valid_until: Option<DateTime<Utc>>,
+    pub max_redemptions: Option<u32>,
+    pub max_per_customer: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CouponCode {
+    pub code: String,
+    pub coupon_id: Uuid,
+    /// `Some(1)` for single-use codes, `None` for unlimited.
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CouponRedemption {
+    pub code: String,
+    pub coupon_id: Uuid,
+    pub invoice_number: u64,
+    pub sale_ids: Vec<Uuid>,
+    pub customer_id: Option<Uuid>,
+    pub discount: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum LoyaltyEntryKind {
+    Earned,
+    Redeemed,
+    Expired,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoyaltyEntry {
+    pub id: Uuid,
+    pub customer_id: Uuid,
+    pub kind: LoyaltyEntryKind,
+    pub points: u64,
+    pub invoice_number: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+    /// Only set on earned points.
+    pub expires_at: Option<DateTime<Utc>>,
+}
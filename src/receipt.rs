@@ -0,0 +1,304 @@
+use crate::barcode::Barcode;
+use crate::codes;
+use crate::config::{self, Settings};
+use crate::errors::StoreError;
+use crate::models::{Invoice, PaymentMethod};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+const WIDTH: usize = 40;
+
+/// Store details printed at the top of every receipt.
+#[derive(Debug, Clone)]
+pub struct ReceiptHeader {
+    pub name: String,
+    pub address: Vec<String>,
+    pub phone: Option<String>,
+    pub currency: String,
+    pub timezone: Tz,
+}
+
+impl Default for ReceiptHeader {
+    fn default() -> Self {
+        ReceiptHeader::from_settings(&Settings::default())
+    }
+}
+
+impl ReceiptHeader {
+    pub fn from_settings(settings: &Settings) -> Self {
+        ReceiptHeader {
+            name: settings.store_name.clone(),
+            address: Vec::new(),
+            phone: None,
+            currency: settings.currency.clone(),
+            timezone: settings.timezone,
+        }
+    }
+
+    pub fn money(&self, amount: f64) -> String {
+        config::format_money(&self.currency, amount)
+    }
+
+    pub fn time(&self, at: DateTime<Utc>) -> String {
+        config::format_time(&self.timezone, at)
+    }
+}
+
+pub fn payment_label(method: &PaymentMethod) -> String {
+    match method {
+        PaymentMethod::StoredValue(code) => format!("{} {}", method.category(), codes::mask(code)),
+        _ => method.category().to_string(),
+    }
+}
+
+/// The receipt body as fixed-width lines, shared by the text and PDF renderers.
+pub fn receipt_lines(invoice: &Invoice, header: &ReceiptHeader) -> Vec<String> {
+    let rule = "-".repeat(WIDTH);
+    let mut lines = vec![center(&header.name)];
+    lines.extend(header.address.iter().map(|l| center(l)));
+    if let Some(phone) = &header.phone {
+        lines.push(center(&format!("Tel: {}", phone)));
+    }
+    lines.push(rule.clone());
+    lines.push(format!("Invoice: {}", invoice.invoice_id()));
+    lines.push(format!("Date: {}", header.time(invoice.issued_at)));
+    lines.push(rule.clone());
+
+    for line in &invoice.lines {
+        lines.push(truncate(&line.description, WIDTH));
+        lines.push(columns(
+            &format!("  {} x {}", line.quantity, header.money(line.unit_price)),
+            &header.money(line.total),
+        ));
+    }
+
+    lines.push(rule.clone());
+    lines.push(columns("Subtotal", &header.money(invoice.subtotal)));
+    if invoice.discount > 0.0 {
+        lines.push(columns(&discount_label(invoice), &header.money(-invoice.discount)));
+    }
+    if invoice.points_discount > 0.0 {
+        lines.push(columns(&points_label(invoice), &header.money(-invoice.points_discount)));
+    }
+    lines.push(columns(
+        &format!("Tax ({:.2}%)", invoice.tax_rate * 100.0),
+        &header.money(invoice.tax),
+    ));
+    lines.push(columns("TOTAL", &header.money(invoice.total)));
+    for payment in &invoice.payments {
+        lines.push(columns(&payment_label(&payment.method), &header.money(payment.amount)));
+    }
+    if invoice.change_due() > 0.0 {
+        lines.push(columns("Change", &header.money(invoice.change_due())));
+    }
+    if invoice.points_earned > 0 {
+        lines.push(columns("Points earned", &invoice.points_earned.to_string()));
+    }
+    lines.push(rule);
+    lines
+}
+
+pub fn discount_label(invoice: &Invoice) -> String {
+    match &invoice.coupon_code {
+        Some(code) => format!("Discount ({})", code),
+        None => "Discount".to_string(),
+    }
+}
+
+pub fn points_label(invoice: &Invoice) -> String {
+    format!("Points ({})", invoice.points_redeemed)
+}
+
+pub fn receipt_barcode(invoice: &Invoice) -> Result<Barcode, StoreError> {
+    Barcode::code39(&invoice.invoice_id())
+}
+
+pub fn render_text(invoice: &Invoice, header: &ReceiptHeader) -> Result<String, StoreError> {
+    let barcode = receipt_barcode(invoice)?;
+    let mut text = receipt_lines(invoice, header).join("\n");
+    text.push('\n');
+    // Two rows of bars so the code is tall enough to scan off a screen
+    let bars = barcode.to_text();
+    text.push_str(&format!("{}\n{}\n", bars, bars));
+    text.push_str(&center(&invoice.invoice_id()));
+    text.push('\n');
+    Ok(text)
+}
+
+pub fn render_html(invoice: &Invoice, header: &ReceiptHeader) -> Result<String, StoreError> {
+    let barcode = receipt_barcode(invoice)?;
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{} {}</title>\n", escape_html(&header.name), invoice.invoice_id()));
+    html.push_str("<style>body{font-family:monospace;max-width:24em;margin:auto}\
+        table{width:100%;border-collapse:collapse}td.amount{text-align:right}\
+        .header,.barcode{text-align:center}</style>\n</head>\n<body>\n");
+
+    html.push_str(&format!("<div class=\"header\">\n<h1>{}</h1>\n", escape_html(&header.name)));
+    for line in &header.address {
+        html.push_str(&format!("<div>{}</div>\n", escape_html(line)));
+    }
+    if let Some(phone) = &header.phone {
+        html.push_str(&format!("<div>Tel: {}</div>\n", escape_html(phone)));
+    }
+    html.push_str("</div>\n");
+
+    html.push_str(&format!(
+        "<p>Invoice: {}<br>Date: {}</p>\n",
+        invoice.invoice_id(),
+        header.time(invoice.issued_at)
+    ));
+
+    html.push_str("<table>\n<tr><th>Item</th><th>Qty</th><th>Price</th><th>Total</th></tr>\n");
+    for line in &invoice.lines {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td><td class=\"amount\">{}</td></tr>\n",
+            escape_html(&line.description), line.quantity, escape_html(&header.money(line.unit_price)),
+            escape_html(&header.money(line.total))
+        ));
+    }
+    html.push_str(&format!("<tr><td colspan=\"3\">Subtotal</td><td class=\"amount\">{}</td></tr>\n",
+        escape_html(&header.money(invoice.subtotal))));
+    if invoice.discount > 0.0 {
+        html.push_str(&format!(
+            "<tr><td colspan=\"3\">{}</td><td class=\"amount\">{}</td></tr>\n",
+            escape_html(&discount_label(invoice)), escape_html(&header.money(-invoice.discount))
+        ));
+    }
+    if invoice.points_discount > 0.0 {
+        html.push_str(&format!(
+            "<tr><td colspan=\"3\">{}</td><td class=\"amount\">{}</td></tr>\n",
+            points_label(invoice), escape_html(&header.money(-invoice.points_discount))
+        ));
+    }
+    html.push_str(&format!(
+        "<tr><td colspan=\"3\">Tax ({:.2}%)</td><td class=\"amount\">{}</td></tr>\n",
+        invoice.tax_rate * 100.0, escape_html(&header.money(invoice.tax))
+    ));
+    html.push_str(&format!("<tr><th colspan=\"3\">Total</th><th class=\"amount\">{}</th></tr>\n",
+        escape_html(&header.money(invoice.total))));
+    for payment in &invoice.payments {
+        html.push_str(&format!(
+            "<tr><td colspan=\"3\">{}</td><td class=\"amount\">{}</td></tr>\n",
+            payment_label(&payment.method), escape_html(&header.money(payment.amount))
+        ));
+    }
+    if invoice.change_due() > 0.0 {
+        html.push_str(&format!("<tr><td colspan=\"3\">Change</td><td class=\"amount\">{}</td></tr>\n",
+            escape_html(&header.money(invoice.change_due()))));
+    }
+    if invoice.points_earned > 0 {
+        html.push_str(&format!("<tr><td colspan=\"3\">Points earned</td><td class=\"amount\">{}</td></tr>\n", invoice.points_earned));
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(&format!(
+        "<div class=\"barcode\">{}<div>{}</div></div>\n</body>\n</html>\n",
+        barcode.to_svg(2, 60),
+        invoice.invoice_id()
+    ));
+    Ok(html)
+}
+
+/// Renders a single-page PDF sized for an 80mm receipt roll.
+pub fn render_pdf(invoice: &Invoice, header: &ReceiptHeader) -> Result<Vec<u8>, StoreError> {
+    const PAGE_WIDTH: f64 = 226.0;
+    const MARGIN: f64 = 10.0;
+    const FONT_SIZE: f64 = 8.0;
+    const LEADING: f64 = 10.0;
+    const BARCODE_HEIGHT: f64 = 40.0;
+
+    let barcode = receipt_barcode(invoice)?;
+    let lines = receipt_lines(invoice, header);
+    let text_height = LEADING * lines.len() as f64;
+    let page_height = MARGIN * 2.0 + text_height + LEADING * 2.0 + BARCODE_HEIGHT;
+
+    // Each ' operator advances one line before drawing, so start a line above the first row
+    let top = page_height - MARGIN;
+    let mut content = format!("BT /F1 {} Tf {} TL {} {} Td\n", FONT_SIZE, LEADING, MARGIN, top);
+    for line in &lines {
+        content.push_str(&format!("({}) '\n", escape_pdf(line)));
+    }
+    content.push_str("ET\n");
+
+    let bar_y = top - text_height - LEADING / 2.0 - BARCODE_HEIGHT;
+    let module = ((PAGE_WIDTH - MARGIN * 2.0) / barcode.modules().len() as f64).min(1.0);
+    let x0 = (PAGE_WIDTH - module * barcode.modules().len() as f64) / 2.0;
+    content.push_str("0 g\n");
+    for (start, width) in barcode.bars() {
+        content.push_str(&format!(
+            "{:.2} {:.2} {:.2} {:.2} re f\n",
+            x0 + start as f64 * module, bar_y, width as f64 * module, BARCODE_HEIGHT
+        ));
+    }
+    content.push_str(&format!(
+        "BT /F1 {} Tf {} {} Td ({}) Tj ET\n",
+        FONT_SIZE, MARGIN, bar_y - LEADING, escape_pdf(&center(&invoice.invoice_id()))
+    ));
+
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {:.0}] /Resources << /Font << /F1 4 0 R >> >> /Contents 5 0 R >>",
+            PAGE_WIDTH, page_height
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_string(),
+        format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content),
+    ];
+
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+    }
+    let xref = pdf.len();
+    pdf.push_str(&format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1));
+    for offset in offsets {
+        pdf.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    pdf.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1, xref
+    ));
+    Ok(pdf.into_bytes())
+}
+
+fn center(text: &str) -> String {
+    let text = truncate(text, WIDTH);
+    let pad = (WIDTH - text.chars().count()) / 2;
+    format!("{}{}", " ".repeat(pad), text)
+}
+
+fn columns(left: &str, right: &str) -> String {
+    columns_width(left, right, WIDTH)
+}
+
+/// Left-aligns `left` and right-aligns `right` within `width` characters.
+pub(crate) fn columns_width(left: &str, right: &str, width: usize) -> String {
+    let left = truncate(left, width.saturating_sub(right.chars().count() + 1));
+    let pad = width.saturating_sub(left.chars().count() + right.chars().count());
+    format!("{}{}{}", left, " ".repeat(pad), right)
+}
+
+pub(crate) fn truncate(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The built-in PDF fonts only cover Latin-1, so anything outside ASCII is replaced.
+fn escape_pdf(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '(' | ')' | '\\' => format!("\\{}", c),
+            c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+            _ => "?".to_string(),
+        })
+        .collect()
+}
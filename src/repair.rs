@@ -0,0 +1,154 @@
+use crate::errors::StoreError;
+use crate::events::{Event, RecordedEvent};
+use crate::models::{Product, Transaction, User};
+use crate::storage::{JsonStorage, Storage};
+use crate::store::{Store, StoreData};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// What `repair` recovered from the most recently quarantined files.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub store_source: Option<PathBuf>,
+    pub products: usize,
+    pub transactions: usize,
+    /// Leading events of the damaged log that survived intact.
+    pub events: usize,
+    pub users_source: Option<PathBuf>,
+    pub users: usize,
+}
+
+/// Salvages what it can from the newest quarantined store and users files and
+/// merges it into the live files. Records already present are left alone, so
+/// running it twice is harmless. Only products, transactions and users are
+/// recovered; invoices, customers and the other books are not.
+///
+/// The merged state is recorded as a `SnapshotImported` event. It follows the
+/// live file's events or, if the live file has none, whatever events survived
+/// in the damaged one so their history can still be audited.
+pub fn repair(storage: &mut JsonStorage) -> Result<RepairReport, StoreError> {
+    let mut report = RepairReport::default();
+
+    if let Some(source) = storage.quarantined_store_files()?.into_iter().next() {
+        let text = read_lossy(storage, &source)?;
+        let (products, transactions) = (salvage_products(&text), salvage_transactions(&text));
+        report.products = products.len();
+        report.transactions = transactions.len();
+
+        let mut data = storage.load_store()?.map(|data| Store::from_data(data).to_data()).unwrap_or_default();
+        for product in products {
+            data.products.entry(product.id).or_insert(product);
+        }
+        let known: HashSet<Uuid> = data.transactions.iter().map(|t| t.id).collect();
+        data.transactions.extend(transactions.into_iter().filter(|t| !known.contains(&t.id)));
+        data.transactions.sort_by_key(|t| t.timestamp);
+
+        let mut events = std::mem::take(&mut data.events);
+        if events.is_empty() {
+            events = salvage_events(&text);
+            report.events = events.len();
+        }
+        events.push(RecordedEvent {
+            seq: events.last().map(|e| e.seq + 1).unwrap_or(1),
+            timestamp: chrono::Utc::now(),
+            event: Event::SnapshotImported(Box::new(StoreData { events: Vec::new(), ..data.clone() })),
+        });
+        storage.save_store(&StoreData { events, ..data }.view())?;
+        report.store_source = Some(source);
+    }
+
+    if let Some(source) = storage.quarantined_users_files()?.into_iter().next() {
+        let recovered = salvage_users(&read_lossy(storage, &source)?);
+        report.users = recovered.len();
+
+        let mut users = storage.load_users()?.unwrap_or_default();
+        for (username, user) in recovered {
+            users.entry(username).or_insert(user);
+        }
+        // With nobody to save, leave the file absent so the default admin is recreated
+        if !users.is_empty() {
+            storage.save_users(&users)?;
+        }
+        report.users_source = Some(source);
+    }
+
+    Ok(report)
+}
+
+/// Every product in `text` that still parses, in file order.
+pub fn salvage_products(text: &str) -> Vec<Product> {
+    let mut seen = HashSet::new();
+    salvage::<Product>(text).into_iter().filter(|p| seen.insert(p.id)).collect()
+}
+
+/// Every transaction in `text` that still parses, in file order.
+pub fn salvage_transactions(text: &str) -> Vec<Transaction> {
+    let mut seen = HashSet::new();
+    salvage::<Transaction>(text).into_iter().filter(|t| seen.insert(t.id)).collect()
+}
+
+/// The events in `text` numbered on from the first one found, up to the first
+/// one missing.
+/// Later events would be replayed without the ones they depend on.
+pub fn salvage_events(text: &str) -> Vec<RecordedEvent> {
+    let mut events = salvage::<RecordedEvent>(text);
+    events.sort_by_key(|e| e.seq);
+    events.dedup_by_key(|e| e.seq);
+    // Archiving starts the log over at a later seq, so count from the first one found
+    let first = events.first().map(|e| e.seq).unwrap_or(1);
+    let intact = events.iter().enumerate().take_while(|(i, e)| e.seq == first + *i as u64).count();
+    events.truncate(intact);
+    events
+}
+
+pub fn salvage_users(text: &str) -> HashMap<String, User> {
+    salvage::<User>(text).into_iter().map(|u| (u.username.clone(), u)).collect()
+}
+
+fn read_lossy(storage: &JsonStorage, path: &Path) -> Result<String, StoreError> {
+    let bytes = storage.read_file(path)?.ok_or(StoreError::NotFound)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn salvage<T: DeserializeOwned>(text: &str) -> Vec<T> {
+    let mut found: Vec<(usize, T)> = json_objects(text).into_iter()
+        .filter_map(|(start, object)| serde_json::from_str(object).ok().map(|value| (start, value)))
+        .collect();
+    found.sort_by_key(|(start, _)| *start);
+    found.into_iter().map(|(_, value)| value).collect()
+}
+
+// Every complete `{...}` in `text` with its offset. Braces are matched outside
+// string literals only, so this keeps working on truncated or partly
+// overwritten files where the document as a whole no longer parses.
+fn json_objects(text: &str) -> Vec<(usize, &str)> {
+    let mut starts = Vec::new();
+    let mut objects = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, byte) in text.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {},
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' => starts.push(i),
+            b'}' => {
+                if let Some(start) = starts.pop() {
+                    objects.push((start, &text[start..=i]));
+                }
+            },
+            _ => {},
+        }
+    }
+    objects
+}
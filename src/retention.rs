@@ -0,0 +1,262 @@
+use crate::config::format_money;
+use crate::crypto::{self, Cipher};
+use crate::errors::StoreError;
+use crate::events::RecordedEvent;
+use crate::models::{Transaction, TransactionType};
+use crate::storage::write_atomic;
+use crate::store::round_cents;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Directory inside the data directory that archive files are written to.
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// Length of the periods archived transactions are summarised by. Periods
+/// start at local midnight in the configured timezone; weeks start on Monday.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl FromStr for Period {
+    type Err = StoreError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_lowercase().as_str() {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            other => Err(StoreError::InvalidInput(format!(
+                "Unknown period '{}'; expected day, week, month or year", other
+            ))),
+        }
+    }
+}
+
+impl Period {
+    /// The period containing `at` as `[start, end)`.
+    pub fn bounds(&self, timezone: &Tz, at: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let date = at.with_timezone(timezone).date_naive();
+        let start = match self {
+            Period::Day => date,
+            Period::Week => date - Duration::days(date.weekday().num_days_from_monday() as i64),
+            Period::Month => date.with_day(1).unwrap_or(date),
+            Period::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap_or(date),
+        };
+        let end = match self {
+            Period::Day => start + Duration::days(1),
+            Period::Week => start + Duration::days(7),
+            Period::Month => start.checked_add_months(chrono::Months::new(1)).unwrap_or(start),
+            Period::Year => start.checked_add_months(chrono::Months::new(12)).unwrap_or(start),
+        };
+        (local_midnight(timezone, start), local_midnight(timezone, end))
+    }
+
+    /// How the period starting at `start` is shown, e.g. `2024-03` for a month.
+    pub fn label(&self, timezone: &Tz, start: DateTime<Utc>) -> String {
+        let local = start.with_timezone(timezone);
+        match self {
+            Period::Day => local.format("%Y-%m-%d").to_string(),
+            Period::Week => local.format("%G-W%V").to_string(),
+            Period::Month => local.format("%Y-%m").to_string(),
+            Period::Year => local.format("%Y").to_string(),
+        }
+    }
+}
+
+// Where a DST change skips midnight, the day starts at the first hour that exists.
+fn local_midnight(timezone: &Tz, date: NaiveDate) -> DateTime<Utc> {
+    (0..3)
+        .filter_map(|hour| NaiveTime::from_hms_opt(hour, 0, 0))
+        .find_map(|time| timezone.from_local_datetime(&date.and_time(time)).earliest())
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)))
+}
+
+/// Where archiving starts: the beginning of the period `keep_days` before `now`,
+/// so only whole periods are archived.
+pub fn cutoff(keep_days: u32, period: Period, timezone: &Tz, now: DateTime<Utc>) -> DateTime<Utc> {
+    period.bounds(timezone, now - Duration::days(keep_days as i64)).0
+}
+
+/// The transactions of one product and type in one archived period.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeriodSummary {
+    pub period: Period,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub product_id: Uuid,
+    pub transaction_type: TransactionType,
+    pub count: usize,
+    /// Units as recorded, so adjustments keep their sign.
+    pub quantity: i64,
+    pub value: f64,
+}
+
+impl PeriodSummary {
+    /// How the period moved stock, with the same signs as `ledger::quantity_change`.
+    pub fn quantity_change(&self) -> i64 {
+        match self.transaction_type {
+            TransactionType::Sale => -self.quantity,
+            TransactionType::Purchase | TransactionType::Adjustment => self.quantity,
+        }
+    }
+}
+
+/// Rolls transactions up by period, product and type, oldest period first.
+pub fn summarize(transactions: &[Transaction], period: Period, timezone: &Tz) -> Vec<PeriodSummary> {
+    let mut summaries: HashMap<(DateTime<Utc>, Uuid, u8), PeriodSummary> = HashMap::new();
+    for transaction in transactions {
+        let (start, end) = period.bounds(timezone, transaction.timestamp);
+        let kind = match transaction.transaction_type {
+            TransactionType::Sale => 0,
+            TransactionType::Purchase => 1,
+            TransactionType::Adjustment => 2,
+        };
+        let summary = summaries.entry((start, transaction.product_id, kind)).or_insert_with(|| PeriodSummary {
+            period,
+            start,
+            end,
+            product_id: transaction.product_id,
+            transaction_type: transaction.transaction_type.clone(),
+            count: 0,
+            quantity: 0,
+            value: 0.0,
+        });
+        summary.count += 1;
+        summary.quantity += transaction.quantity as i64;
+        summary.value = round_cents(summary.value + transaction.price * transaction.quantity as f64);
+    }
+
+    let mut summaries: Vec<(u8, PeriodSummary)> = summaries.into_iter().map(|((_, _, kind), s)| (kind, s)).collect();
+    summaries.sort_by(|(a_kind, a), (b_kind, b)| (a.start, a.product_id, a_kind).cmp(&(b.start, b.product_id, b_kind)));
+    summaries.into_iter().map(|(_, s)| s).collect()
+}
+
+/// Net stock movement per product across `summaries`.
+pub fn net_quantities(summaries: &[PeriodSummary]) -> HashMap<Uuid, i64> {
+    let mut totals = HashMap::new();
+    for summary in summaries {
+        *totals.entry(summary.product_id).or_default() += summary.quantity_change();
+    }
+    totals
+}
+
+/// Sales or purchases per archived period across all products, for the end of
+/// the sales and purchase reports. Returns the report lines and their total.
+pub fn period_totals(summaries: &[PeriodSummary], transaction_type: &TransactionType, timezone: &Tz, currency: &str) -> (String, f64) {
+    let mut periods: Vec<(DateTime<Utc>, Period, i64, f64)> = Vec::new();
+    for summary in summaries.iter().filter(|s| &s.transaction_type == transaction_type) {
+        match periods.iter_mut().find(|(start, period, _, _)| *start == summary.start && *period == summary.period) {
+            Some((_, _, quantity, value)) => {
+                *quantity += summary.quantity;
+                *value += summary.value;
+            },
+            None => periods.push((summary.start, summary.period, summary.quantity, summary.value)),
+        }
+    }
+    periods.sort_by_key(|(start, ..)| *start);
+
+    let mut lines = String::new();
+    for (start, period, quantity, value) in &periods {
+        lines.push_str(&format!("Archived Period: {}\nQuantity: {}\nTotal: {}\n\n",
+            period.label(timezone, *start), quantity, format_money(currency, round_cents(*value))));
+    }
+    (lines, round_cents(periods.iter().map(|(.., value)| value).sum()))
+}
+
+/// One archive file and what it covers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionArchive {
+    pub file: String,
+    /// Oldest transaction in the file.
+    pub from: DateTime<Utc>,
+    /// The cutoff it was archived at; every transaction in it is older.
+    pub to: DateTime<Utc>,
+    pub transactions: usize,
+    pub events: usize,
+    pub created_at: DateTime<Utc>,
+    /// Hash of the last transaction in the file, which the first one left in
+    /// the store points back to.
+    #[serde(default)]
+    pub head: Option<String>,
+}
+
+/// What an archive file holds: the raw transactions and the events they were
+/// folded out of, so the audit trail survives archiving.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchiveContents {
+    pub transactions: Vec<Transaction>,
+    pub events: Vec<RecordedEvent>,
+}
+
+/// Gzipped JSON archive files in one directory, encrypted like the data files
+/// when a cipher is set.
+pub struct ArchiveDir {
+    dir: PathBuf,
+    cipher: Option<Cipher>,
+}
+
+impl ArchiveDir {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ArchiveDir { dir: dir.into(), cipher: None }
+    }
+
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn write(&self, file: &str, contents: &ArchiveContents) -> Result<(), StoreError> {
+        let path = self.dir.join(file);
+        let io_error = |e: std::io::Error| StoreError::DatabaseError(format!("{}: {}", path.display(), e));
+        fs::create_dir_all(&self.dir).map_err(io_error)?;
+
+        let json = serde_json::to_vec(contents).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&json).map_err(io_error)?;
+        let compressed = encoder.finish().map_err(io_error)?;
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.encrypt(&compressed)?,
+            None => compressed,
+        };
+        write_atomic(&path, 0, &bytes)
+    }
+
+    pub fn read(&self, archive: &TransactionArchive) -> Result<ArchiveContents, StoreError> {
+        let path = self.dir.join(&archive.file);
+        let name = path.display().to_string();
+        let mut bytes = fs::read(&path).map_err(|e| StoreError::DatabaseError(format!("{}: {}", name, e)))?;
+        if crypto::is_encrypted(&bytes) {
+            let cipher = self.cipher.as_ref().ok_or_else(|| StoreError::KeyRequired(name.clone()))?;
+            bytes = cipher.decrypt(&bytes, &name)?;
+        }
+        serde_json::from_reader(GzDecoder::new(bytes.as_slice()))
+            .map_err(|e| StoreError::DatabaseError(format!("{}: {}", name, e)))
+    }
+
+    /// Where `archive` is kept, so its file can be rewritten under a new key
+    /// along with the store's.
+    pub fn file_path(&self, archive: &TransactionArchive) -> PathBuf {
+        self.dir.join(&archive.file)
+    }
+}
@@ -0,0 +1,164 @@
+use crate::errors::StoreError;
+use crate::migrations::SCHEMA_VERSION;
+use crate::storage::DEFAULT_STORE_FILE;
+use crate::store::{StoreData, StoreView};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const BINARY_STORE_FILE: &str = "store.bin";
+
+/// First bytes of every binary snapshot.
+pub const MAGIC: &[u8; 4] = b"RSSB";
+/// Version of the header layout and encoding below.
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 22;
+
+/// How the store file is written. Either format is read whatever is configured.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreFormat {
+    #[default]
+    Json,
+    Binary,
+}
+
+impl FromStr for StoreFormat {
+    type Err = StoreError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_lowercase().as_str() {
+            "json" => Ok(StoreFormat::Json),
+            "binary" => Ok(StoreFormat::Binary),
+            other => Err(StoreError::InvalidInput(format!("Unknown store format '{}'; expected json or binary", other))),
+        }
+    }
+}
+
+impl StoreFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreFormat::Json => "json",
+            StoreFormat::Binary => "binary",
+        }
+    }
+
+    /// The store file's name in the data directory.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            StoreFormat::Json => DEFAULT_STORE_FILE,
+            StoreFormat::Binary => BINARY_STORE_FILE,
+        }
+    }
+
+    pub fn encode(&self, data: &StoreView) -> Result<Vec<u8>, StoreError> {
+        match self {
+            StoreFormat::Json => serde_json::to_vec(data).map_err(|e| StoreError::DatabaseError(e.to_string())),
+            StoreFormat::Binary => encode(data),
+        }
+    }
+}
+
+/// The fixed-size header in front of the payload, all little-endian.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub format_version: u16,
+    pub schema_version: u32,
+    /// CRC-32 of the payload.
+    pub checksum: u32,
+    pub length: u64,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.format_version.to_le_bytes());
+        bytes.extend_from_slice(&self.schema_version.to_le_bytes());
+        bytes.extend_from_slice(&self.checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the header, failing if `bytes` is too short to hold one.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if !is_binary(bytes) || bytes.len() < HEADER_LEN {
+            return Err("not a binary snapshot or header is truncated".to_string());
+        }
+        let field = |range: std::ops::Range<usize>| &bytes[range];
+        Ok(Header {
+            format_version: u16::from_le_bytes(field(4..6).try_into().unwrap_or_default()),
+            schema_version: u32::from_le_bytes(field(6..10).try_into().unwrap_or_default()),
+            checksum: u32::from_le_bytes(field(10..14).try_into().unwrap_or_default()),
+            length: u64::from_le_bytes(field(14..22).try_into().unwrap_or_default()),
+        })
+    }
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// A header followed by the store as compact MessagePack: structs as arrays
+/// of their fields in order and IDs as raw bytes. The layout is that of the
+/// schema version in the header; see `decode`.
+pub fn encode(data: &StoreView) -> Result<Vec<u8>, StoreError> {
+    let payload = rmp_serde::to_vec(data).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+
+    let header = Header {
+        format_version: FORMAT_VERSION,
+        schema_version: data.schema_version,
+        checksum: crc32fast::hash(&payload),
+        length: payload.len() as u64,
+    };
+    let mut bytes = header.to_bytes();
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+// The payload after checking it against the header.
+fn payload(bytes: &[u8]) -> Result<(Header, &[u8]), String> {
+    let header = Header::parse(bytes)?;
+    if header.format_version != FORMAT_VERSION {
+        return Err(format!("snapshot format version {} is not supported (expected {})",
+            header.format_version, FORMAT_VERSION));
+    }
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() as u64 != header.length {
+        return Err(format!("payload is {} bytes but the header says {}", payload.len(), header.length));
+    }
+    if crc32fast::hash(payload) != header.checksum {
+        return Err("checksum does not match; the file is damaged".to_string());
+    }
+    Ok((header, payload))
+}
+
+/// The snapshot as a JSON document, e.g. to see what migrating it would do.
+/// Damage is reported through `corrupt`.
+pub fn decode_document<C>(bytes: &[u8], corrupt: C) -> Result<serde_json::Value, StoreError>
+where
+    C: Fn(String) -> StoreError,
+{
+    let data = decode(bytes, corrupt)?;
+    serde_json::to_value(data).map_err(|e| StoreError::DatabaseError(e.to_string()))
+}
+
+/// Reads a snapshot. The payload only decodes against the layout of the schema
+/// version that wrote it, so one from another schema is refused rather than
+/// migrated. The checksum has been verified by then, so the data is marked
+/// `checksummed` and the store takes its state as it is. Damage is reported
+/// through `corrupt`.
+pub fn decode<C>(bytes: &[u8], corrupt: C) -> Result<StoreData, StoreError>
+where
+    C: Fn(String) -> StoreError,
+{
+    let (header, payload) = payload(bytes).map_err(&corrupt)?;
+    if header.schema_version != SCHEMA_VERSION {
+        return Err(StoreError::InvalidInput(format!(
+            "snapshot is for schema version {} but this version reads {}; convert it to JSON with the version that wrote it",
+            header.schema_version, SCHEMA_VERSION
+        )));
+    }
+    let mut data: StoreData = rmp_serde::from_slice(payload).map_err(|e| corrupt(e.to_string()))?;
+    data.checksummed = true;
+    Ok(data)
+}
@@ -0,0 +1,499 @@
+use crate::errors::StoreError;
+use crate::events::RecordedEvent;
+use crate::migrations::SCHEMA_VERSION;
+use crate::models::{Product, Transaction, TransactionType, User, UserRole};
+use crate::storage::{JsonStorage, Storage};
+use crate::store::{Store, StoreChanges, StoreData, StoreView};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS products (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    price REAL NOT NULL,
+    quantity INTEGER NOT NULL,
+    version INTEGER NOT NULL DEFAULT 0,
+    sku TEXT,
+    archived_at TEXT
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    id TEXT PRIMARY KEY,
+    product_id TEXT NOT NULL,
+    quantity INTEGER NOT NULL,
+    price REAL NOT NULL,
+    transaction_type TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    prev_hash TEXT,
+    user TEXT
+);
+CREATE INDEX IF NOT EXISTS idx_transactions_product ON transactions (product_id, timestamp);
+CREATE INDEX IF NOT EXISTS idx_transactions_type ON transactions (transaction_type, timestamp);
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    id TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    role TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS events (
+    seq INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS documents (
+    name TEXT PRIMARY KEY,
+    body TEXT NOT NULL
+);
+";
+
+/// Store data in an embedded SQLite database. The event log, products,
+/// transactions and users get real tables; the remaining bookkeeping (invoices,
+/// gift cards, coupons, loyalty) is kept as JSON documents.
+pub struct SqliteStorage {
+    conn: Connection,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub products: usize,
+    pub transactions: usize,
+    pub users: usize,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::init(Connection::open(path).map_err(db_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::init(Connection::open_in_memory().map_err(db_error)?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, StoreError> {
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .map_err(db_error)?;
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        // Databases created by earlier versions lack the newer product columns
+        add_missing_column(&conn, "products", "version", "INTEGER NOT NULL DEFAULT 0")?;
+        add_missing_column(&conn, "products", "sku", "TEXT")?;
+        add_missing_column(&conn, "products", "archived_at", "TEXT")?;
+        add_missing_column(&conn, "transactions", "prev_hash", "TEXT")?;
+        add_missing_column(&conn, "transactions", "user", "TEXT")?;
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_transactions_user ON transactions (user, timestamp);")
+            .map_err(db_error)?;
+        Ok(SqliteStorage { conn })
+    }
+
+    /// Copies existing `store.json`/`users.json` data into this database.
+    pub fn import_json(&mut self, json: &JsonStorage) -> Result<ImportSummary, StoreError> {
+        let mut summary = ImportSummary { products: 0, transactions: 0, users: 0 };
+        if let Some(data) = json.load_store()? {
+            summary.products = data.products.len();
+            summary.transactions = data.transactions.len();
+            self.save_store(&data.view())?;
+        }
+        if let Some(users) = json.load_users()? {
+            summary.users = users.len();
+            self.save_users(&users)?;
+        }
+        Ok(summary)
+    }
+
+    pub fn product_history(&self, product_id: &Uuid) -> Result<Vec<Transaction>, StoreError> {
+        self.query_transactions(
+            "SELECT id, product_id, quantity, price, transaction_type, timestamp, prev_hash, user FROM transactions
+             WHERE product_id = ?1 ORDER BY timestamp",
+            params![product_id.to_string()],
+        )
+    }
+
+    pub fn transactions_between(
+        &self,
+        transaction_type: TransactionType,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<Transaction>, StoreError> {
+        self.query_transactions(
+            "SELECT id, product_id, quantity, price, transaction_type, timestamp, prev_hash, user FROM transactions
+             WHERE transaction_type = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp",
+            params![type_name(&transaction_type), timestamp(&from), timestamp(&to)],
+        )
+    }
+
+    /// Units and revenue per product for a period, computed inside SQLite.
+    pub fn sales_by_product(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<(Uuid, i64, f64)>, StoreError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT product_id, SUM(quantity), SUM(quantity * price) FROM transactions
+             WHERE transaction_type = 'Sale' AND timestamp >= ?1 AND timestamp < ?2
+             GROUP BY product_id ORDER BY SUM(quantity * price) DESC",
+        ).map_err(db_error)?;
+        let rows = stmt.query_map(params![timestamp(&from), timestamp(&to)], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, f64>(2)?))
+        }).map_err(db_error)?;
+
+        let mut totals = Vec::new();
+        for row in rows {
+            let (id, quantity, revenue) = row.map_err(db_error)?;
+            totals.push((parse_uuid(&id)?, quantity, revenue));
+        }
+        Ok(totals)
+    }
+
+    fn query_transactions(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Transaction>, StoreError> {
+        let mut stmt = self.conn.prepare(sql).map_err(db_error)?;
+        let rows = stmt.query_map(params, raw_transaction).map_err(db_error)?;
+        let mut transactions = Vec::new();
+        for row in rows {
+            transactions.push(row.map_err(db_error)?.into_transaction()?);
+        }
+        Ok(transactions)
+    }
+
+    fn load_events(&self) -> Result<Vec<RecordedEvent>, StoreError> {
+        let mut stmt = self.conn.prepare("SELECT body FROM events ORDER BY seq").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0)).map_err(db_error)?;
+        let mut events = Vec::new();
+        for row in rows {
+            let body = row.map_err(db_error)?;
+            events.push(serde_json::from_str(&body).map_err(|e| StoreError::DatabaseError(e.to_string()))?);
+        }
+        Ok(events)
+    }
+
+    fn load_document<T: DeserializeOwned + Default>(&self, name: &str) -> Result<T, StoreError> {
+        let body: Option<String> = self.conn
+            .query_row("SELECT body FROM documents WHERE name = ?1", params![name], |row| row.get(0))
+            .optional()
+            .map_err(db_error)?;
+        match body {
+            Some(body) => serde_json::from_str(&body).map_err(|e| StoreError::DatabaseError(e.to_string())),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+fn add_missing_column(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<(), StoreError> {
+    let present: bool = conn
+        .query_row(
+            "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    if !present {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .map_err(db_error)?;
+    }
+    Ok(())
+}
+
+fn save_document<T: Serialize + ?Sized>(tx: &rusqlite::Transaction, name: &str, value: &T) -> Result<(), StoreError> {
+    let body = serde_json::to_string(value).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+    tx.execute(
+        "INSERT INTO documents (name, body) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET body = excluded.body",
+        params![name, body],
+    ).map_err(db_error)?;
+    Ok(())
+}
+
+fn upsert_product(tx: &rusqlite::Transaction, product: &Product) -> Result<(), StoreError> {
+    tx.execute(
+        "INSERT INTO products (id, name, description, price, quantity, version, sku, archived_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+         ON CONFLICT(id) DO UPDATE SET name = excluded.name, description = excluded.description,
+             price = excluded.price, quantity = excluded.quantity, version = excluded.version, sku = excluded.sku,
+             archived_at = excluded.archived_at",
+        params![
+            product.id.to_string(),
+            product.name,
+            product.description,
+            product.price,
+            product.quantity,
+            product.version as i64,
+            product.sku,
+            product.archived_at.as_ref().map(timestamp),
+        ],
+    ).map_err(db_error)?;
+    Ok(())
+}
+
+fn insert_event(tx: &rusqlite::Transaction, event: &RecordedEvent) -> Result<(), StoreError> {
+    let body = serde_json::to_string(event).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+    tx.execute(
+        "INSERT OR REPLACE INTO events (seq, timestamp, body) VALUES (?1, ?2, ?3)",
+        params![event.seq as i64, timestamp(&event.timestamp), body],
+    ).map_err(db_error)?;
+    Ok(())
+}
+
+fn insert_transaction(tx: &rusqlite::Transaction, transaction: &Transaction) -> Result<(), StoreError> {
+    tx.execute(
+        "INSERT OR REPLACE INTO transactions (id, product_id, quantity, price, transaction_type, timestamp, prev_hash, user)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            transaction.id.to_string(),
+            transaction.product_id.to_string(),
+            transaction.quantity,
+            transaction.price,
+            type_name(&transaction.transaction_type),
+            timestamp(&transaction.timestamp),
+            transaction.prev_hash,
+            transaction.user,
+        ],
+    ).map_err(db_error)?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load_store(&self) -> Result<Option<StoreData>, StoreError> {
+        let saved: i64 = self.conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM products) + (SELECT COUNT(*) FROM documents) + (SELECT COUNT(*) FROM events)",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(db_error)?;
+        if saved == 0 {
+            return Ok(None);
+        }
+
+        let mut products = HashMap::new();
+        let mut stmt = self.conn
+            .prepare("SELECT id, name, description, price, quantity, version, sku, archived_at FROM products")
+            .map_err(db_error)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get::<_, i64>(5)?,
+                row.get(6)?,
+                row.get::<_, Option<String>>(7)?,
+            ))
+        }).map_err(db_error)?;
+        for row in rows {
+            let (id, name, description, price, quantity, version, sku, archived_at) = row.map_err(db_error)?;
+            let id = parse_uuid(&id)?;
+            let archived_at = archived_at.as_deref().map(parse_timestamp).transpose()?;
+            products.insert(id, Product { id, name, description, price, quantity, version: version as u64, sku, archived_at });
+        }
+
+        let transactions = self.query_transactions(
+            "SELECT id, product_id, quantity, price, transaction_type, timestamp, prev_hash, user FROM transactions ORDER BY rowid",
+            [],
+        )?;
+
+        Ok(Some(StoreData {
+            schema_version: SCHEMA_VERSION,
+            products,
+            transactions,
+            invoices: self.load_document("invoices")?,
+            stored_value: self.load_document("stored_value")?,
+            customers: self.load_document("customers")?,
+            coupons: self.load_document("coupons")?,
+            loyalty: self.load_document("loyalty")?,
+            journal_seq: self.load_document("journal_seq")?,
+            summaries: self.load_document("summaries")?,
+            archives: self.load_document("archives")?,
+            events: self.load_events()?,
+            // Rows are written one at a time, so there is no single snapshot to sign
+            signature: None,
+            checksummed: false,
+        }))
+    }
+
+    fn save_store(&mut self, data: &StoreView) -> Result<(), StoreError> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM products", []).map_err(db_error)?;
+        for product in data.products.values() {
+            upsert_product(&tx, product)?;
+        }
+        tx.execute("DELETE FROM transactions", []).map_err(db_error)?;
+        for transaction in data.transactions {
+            insert_transaction(&tx, transaction)?;
+        }
+        save_document(&tx, "invoices", data.invoices)?;
+        save_document(&tx, "stored_value", data.stored_value)?;
+        save_document(&tx, "customers", data.customers)?;
+        save_document(&tx, "coupons", data.coupons)?;
+        save_document(&tx, "loyalty", data.loyalty)?;
+        save_document(&tx, "journal_seq", &data.journal_seq)?;
+        save_document(&tx, "summaries", data.summaries)?;
+        save_document(&tx, "archives", data.archives)?;
+        tx.execute("DELETE FROM events", []).map_err(db_error)?;
+        for event in data.events {
+            insert_event(&tx, event)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    /// Reads just the id and version columns.
+    fn product_versions(&self) -> Result<HashMap<Uuid, u64>, StoreError> {
+        let mut stmt = self.conn.prepare("SELECT id, version FROM products").map_err(db_error)?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))
+            .map_err(db_error)?;
+        let mut versions = HashMap::new();
+        for row in rows {
+            let (id, version) = row.map_err(db_error)?;
+            versions.insert(parse_uuid(&id)?, version as u64);
+        }
+        Ok(versions)
+    }
+
+    /// Writes only the changed products, the new transactions and events, and the
+    /// documents if any of them changed, in one SQLite transaction.
+    fn save_changes(&mut self, _store: &Store, changes: &StoreChanges) -> Result<(), StoreError> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        for product in &changes.products {
+            upsert_product(&tx, product)?;
+        }
+        for id in &changes.deleted_products {
+            tx.execute("DELETE FROM products WHERE id = ?1", params![id.to_string()]).map_err(db_error)?;
+        }
+        for transaction in changes.transactions {
+            insert_transaction(&tx, transaction)?;
+        }
+        if let Some(documents) = &changes.documents {
+            save_document(&tx, "invoices", documents.invoices)?;
+            save_document(&tx, "stored_value", documents.stored_value)?;
+            save_document(&tx, "customers", documents.customers)?;
+            save_document(&tx, "coupons", documents.coupons)?;
+            save_document(&tx, "loyalty", documents.loyalty)?;
+        }
+        save_document(&tx, "journal_seq", &changes.journal_seq)?;
+        for event in changes.events {
+            insert_event(&tx, event)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    /// Writes the event, the ledger entry and the product's new stock level in one SQLite transaction.
+    fn record_transaction(&mut self, event: &RecordedEvent, product: &Product) -> Result<(), StoreError> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        insert_event(&tx, event)?;
+        upsert_product(&tx, product)?;
+        if let Some(transaction) = event.event.transaction() {
+            insert_transaction(&tx, transaction)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    fn load_users(&self) -> Result<Option<HashMap<String, User>>, StoreError> {
+        let mut stmt = self.conn
+            .prepare("SELECT username, id, password_hash, role FROM users")
+            .map_err(db_error)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get(2)?, row.get::<_, String>(3)?))
+        }).map_err(db_error)?;
+
+        let mut users = HashMap::new();
+        for row in rows {
+            let (username, id, password_hash, role) = row.map_err(db_error)?;
+            let role = match role.as_str() {
+                "Manager" => UserRole::Manager,
+                "Employee" => UserRole::Employee,
+                other => return Err(StoreError::DatabaseError(format!("Unknown role '{}'", other))),
+            };
+            users.insert(username.clone(), User { id: parse_uuid(&id)?, username, password_hash, role });
+        }
+
+        Ok(if users.is_empty() { None } else { Some(users) })
+    }
+
+    fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), StoreError> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM users", []).map_err(db_error)?;
+        for user in users.values() {
+            let role = match user.role {
+                UserRole::Manager => "Manager",
+                UserRole::Employee => "Employee",
+            };
+            tx.execute(
+                "INSERT INTO users (username, id, password_hash, role) VALUES (?1, ?2, ?3, ?4)",
+                params![user.username, user.id.to_string(), user.password_hash, role],
+            ).map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)
+    }
+}
+
+// Columns as stored, converted after the row callback so parse errors surface as `StoreError`.
+struct RawTransaction {
+    id: String,
+    product_id: String,
+    quantity: i32,
+    price: f64,
+    transaction_type: String,
+    timestamp: String,
+    prev_hash: Option<String>,
+    user: Option<String>,
+}
+
+impl RawTransaction {
+    fn into_transaction(self) -> Result<Transaction, StoreError> {
+        Ok(Transaction {
+            id: parse_uuid(&self.id)?,
+            product_id: parse_uuid(&self.product_id)?,
+            quantity: self.quantity,
+            price: self.price,
+            transaction_type: match self.transaction_type.as_str() {
+                "Sale" => TransactionType::Sale,
+                "Purchase" => TransactionType::Purchase,
+                "Adjustment" => TransactionType::Adjustment,
+                other => return Err(StoreError::DatabaseError(format!("Unknown transaction type '{}'", other))),
+            },
+            timestamp: parse_timestamp(&self.timestamp)?,
+            prev_hash: self.prev_hash,
+            user: self.user,
+        })
+    }
+}
+
+fn raw_transaction(row: &Row) -> rusqlite::Result<RawTransaction> {
+    Ok(RawTransaction {
+        id: row.get(0)?,
+        product_id: row.get(1)?,
+        quantity: row.get(2)?,
+        price: row.get(3)?,
+        transaction_type: row.get(4)?,
+        timestamp: row.get(5)?,
+        prev_hash: row.get(6)?,
+        user: row.get(7)?,
+    })
+}
+
+fn type_name(transaction_type: &TransactionType) -> &'static str {
+    match transaction_type {
+        TransactionType::Sale => "Sale",
+        TransactionType::Purchase => "Purchase",
+        TransactionType::Adjustment => "Adjustment",
+    }
+}
+
+// Fixed-width UTC timestamps so string comparison in SQL matches time order.
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_timestamp(text: &str) -> Result<DateTime<Utc>, StoreError> {
+    DateTime::parse_from_rfc3339(text)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| StoreError::DatabaseError(e.to_string()))
+}
+
+fn parse_uuid(text: &str) -> Result<Uuid, StoreError> {
+    Uuid::parse_str(text).map_err(|e| StoreError::DatabaseError(e.to_string()))
+}
+
+fn db_error(e: rusqlite::Error) -> StoreError {
+    StoreError::DatabaseError(e.to_string())
+}
@@ -0,0 +1,565 @@
+use crate::crypto::{self, Cipher};
+use crate::errors::StoreError;
+use crate::migrations::{self, MigrationReport, SCHEMA_VERSION};
+use crate::events::RecordedEvent;
+use crate::integrity::Signer;
+use crate::models::{Product, User};
+use crate::snapshot::{self, StoreFormat};
+use crate::store::{Store, StoreChanges, StoreData, StoreView};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+pub const DEFAULT_STORE_FILE: &str = "store.json";
+pub const DEFAULT_USERS_FILE: &str = "users.json";
+
+/// How many previous versions of each file `JsonStorage` keeps.
+pub const DEFAULT_BACKUPS: usize = 5;
+
+const BACKUP_TIMESTAMP: &str = "%Y%m%dT%H%M%S%.6f";
+
+/// Where `Store` and `Auth` keep their data. Loads return `Ok(None)` when nothing
+/// has been saved yet.
+pub trait Storage {
+    fn load_store(&self) -> Result<Option<StoreData>, StoreError>;
+    fn save_store(&mut self, data: &StoreView) -> Result<(), StoreError>;
+
+    /// Something that changes whenever the saved store does, such as a file's
+    /// size and modification time. `Store::save` skips looking for other
+    /// sessions' changes while it matches what the store last loaded or saved.
+    /// `None` when the backend cannot tell cheaply.
+    fn revision(&self) -> Result<Option<String>, StoreError> {
+        Ok(None)
+    }
+
+    /// Each saved product's version, for `Store::save` to spot products another
+    /// session has changed. Backends that can should answer without loading the
+    /// whole store.
+    fn product_versions(&self) -> Result<HashMap<Uuid, u64>, StoreError> {
+        Ok(self.load_store()?
+            .map(|data| data.products.values().map(|p| (p.id, p.version)).collect())
+            .unwrap_or_default())
+    }
+
+    /// Called by `Store::write_through` with the event that recorded a sale or
+    /// purchase and the product's new state. Backends that can write a single
+    /// row do so immediately; file backends wait for the next save.
+    fn record_transaction(&mut self, _event: &RecordedEvent, _product: &Product) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Called by `Store::save` with what changed since the last save. Backends
+    /// that keep the store as one document rewrite it whole, straight from the
+    /// store rather than from a copy of it.
+    fn save_changes(&mut self, store: &Store, _changes: &StoreChanges) -> Result<(), StoreError> {
+        self.save_store(&store.view())
+    }
+
+    fn load_users(&self) -> Result<Option<HashMap<String, User>>, StoreError>;
+    fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), StoreError>;
+}
+
+/// The original file layout: one JSON document for the store and one for users.
+/// The store file can be written as a binary snapshot instead; either is read.
+/// Files are replaced atomically, and the version being replaced is kept as a
+/// timestamped backup next to it (`store.json.<timestamp>.bak`). With a cipher
+/// set, files are written encrypted; plain files are still read, so existing
+/// data is encrypted at its next save.
+pub struct JsonStorage {
+    store_path: PathBuf,
+    users_path: PathBuf,
+    backups: usize,
+    cipher: Option<Cipher>,
+    format: StoreFormat,
+    signer: Option<Signer>,
+}
+
+/// What `JsonStorage::recover_rotation` did with an interrupted key rotation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationRecovery {
+    /// Every file had been written; they are now all under the new key.
+    Finished,
+    /// Not every file had been written; they are all still under the old key.
+    RolledBack,
+}
+
+// The marker kept beside the store while a key rotation is under way.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Rotation {
+    pub(crate) files: Vec<PathBuf>,
+    /// Every file has been written under the new key.
+    pub(crate) written: bool,
+}
+
+/// A previous version of the store file.
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub taken_at: DateTime<Utc>,
+}
+
+impl Default for JsonStorage {
+    fn default() -> Self {
+        JsonStorage::new(DEFAULT_STORE_FILE, DEFAULT_USERS_FILE)
+    }
+}
+
+impl JsonStorage {
+    pub fn new(store_path: impl Into<PathBuf>, users_path: impl Into<PathBuf>) -> Self {
+        JsonStorage {
+            store_path: store_path.into(),
+            users_path: users_path.into(),
+            backups: DEFAULT_BACKUPS,
+            cipher: None,
+            format: StoreFormat::Json,
+            signer: None,
+        }
+    }
+
+    /// Signs the store file at every save, so `integrity::verify` can prove it
+    /// has not been edited since.
+    pub fn with_signer(mut self, signer: Option<Signer>) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    /// How the store file is written from now on.
+    pub fn with_format(mut self, format: StoreFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Number of backups to keep per file; 0 disables them.
+    pub fn with_backups(mut self, backups: usize) -> Self {
+        self.backups = backups;
+        self
+    }
+
+    /// Storage for a single store file, used by `Store::save_to_file` and `load_from_file`.
+    pub fn store_only(store_path: impl Into<PathBuf>) -> Self {
+        JsonStorage::new(store_path, DEFAULT_USERS_FILE)
+    }
+
+    /// How the store file is written.
+    pub fn format(&self) -> StoreFormat {
+        self.format
+    }
+
+    pub fn store_path(&self) -> &Path {
+        &self.store_path
+    }
+
+    pub fn users_path(&self) -> &Path {
+        &self.users_path
+    }
+
+    /// Backups of the store file, newest first.
+    pub fn backups(&self) -> Result<Vec<Backup>, StoreError> {
+        list_backups(&self.store_path)
+    }
+
+    /// Store files set aside by a failed load, newest first.
+    pub fn quarantined_store_files(&self) -> Result<Vec<PathBuf>, StoreError> {
+        list_quarantined(&self.store_path)
+    }
+
+    /// User files set aside by a failed load, newest first.
+    pub fn quarantined_users_files(&self) -> Result<Vec<PathBuf>, StoreError> {
+        list_quarantined(&self.users_path)
+    }
+
+    /// Reads a backup without touching the live store file. Older backups are
+    /// migrated like any other store file.
+    pub fn read_backup(&self, backup: &Backup) -> Result<StoreData, StoreError> {
+        let bytes = self.read_file(&backup.path)?.ok_or(StoreError::NotFound)?;
+        let damaged = |e: String| StoreError::DatabaseError(format!("{}: {}", backup.path.display(), e));
+        decode_store(&bytes, |e| damaged(e.to_string()), damaged)
+    }
+
+    /// What loading the store file would migrate, without changing it. `None`
+    /// when there is no store file yet.
+    pub fn plan_migration(&self) -> Result<Option<MigrationReport>, StoreError> {
+        match self.read_file(&self.store_path)? {
+            Some(bytes) => {
+                let damaged = |e: String| StoreError::DatabaseError(format!("{}: {}", self.store_path.display(), e));
+                let doc = match snapshot::is_binary(&bytes) {
+                    true => snapshot::decode_document(&bytes, damaged)?,
+                    false => serde_json::from_slice(&bytes).map_err(|e| damaged(e.to_string()))?,
+                };
+                migrations::dry_run(&doc).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Re-encrypts the store, users, backup and quarantined files under `cipher`,
+    /// or writes them in plain text when it is `None`, along with the files in
+    /// `also` (such as transaction archives). Every file is decrypted with the
+    /// current key and written beside itself under the new one before any is
+    /// replaced; a marker file beside the store records the rotation, so
+    /// `recover_rotation` can finish or undo one that was interrupted. Returns
+    /// the number of files rewritten.
+    pub fn rotate_key(&mut self, cipher: Option<Cipher>, also: &[PathBuf]) -> Result<usize, StoreError> {
+        self.recover_rotation()?;
+        let mut files = Vec::new();
+        for path in [&self.store_path, &self.users_path] {
+            files.push(path.clone());
+            files.extend(list_backups(path)?.into_iter().map(|b| b.path));
+            files.extend(list_quarantined(path)?);
+        }
+        files.extend(also.iter().cloned());
+
+        let mut contents = Vec::new();
+        for path in files {
+            if let Some(bytes) = self.read_file(&path)? {
+                contents.push((path, bytes));
+            }
+        }
+
+        // Nothing is replaced until every file has been written under the new key
+        let mut rotation = Rotation { files: contents.iter().map(|(path, _)| path.clone()).collect(), written: false };
+        self.write_rotation(&rotation)?;
+        for (path, bytes) in &contents {
+            let encoded = match &cipher {
+                Some(cipher) => cipher.encrypt(bytes),
+                None => Ok(bytes.clone()),
+            };
+            if let Err(e) = encoded.and_then(|bytes| write_synced(&rotating_path(path), &bytes)) {
+                self.recover_rotation()?;
+                return Err(e);
+            }
+        }
+        rotation.written = true;
+        if let Err(e) = self.write_rotation(&rotation) {
+            self.recover_rotation()?;
+            return Err(e);
+        }
+
+        // From here on the rotation is finished, now or by the next recovery
+        self.cipher = cipher;
+        self.recover_rotation()?;
+        Ok(contents.len())
+    }
+
+    /// Finishes a key rotation that was interrupted after every file had been
+    /// written under the new key, or throws its partly written files away if it
+    /// was interrupted before. Either way each file is left wholly under one
+    /// key. `None` when no rotation was under way.
+    pub fn recover_rotation(&self) -> Result<Option<RotationRecovery>, StoreError> {
+        let marker = self.rotation_path();
+        let rotation: Rotation = match read_file(&marker)? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| StoreError::DatabaseError(format!("{}: {}", marker.display(), e)))?,
+            None => return Ok(None),
+        };
+
+        for path in &rotation.files {
+            let rotating = rotating_path(path);
+            let moved = match rotation.written {
+                true => fs::rename(&rotating, path),
+                false => fs::remove_file(&rotating),
+            };
+            match moved {
+                Ok(()) => {},
+                // Already moved before the interruption, or never written
+                Err(e) if e.kind() == ErrorKind::NotFound => {},
+                Err(e) => return Err(StoreError::DatabaseError(format!("{}: {}", rotating.display(), e))),
+            }
+        }
+        sync_dir(&self.store_path);
+        fs::remove_file(&marker).map_err(|e| StoreError::DatabaseError(format!("{}: {}", marker.display(), e)))?;
+        Ok(Some(match rotation.written {
+            true => RotationRecovery::Finished,
+            false => RotationRecovery::RolledBack,
+        }))
+    }
+
+    fn rotation_path(&self) -> PathBuf {
+        self.store_path.with_file_name(format!("{}rotation", backup_prefix(&self.store_path)))
+    }
+
+    fn write_rotation(&self, rotation: &Rotation) -> Result<(), StoreError> {
+        let json = serde_json::to_vec(rotation).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        write_atomic(&self.rotation_path(), 0, &json)
+    }
+
+    /// Reads a file, decrypting it if it was written encrypted.
+    pub(crate) fn read_file(&self, path: &Path) -> Result<Option<Vec<u8>>, StoreError> {
+        let bytes = match read_file(path)? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+        if !crypto::is_encrypted(&bytes) {
+            return Ok(Some(bytes));
+        }
+        let cipher = self.cipher.as_ref().ok_or_else(|| StoreError::KeyRequired(path.display().to_string()))?;
+        match cipher.decrypt(&bytes, &path.display().to_string()) {
+            Err(StoreError::Damaged(_)) => Err(quarantine_snapshot(path, "encrypted data failed authentication".to_string())),
+            decrypted => decrypted.map(Some),
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> Result<Vec<u8>, StoreError> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(bytes),
+            None => Ok(bytes.to_vec()),
+        }
+    }
+}
+
+fn backup_prefix(path: &Path) -> String {
+    format!("{}.", path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default())
+}
+
+// Files beside `path` named `<file name>.<timestamp><suffix>`, newest first.
+fn list_stamped(path: &Path, suffix: &str) -> Result<Vec<(PathBuf, DateTime<Utc>)>, StoreError> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = backup_prefix(path);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StoreError::DatabaseError(format!("{}: {}", dir.display(), e))),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let stamp = match name.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(suffix)) {
+            Some(stamp) => stamp,
+            None => continue,
+        };
+        if let Ok(taken_at) = NaiveDateTime::parse_from_str(stamp, BACKUP_TIMESTAMP) {
+            files.push((entry.path(), taken_at.and_utc()));
+        }
+    }
+    files.sort_by_key(|(_, taken_at)| std::cmp::Reverse(*taken_at));
+    Ok(files)
+}
+
+fn list_backups(path: &Path) -> Result<Vec<Backup>, StoreError> {
+    Ok(list_stamped(path, ".bak")?.into_iter()
+        .map(|(path, taken_at)| Backup { path, taken_at })
+        .collect())
+}
+
+fn list_quarantined(path: &Path) -> Result<Vec<PathBuf>, StoreError> {
+    Ok(list_stamped(path, ".corrupt")?.into_iter().map(|(path, _)| path).collect())
+}
+
+// Moves a file that failed to parse out of the way so it is never overwritten.
+pub(crate) fn set_aside(path: &Path) -> Result<PathBuf, StoreError> {
+    let quarantined = path.with_file_name(format!(
+        "{}{}.corrupt", backup_prefix(path), Utc::now().format(BACKUP_TIMESTAMP)
+    ));
+    fs::rename(path, &quarantined).map_err(|e| {
+        StoreError::DatabaseError(format!("{} is corrupted and could not be moved aside: {}", path.display(), e))
+    })?;
+    Ok(quarantined)
+}
+
+/// Moves a file that failed to parse out of the way so it is never overwritten,
+/// and describes where parsing stopped.
+fn quarantine(path: &Path, error: serde_json::Error) -> StoreError {
+    let quarantined = match set_aside(path) {
+        Ok(quarantined) => quarantined,
+        Err(e) => return e,
+    };
+    StoreError::CorruptData {
+        path: path.display().to_string(),
+        line: error.line(),
+        column: error.column(),
+        // serde_json appends the position itself; it is reported separately here
+        message: error.to_string().split(" at line ").next().unwrap_or_default().to_string(),
+        quarantined: quarantined.display().to_string(),
+    }
+}
+
+// Copies the current file aside before it is replaced, then prunes the oldest copies.
+fn rotate_backups(path: &Path, keep: usize) -> Result<(), StoreError> {
+    if keep == 0 || !path.exists() {
+        return Ok(());
+    }
+    let stamp = Utc::now().format(BACKUP_TIMESTAMP);
+    let backup = path.with_file_name(format!("{}{}.bak", backup_prefix(path), stamp));
+    fs::copy(path, &backup).map_err(|e| StoreError::DatabaseError(format!("{}: {}", backup.display(), e)))?;
+
+    for old in list_backups(path)?.into_iter().skip(keep) {
+        fs::remove_file(&old.path).map_err(|e| StoreError::DatabaseError(format!("{}: {}", old.path.display(), e)))?;
+    }
+    Ok(())
+}
+
+/// Like `quarantine`, for a binary snapshot that failed its checks.
+fn quarantine_snapshot(path: &Path, message: String) -> StoreError {
+    match set_aside(path) {
+        Ok(quarantined) => StoreError::CorruptSnapshot {
+            path: path.display().to_string(),
+            message,
+            quarantined: quarantined.display().to_string(),
+        },
+        Err(e) => e,
+    }
+}
+
+/// Parses a store document or binary snapshot, upgrading it first if it was
+/// written by an older version.
+fn decode_store<E, C>(bytes: &[u8], parse_error: E, corrupt: C) -> Result<StoreData, StoreError>
+where
+    E: Fn(serde_json::Error) -> StoreError,
+    C: Fn(String) -> StoreError,
+{
+    if snapshot::is_binary(bytes) {
+        return snapshot::decode(bytes, corrupt);
+    }
+    let mut doc: serde_json::Value = serde_json::from_slice(bytes).map_err(&parse_error)?;
+    if migrations::schema_version(&doc) == SCHEMA_VERSION {
+        // Parse the text again so errors keep their line and column
+        return serde_json::from_slice(bytes).map_err(parse_error);
+    }
+    migrations::migrate(&mut doc)?;
+    serde_json::from_value(doc).map_err(parse_error)
+}
+
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>, StoreError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(StoreError::DatabaseError(format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Writes to a temporary file beside `path` and renames it into place, so a
+/// failure part-way leaves the old file intact.
+pub(crate) fn write_atomic(path: &Path, backups: usize, bytes: &[u8]) -> Result<(), StoreError> {
+    let io_error = |e: std::io::Error| StoreError::DatabaseError(format!("{}: {}", path.display(), e));
+    let tmp_path = path.with_file_name(format!("{}tmp", backup_prefix(path)));
+
+    write_synced(&tmp_path, bytes)?;
+    rotate_backups(path, backups)?;
+    fs::rename(&tmp_path, path).map_err(io_error)?;
+    sync_dir(path);
+    Ok(())
+}
+
+// Writes and flushes `bytes` to `path`, removing what was written if it fails.
+fn write_synced(path: &Path, bytes: &[u8]) -> Result<(), StoreError> {
+    let written = File::create(path)
+        .and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()))
+        .map_err(|e| StoreError::DatabaseError(format!("{}: {}", path.display(), e)));
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+// Persists renames in the directory holding `path`; not every platform lets a
+// directory be opened.
+fn sync_dir(path: &Path) {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+// Where a file's contents under the new key wait during a key rotation.
+fn rotating_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!("{}rotating", backup_prefix(path)))
+}
+
+impl Storage for JsonStorage {
+    fn load_store(&self) -> Result<Option<StoreData>, StoreError> {
+        match self.read_file(&self.store_path)? {
+            Some(bytes) => decode_store(&bytes, |e| quarantine(&self.store_path, e),
+                |message| quarantine_snapshot(&self.store_path, message)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn save_store(&mut self, data: &StoreView) -> Result<(), StoreError> {
+        let bytes = match &self.signer {
+            Some(signer) => self.format.encode(&StoreView { signature: Some(signer.sign(data)?), ..data.clone() })?,
+            None => self.format.encode(data)?,
+        };
+        write_atomic(&self.store_path, self.backups, &self.encode(&bytes)?)
+    }
+
+    fn revision(&self) -> Result<Option<String>, StoreError> {
+        let metadata = match fs::metadata(&self.store_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StoreError::DatabaseError(format!("{}: {}", self.store_path.display(), e))),
+        };
+        // Every save replaces the file, so its size and time change with it
+        let modified = metadata.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos());
+        Ok(modified.map(|modified| format!("{}:{}", metadata.len(), modified)))
+    }
+
+    fn load_users(&self) -> Result<Option<HashMap<String, User>>, StoreError> {
+        match self.read_file(&self.users_path)? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| quarantine(&self.users_path, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), StoreError> {
+        let json = serde_json::to_vec_pretty(users).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
+        write_atomic(&self.users_path, self.backups, &self.encode(&json)?)
+    }
+}
+
+/// Keeps everything in memory; nothing survives the process. Handy for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    store: Option<StoreData>,
+    users: Option<HashMap<String, User>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_store(&self) -> Result<Option<StoreData>, StoreError> {
+        Ok(self.store.clone())
+    }
+
+    fn save_store(&mut self, data: &StoreView) -> Result<(), StoreError> {
+        self.store = Some(data.to_data());
+        Ok(())
+    }
+
+    fn product_versions(&self) -> Result<HashMap<Uuid, u64>, StoreError> {
+        Ok(self.store.iter()
+            .flat_map(|data| data.products.values())
+            .map(|p| (p.id, p.version))
+            .collect())
+    }
+
+    fn load_users(&self) -> Result<Option<HashMap<String, User>>, StoreError> {
+        Ok(self.users.clone())
+    }
+
+    fn save_users(&mut self, users: &HashMap<String, User>) -> Result<(), StoreError> {
+        self.users = Some(users.clone());
+        Ok(())
+    }
+}
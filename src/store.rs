@@ -1,171 +1,1587 @@
-use crate::models::{Product, Transaction, TransactionType};
+use crate::models::{
+    Checkout, Coupon, CouponCode, CouponRedemption, Customer, Invoice, InvoiceLine, LoyaltyEntry, PaymentMethod,
+    Product, ShiftSummary, StoredValueAccount, StoredValueEntry, StoredValueKind, Transaction, TransactionType,
+};
+use chrono::{DateTime, Utc};
+use crate::config::Settings;
 use crate::errors::StoreError;
-use std::collections::HashMap;
+use crate::giftcard::{self, StoredValueBook};
+use crate::index::TransactionIndex;
+use crate::coupon::{self, CouponBook};
+use crate::events::{Event, Projection, RecordedEvent};
+use crate::integrity::{self, SnapshotSignature};
+use crate::journal::Journal;
+use crate::ledger::{self, Drift, StockReport};
+use crate::migrations::SCHEMA_VERSION;
+use crate::loyalty::{LoyaltyConfig, LoyaltyProgram};
+use crate::sync::{self, Changeset, MergeReport};
+use crate::retention::{self, ArchiveContents, ArchiveDir, Period, PeriodSummary, TransactionArchive};
+use crate::undo::{self, RestorePoint, UndoEntry, UNDO_LIMIT};
+use std::collections::{HashMap, HashSet, VecDeque};
 use uuid::Uuid;
-use serde_json::{to_writer, from_reader};
-use std::fs::File;
+use crate::storage::{JsonStorage, Storage};
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+/// The store's state is a projection of its event log: every change is recorded
+/// as an `Event` and only then applied, so the state can always be rebuilt by
+/// replaying `events()`.
 pub struct Store {
+    events: Vec<RecordedEvent>,
     products: HashMap<Uuid, Product>,
     transactions: Vec<Transaction>,
+    index: TransactionIndex,
+    invoices: Vec<Invoice>,
+    stored_value: StoredValueBook,
+    customers: HashMap<Uuid, Customer>,
+    coupons: CouponBook,
+    loyalty: LoyaltyProgram,
+    summaries: Vec<PeriodSummary>,
+    archives: Vec<TransactionArchive>,
+    restore_points: Vec<RestorePoint>,
+    /// This session's most recent actions, oldest first.
+    undo: VecDeque<UndoEntry>,
+    /// Sequence number of the last journal batch reflected in this state.
+    journal_seq: u64,
+    journal: Option<Journal>,
+    archive_dir: Option<ArchiveDir>,
+    settings: Settings,
+    /// Who is logged in; new transactions are recorded against them.
+    user: Option<String>,
+    /// Product versions as of the last load or save. Storage holding any other
+    /// version means another session has written since.
+    synced_versions: HashMap<Uuid, u64>,
+    /// The storage's revision as of the last load or save, if it keeps one.
+    /// While it is unchanged nobody else has written, so saving needs no check.
+    synced_revision: Option<String>,
+    /// What has changed since the last load or save.
+    changes: ChangeTracker,
+}
+
+/// What a store has changed since it was last loaded or saved.
+#[derive(Debug, Clone)]
+struct ChangeTracker {
+    /// The state was replaced wholesale, or has never been saved.
+    everything: bool,
+    products: HashSet<Uuid>,
+    /// Invoices, stored value, customers, coupons or loyalty changed.
+    documents: bool,
+    // Lengths and position as saved; transactions and events are only appended
+    // to between saves.
+    saved_transactions: usize,
+    saved_events: usize,
+    saved_journal_seq: u64,
+}
+
+impl Default for ChangeTracker {
+    fn default() -> Self {
+        ChangeTracker {
+            everything: true,
+            products: HashSet::new(),
+            documents: false,
+            saved_transactions: 0,
+            saved_events: 0,
+            saved_journal_seq: 0,
+        }
+    }
+}
+
+impl ChangeTracker {
+    fn track(&mut self, event: &Event) {
+        if let Event::SnapshotImported(_) = event {
+            self.everything = true;
+        } else if let Some(product_id) = event.product_id() {
+            self.products.insert(product_id);
+        } else {
+            self.documents = true;
+        }
+    }
+}
+
+/// What a store changed since its last save, for backends that can write just
+/// that. Products are listed as they are now; `transactions` and `events` are
+/// the ones appended since.
+pub struct StoreChanges<'a> {
+    pub products: Vec<&'a Product>,
+    pub deleted_products: Vec<Uuid>,
+    pub transactions: &'a [Transaction],
+    pub events: &'a [RecordedEvent],
+    pub journal_seq: u64,
+    /// The rest of the store, when any of it changed.
+    pub documents: Option<StoreDocuments<'a>>,
+}
+
+/// The parts of a store kept as whole documents rather than rows.
+pub struct StoreDocuments<'a> {
+    pub invoices: &'a [Invoice],
+    pub stored_value: &'a StoredValueBook,
+    pub customers: &'a HashMap<Uuid, Customer>,
+    pub coupons: &'a CouponBook,
+    pub loyalty: &'a LoyaltyProgram,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Store {
     pub fn new() -> Self {
         Store {
+            events: Vec::new(),
             products: HashMap::new(),
             transactions: Vec::new(),
+            index: TransactionIndex::default(),
+            invoices: Vec::new(),
+            stored_value: StoredValueBook::new(),
+            customers: HashMap::new(),
+            coupons: CouponBook::new(),
+            loyalty: LoyaltyProgram::new(),
+            summaries: Vec::new(),
+            archives: Vec::new(),
+            restore_points: Vec::new(),
+            undo: VecDeque::new(),
+            journal_seq: 0,
+            journal: None,
+            archive_dir: None,
+            settings: Settings::default(),
+            user: None,
+            synced_versions: HashMap::new(),
+            synced_revision: None,
+            changes: ChangeTracker::default(),
         }
     }
 
-    pub fn add_product(&mut self, product: Product) -> Result<(), StoreError> {
-        self.products.insert(product.id, product);
+    /// A store whose reports use the configured currency and store name.
+    pub fn with_settings(settings: Settings) -> Self {
+        Store { settings, ..Store::new() }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Records transactions from now on against `user`, or no one.
+    pub fn set_user(&mut self, user: Option<String>) {
+        self.user = user;
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Starts writing every change to `journal` before it is applied, after first
+    /// replaying whatever the journal holds beyond the current state. Returns the
+    /// number of batches replayed.
+    pub fn attach_journal(&mut self, mut journal: Journal) -> Result<usize, StoreError> {
+        journal.advance_to(self.journal_seq);
+        let mut replayed = 0;
+        for batch in journal.batches() {
+            if batch.seq <= self.journal_seq {
+                continue;
+            }
+            for event in &batch.events {
+                if event.seq > self.last_event_seq() {
+                    self.record(event.clone());
+                }
+            }
+            self.journal_seq = batch.seq;
+            replayed += 1;
+        }
+        self.journal = Some(journal);
+        Ok(replayed)
+    }
+
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Where `archive_transactions` writes archive files and where reports read
+    /// them back from.
+    pub fn attach_archives(&mut self, archives: ArchiveDir) {
+        self.archive_dir = Some(archives);
+    }
+
+    fn archive_dir(&self) -> Result<&ArchiveDir, StoreError> {
+        self.archive_dir.as_ref()
+            .ok_or_else(|| StoreError::InvalidInput("No archive directory is attached".to_string()))
+    }
+
+    /// Whether enough has been journaled that a checkpoint is worthwhile.
+    pub fn needs_checkpoint(&self) -> bool {
+        self.journal.as_ref().map(|j| j.needs_compaction()).unwrap_or(false)
+    }
+
+    /// Saves a full snapshot and then empties the journal it supersedes. If the
+    /// process dies in between, replay skips the batches the snapshot already covers.
+    pub fn checkpoint(&mut self, storage: &mut dyn Storage) -> Result<(), StoreError> {
+        self.save(storage)?;
+        if let Some(journal) = &mut self.journal {
+            journal.truncate()?;
+        }
+        Ok(())
+    }
+
+    // Every user action goes through here, and can be undone later if all its
+    // events can be reversed.
+    fn commit(&mut self, events: Vec<Event>) -> Result<(), StoreError> {
+        let first = self.last_event_seq() + 1;
+        self.append(events)?;
+        let recorded = self.events.iter().filter(|e| e.seq >= first);
+        if recorded.clone().next().is_none() || !recorded.clone().all(|e| undo::is_undoable(&e.event)) {
+            return Ok(());
+        }
+        let entry = UndoEntry {
+            first_seq: first,
+            last_seq: self.last_event_seq(),
+            user: self.user.clone(),
+            at: Utc::now(),
+            description: recorded.map(|e| e.event.describe(&self.settings.currency)).collect::<Vec<_>>().join("; "),
+        };
+        self.undo.push_back(entry);
+        if self.undo.len() > UNDO_LIMIT {
+            self.undo.pop_front();
+        }
         Ok(())
     }
 
+    // Every mutation goes through here so the journal and memory never disagree.
+    // It also links each new transaction to the one before it, and records who
+    // made it unless it came from another store already attributed.
+    fn append(&mut self, mut events: Vec<Event>) -> Result<(), StoreError> {
+        let mut head = self.chain_head();
+        for transaction in events.iter_mut().filter_map(Event::transaction_mut) {
+            if transaction.user.is_none() {
+                transaction.user = self.user.clone();
+            }
+            transaction.prev_hash = Some(head);
+            head = integrity::transaction_hash(transaction);
+        }
+        let timestamp = Utc::now();
+        let first = self.last_event_seq() + 1;
+        let events: Vec<RecordedEvent> = events.into_iter()
+            .enumerate()
+            .map(|(i, event)| RecordedEvent { seq: first + i as u64, timestamp, event })
+            .collect();
+        if let Some(journal) = &mut self.journal {
+            self.journal_seq = journal.append(events.clone())?;
+        }
+        for event in events {
+            self.record(event);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, event: RecordedEvent) {
+        self.apply(&event);
+        self.changes.track(&event.event);
+        self.events.push(event);
+    }
+
+    // Replaces the state wholesale; the event log is left as it is.
+    fn set_state(&mut self, data: StoreData) {
+        self.products = data.products;
+        self.index = TransactionIndex::build(&data.transactions);
+        self.transactions = data.transactions;
+        self.invoices = data.invoices;
+        self.stored_value = data.stored_value;
+        self.customers = data.customers;
+        self.coupons = data.coupons;
+        self.loyalty = data.loyalty;
+        self.summaries = data.summaries;
+        self.archives = data.archives;
+    }
+
+    fn apply(&mut self, recorded: &RecordedEvent) {
+        match &recorded.event {
+            Event::ProductCreated { id, name, description, price, sku, opening_stock } => {
+                self.products.insert(*id, Product {
+                    id: *id,
+                    name: name.clone(),
+                    description: description.clone(),
+                    price: *price,
+                    quantity: opening_stock.as_ref().map(|t| t.quantity).unwrap_or(0),
+                    version: 0,
+                    sku: sku.clone(),
+                    archived_at: None,
+                });
+                if let Some(transaction) = opening_stock {
+                    self.push_transaction(transaction.clone());
+                }
+            },
+            Event::ProductDetailsChanged { product_id, name, description, sku } => {
+                if let Some(product) = self.touch_product(product_id) {
+                    product.name = name.clone();
+                    product.description = description.clone();
+                    product.sku = sku.clone();
+                }
+            },
+            Event::PriceChanged { product_id, price } => {
+                if let Some(product) = self.touch_product(product_id) {
+                    product.price = *price;
+                }
+            },
+            Event::ProductArchived { product_id } => {
+                if let Some(product) = self.touch_product(product_id) {
+                    product.archived_at = Some(recorded.timestamp);
+                }
+            },
+            Event::ProductRestored { product_id } => {
+                if let Some(product) = self.touch_product(product_id) {
+                    product.archived_at = None;
+                }
+            },
+            Event::ProductDeleted { product_id } => {
+                self.products.remove(product_id);
+            },
+            Event::SaleRecorded(transaction) | Event::PurchaseRecorded(transaction) | Event::StockAdjusted(transaction) => {
+                if let Some(product) = self.touch_product(&transaction.product_id) {
+                    product.quantity += ledger::quantity_change(transaction);
+                }
+                self.push_transaction(transaction.clone());
+            },
+            Event::StockReconciled(transaction) => self.push_transaction(transaction.clone()),
+            Event::InvoiceIssued(invoice) => self.invoices.push(invoice.clone()),
+            Event::CustomerAdded(customer) => {
+                self.customers.insert(customer.id, customer.clone());
+            },
+            Event::StoredValueIssued { account, entry } => {
+                self.stored_value.apply_account(account.clone());
+                self.stored_value.apply_entry(entry.clone());
+            },
+            Event::StoredValueRedeemed(entry) | Event::StoredValueVoided(entry) => {
+                self.stored_value.apply_entry(entry.clone());
+            },
+            Event::CouponCreated(coupon) => self.coupons.apply_coupon(coupon.clone()),
+            Event::CouponCodeAdded(code) => self.coupons.apply_code(code.clone()),
+            Event::CouponRedeemed(redemption) => self.coupons.apply_redemption(redemption.clone()),
+            Event::LoyaltyConfigured(config) => self.loyalty.apply_config(config.clone()),
+            Event::LoyaltyPointsPosted(entry) => self.loyalty.apply_entry(entry.clone()),
+            Event::RestorePointCreated { name } => self.restore_points.push(RestorePoint {
+                name: name.clone(),
+                seq: recorded.seq,
+                created_at: recorded.timestamp,
+            }),
+            Event::SnapshotImported(data) => {
+                self.set_state((**data).clone());
+                // The log no longer reaches back past this
+                self.restore_points.clear();
+            },
+        }
+    }
+
+    fn push_transaction(&mut self, transaction: Transaction) {
+        self.index.insert(self.transactions.len(), &transaction);
+        self.transactions.push(transaction);
+    }
+
+    // Every event about a product makes a new version of it.
+    fn touch_product(&mut self, id: &Uuid) -> Option<&mut Product> {
+        let product = self.products.get_mut(id)?;
+        product.version += 1;
+        Some(product)
+    }
+
+    /// Every change ever made, oldest first.
+    pub fn events(&self) -> &[RecordedEvent] {
+        &self.events
+    }
+
+    /// The events about one product, oldest first.
+    pub fn product_events(&self, product_id: &Uuid) -> Vec<&RecordedEvent> {
+        self.events.iter().filter(|e| e.event.product_id() == Some(*product_id)).collect()
+    }
+
+    /// This session's actions by `user` that can still be undone, newest first.
+    pub fn undoable_actions(&self, user: Option<&str>) -> Vec<&UndoEntry> {
+        self.undo.iter().rev().filter(|entry| entry.user.as_deref() == user).collect()
+    }
+
+    /// Reverses the action starting at event `first_seq` with compensating
+    /// events, leaving what it recorded in the log. Later actions are kept.
+    pub fn undo(&mut self, first_seq: u64) -> Result<UndoEntry, StoreError> {
+        let position = self.undo.iter().position(|entry| entry.first_seq == first_seq)
+            .ok_or_else(|| StoreError::InvalidInput(format!("No action starting at #{} can be undone", first_seq)))?;
+        let entry = self.undo[position].clone();
+        undo::check_superseded(self, &entry)?;
+        let mut compensations = Vec::new();
+        for recorded in self.events.iter().rev().filter(|e| e.seq >= entry.first_seq && e.seq <= entry.last_seq) {
+            compensations.extend(undo::compensate(self, recorded)?);
+        }
+        undo::check_stock(self, &compensations)?;
+        self.append(compensations)?;
+        self.undo.remove(position);
+        Ok(entry)
+    }
+
+    /// Names the current point in the log so `restore_to` can wind back to it.
+    pub fn create_restore_point(&mut self, name: &str) -> Result<(), StoreError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(StoreError::InvalidInput("Restore point needs a name".to_string()));
+        }
+        if self.restore_points.iter().any(|p| p.name == name) {
+            return Err(StoreError::InvalidInput(format!("Restore point '{}' already exists", name)));
+        }
+        self.append(vec![Event::RestorePointCreated { name: name.to_string() }])
+    }
+
+    /// Restore points still in the log, oldest first.
+    pub fn restore_points(&self) -> &[RestorePoint] {
+        &self.restore_points
+    }
+
+    /// Reverses everything recorded since the restore point `name`, newest
+    /// first, with compensating events. Refuses, changing nothing, if any of it
+    /// cannot be reversed. Returns the number of events reversed.
+    pub fn restore_to(&mut self, name: &str) -> Result<usize, StoreError> {
+        let point = self.restore_points.iter().find(|p| p.name == name.trim())
+            .ok_or_else(|| StoreError::InvalidInput(format!("No restore point named '{}'", name.trim())))?
+            .clone();
+        let mut compensations = Vec::new();
+        let mut reversed = 0;
+        for recorded in self.events.iter().rev().take_while(|e| e.seq > point.seq) {
+            compensations.extend(undo::compensate(self, recorded)?);
+            reversed += 1;
+        }
+        undo::check_stock(self, &compensations)?;
+        self.append(compensations)?;
+        self.undo.retain(|entry| entry.first_seq <= point.seq);
+        Ok(reversed)
+    }
+
+    /// The hash the next transaction will point back to.
+    pub fn chain_head(&self) -> String {
+        integrity::chain_head(&self.transactions, self.archives.last().and_then(|a| a.head.as_deref()))
+    }
+
+    fn last_event_seq(&self) -> u64 {
+        self.events.last().map(|e| e.seq).unwrap_or(0)
+    }
+
+    /// Feeds the whole event log to `projection`.
+    pub fn project<P: Projection>(&self, projection: &mut P) {
+        for event in &self.events {
+            projection.apply(event);
+        }
+    }
+
+    /// Throws the current state away and derives it again from the event log.
+    pub fn rebuild(&mut self) {
+        let events = std::mem::take(&mut self.events);
+        let old = std::mem::take(self);
+        *self = Store {
+            journal_seq: old.journal_seq,
+            journal: old.journal,
+            archive_dir: old.archive_dir,
+            settings: old.settings,
+            user: old.user,
+            undo: old.undo,
+            synced_versions: old.synced_versions,
+            synced_revision: old.synced_revision,
+            ..Store::new()
+        };
+        for event in events {
+            self.record(event);
+        }
+    }
+
+    /// Moves every transaction older than `cutoff` out of the store into a new
+    /// file in the attached archive directory, leaving per-`period` summaries behind so totals and
+    /// stock levels still add up. The events that recorded them go into the file
+    /// too, and the log starts over from a snapshot of the state they produced.
+    /// Saves a checkpoint to `storage` afterwards. Returns `None` if nothing is
+    /// old enough to archive.
+    pub fn archive_transactions(
+        &mut self,
+        cutoff: DateTime<Utc>,
+        period: Period,
+        storage: &mut dyn Storage,
+    ) -> Result<Option<TransactionArchive>, StoreError> {
+        let holds_archivable = |event: &Event| match event {
+            Event::SnapshotImported(data) => data.transactions.iter().any(|t| t.timestamp < cutoff),
+            _ => event.transaction().is_some_and(|t| t.timestamp < cutoff),
+        };
+        let split = match self.events.iter().rposition(|e| holds_archivable(&e.event)) {
+            Some(index) => index + 1,
+            None => return Ok(None),
+        };
+
+        let mut base = Store::new();
+        for event in &self.events[..split] {
+            base.record(event.clone());
+        }
+        // Only a leading run, so what stays is still one unbroken chain
+        let keep_from = base.transactions.iter().position(|t| t.timestamp >= cutoff).unwrap_or(base.transactions.len());
+        let archived: Vec<Transaction> = base.transactions.drain(..keep_from).collect();
+        if archived.is_empty() {
+            return Ok(None);
+        }
+        base.summaries.extend(retention::summarize(&archived, period, &self.settings.timezone));
+
+        let last = &self.events[split - 1];
+        let from = archived.iter().map(|t| t.timestamp).min().unwrap_or(cutoff);
+        let archive = TransactionArchive {
+            file: format!("transactions-{}-{}-{}.json.gz", from.format("%Y%m%d"), cutoff.format("%Y%m%d"), last.seq),
+            from,
+            to: cutoff,
+            transactions: archived.len(),
+            events: split,
+            created_at: Utc::now(),
+            head: archived.last().map(integrity::transaction_hash),
+        };
+        self.archive_dir()?.write(&archive.file, &ArchiveContents { transactions: archived, events: self.events[..split].to_vec() })?;
+        base.archives.push(archive.clone());
+
+        let snapshot = RecordedEvent {
+            seq: last.seq,
+            timestamp: last.timestamp,
+            event: Event::SnapshotImported(Box::new(StoreData { events: Vec::new(), ..base.to_data() })),
+        };
+        let archived_seq = last.seq;
+        self.undo.retain(|entry| entry.first_seq > archived_seq);
+        let rest = self.events.split_off(split);
+        self.events = std::iter::once(snapshot).chain(rest).collect();
+        self.rebuild();
+        self.checkpoint(storage)?;
+        Ok(Some(archive))
+    }
+
+    /// The archive files written so far, oldest first.
+    pub fn archives(&self) -> &[TransactionArchive] {
+        &self.archives
+    }
+
+    /// What archived transactions add up to, per period, product and type.
+    pub fn summaries(&self) -> &[PeriodSummary] {
+        &self.summaries
+    }
+
+    /// Everything before this time has been archived.
+    pub fn archived_until(&self) -> Option<DateTime<Utc>> {
+        self.archives.iter().map(|a| a.to).max()
+    }
+
+    /// Reads back the archived transactions from `from` up to `to`, oldest first.
+    pub fn archived_transactions(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Transaction>, StoreError> {
+        let mut transactions = Vec::new();
+        for archive in self.archives.iter().filter(|a| a.from < to && a.to > from) {
+            transactions.extend(self.archive_dir()?.read(archive)?.transactions.into_iter()
+                .filter(|t| t.timestamp >= from && t.timestamp < to));
+        }
+        transactions.sort_by_key(|t| t.timestamp);
+        Ok(transactions)
+    }
+
+    pub fn generate_archived_report(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<String, StoreError> {
+        let transactions = self.archived_transactions(from, to)?;
+        let mut report = String::from("Archived Transactions\n=====================\n\n");
+        for transaction in &transactions {
+            report.push_str(&format!(
+                "Transaction ID: {}\nType: {:?}\nProduct: {}\nQuantity: {}\nPrice: {}\nDate: {}\n\n",
+                transaction.id, transaction.transaction_type, self.product_label(&transaction.product_id),
+                transaction.quantity, self.settings.format_money(transaction.price),
+                self.settings.format_time(transaction.timestamp)
+            ));
+        }
+        report.push_str(&format!("Total Transactions: {}\n", transactions.len()));
+        Ok(report)
+    }
+
+    /// The events after `since`, to merge into another store with `merge_changes`.
+    pub fn export_changes(&self, since: u64) -> Changeset {
+        Changeset {
+            origin: self.settings.store_name.clone(),
+            since,
+            exported_at: Utc::now(),
+            events: self.events.iter().filter(|e| e.seq > since).cloned().collect(),
+        }
+    }
+
+    /// Brings another store's changes into this one as a single change. Sales,
+    /// purchases and adjustments are added unless already here, so stock levels
+    /// end up reflecting both stores; see `sync::plan_merge` for how product
+    /// edits are reconciled.
+    pub fn merge_changes(&mut self, changeset: &Changeset) -> Result<MergeReport, StoreError> {
+        let (events, report) = sync::plan_merge(self, changeset);
+        if !events.is_empty() {
+            self.commit(events)?;
+        }
+        Ok(report)
+    }
+
+    /// Adds a product. Its starting quantity is recorded as an adjustment so the
+    /// ledger accounts for every unit.
+    pub fn add_product(&mut self, product: Product) -> Result<(), StoreError> {
+        if self.products.contains_key(&product.id) {
+            return Err(StoreError::InvalidInput(format!("Product {} already exists", product.id)));
+        }
+        self.check_sku(&product)?;
+        let events = self.product_changes(&product);
+        self.commit(events)
+    }
+
+    /// Looks a product up whether or not it is archived.
     pub fn get_product(&self, id: &Uuid) -> Option<&Product> {
         self.products.get(id)
     }
 
+    /// The catalog: every product that is not archived.
+    pub fn products(&self) -> impl Iterator<Item = &Product> {
+        self.products.values().filter(|p| p.archived_at.is_none())
+    }
+
+    pub fn archived_products(&self) -> impl Iterator<Item = &Product> {
+        self.products.values().filter(|p| p.archived_at.is_some())
+    }
+
+    /// How reports name a product: archived ones are marked as such and deleted
+    /// ones fall back to their ID.
+    pub fn product_label(&self, id: &Uuid) -> String {
+        match self.products.get(id) {
+            Some(product) if product.archived_at.is_some() => format!("{} (archived)", product.name),
+            Some(product) => product.name.clone(),
+            None => format!("(deleted product {})", id),
+        }
+    }
+
+    pub fn find_product_by_sku(&self, sku: &str) -> Option<&Product> {
+        self.products.values().find(|p| p.sku.as_deref() == Some(sku))
+    }
+
+    /// Saves an edited copy of a product as the events that turn the stored
+    /// product into it; a changed quantity becomes a stock adjustment. The copy
+    /// must carry the version it was read at; if the product has changed since,
+    /// the edit is refused.
     pub fn update_product(&mut self, product: Product) -> Result<(), StoreError> {
-        if !self.products.contains_key(&product.id) {
-            return Err(StoreError::NotFound);
+        self.check_version(&product)?;
+        self.check_sku(&product)?;
+        let events = self.product_changes(&product);
+        self.commit(events)
+    }
+
+    /// Adds or updates many products as one change, e.g. a catalog import. New
+    /// products are added as given; existing ones follow the rules of `update_product`.
+    pub fn save_products(&mut self, products: Vec<Product>) -> Result<(), StoreError> {
+        let mut skus: HashMap<&str, Uuid> = self.products.values()
+            .filter(|p| !products.iter().any(|n| n.id == p.id))
+            .filter_map(|p| p.sku.as_deref().map(|sku| (sku, p.id)))
+            .collect();
+        for product in &products {
+            if let Some(sku) = product.sku.as_deref() {
+                if sku.is_empty() {
+                    return Err(StoreError::InvalidInput("SKU cannot be empty".to_string()));
+                }
+                if skus.insert(sku, product.id).is_some_and(|id| id != product.id) {
+                    return Err(StoreError::InvalidInput(format!("SKU '{}' is already in use", sku)));
+                }
+            }
+        }
+
+        let mut events = Vec::with_capacity(products.len());
+        for product in &products {
+            if self.products.contains_key(&product.id) {
+                self.check_version(product)?;
+            }
+            events.extend(self.product_changes(product));
+        }
+        self.commit(events)
+    }
+
+    fn check_version(&self, product: &Product) -> Result<(), StoreError> {
+        let current = self.active_product(&product.id)?;
+        if product.version != current.version {
+            return Err(StoreError::Conflict(format!(
+                "{} was changed since it was read (version {}, now {})",
+                current.name, product.version, current.version
+            )));
         }
-        self.products.insert(product.id, product);
         Ok(())
     }
 
+    // The events that take the stored product, or nothing, to `product`.
+    fn product_changes(&self, product: &Product) -> Vec<Event> {
+        let before = match self.products.get(&product.id) {
+            Some(current) => current,
+            None => {
+                return vec![Event::ProductCreated {
+                    id: product.id,
+                    name: product.name.clone(),
+                    description: product.description.clone(),
+                    price: product.price,
+                    sku: product.sku.clone(),
+                    opening_stock: (product.quantity != 0).then(|| adjustment(product, product.quantity, Utc::now())),
+                }];
+            },
+        };
+
+        let mut events = Vec::new();
+
+        if (&before.name, &before.description, &before.sku) != (&product.name, &product.description, &product.sku) {
+            events.push(Event::ProductDetailsChanged {
+                product_id: product.id,
+                name: product.name.clone(),
+                description: product.description.clone(),
+                sku: product.sku.clone(),
+            });
+        }
+        if before.price != product.price {
+            events.push(Event::PriceChanged { product_id: product.id, price: product.price });
+        }
+        if before.quantity != product.quantity {
+            events.push(Event::StockAdjusted(adjustment(product, product.quantity - before.quantity, Utc::now())));
+        }
+        events
+    }
+
+    fn check_sku(&self, product: &Product) -> Result<(), StoreError> {
+        match product.sku.as_deref() {
+            Some("") => Err(StoreError::InvalidInput("SKU cannot be empty".to_string())),
+            Some(sku) if self.products.values().any(|p| p.id != product.id && p.sku.as_deref() == Some(sku)) => {
+                Err(StoreError::InvalidInput(format!("SKU '{}' is already in use", sku)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // A product that exists and can be sold, bought or edited.
+    fn active_product(&self, id: &Uuid) -> Result<&Product, StoreError> {
+        let product = self.products.get(id).ok_or(StoreError::NotFound)?;
+        if product.archived_at.is_some() {
+            return Err(StoreError::InvalidInput(format!("{} is archived; restore it first", product.name)));
+        }
+        Ok(product)
+    }
+
+    /// Takes a product out of the catalog. Its history is untouched and reports
+    /// keep naming it; `restore_product` brings it back.
+    pub fn archive_product(&mut self, id: &Uuid) -> Result<(), StoreError> {
+        self.active_product(id)?;
+        self.commit(vec![Event::ProductArchived { product_id: *id }])
+    }
+
+    pub fn restore_product(&mut self, id: &Uuid) -> Result<(), StoreError> {
+        let product = self.products.get(id).ok_or(StoreError::NotFound)?;
+        if product.archived_at.is_none() {
+            return Err(StoreError::InvalidInput(format!("{} is not archived", product.name)));
+        }
+        self.commit(vec![Event::ProductRestored { product_id: *id }])
+    }
+
+    /// Deletes a product that no transaction refers to. Products with history
+    /// should be archived instead, or purged if they really must go.
     pub fn delete_product(&mut self, id: &Uuid) -> Result<(), StoreError> {
-        self.products.remove(id).ok_or(StoreError::NotFound)?;
-        Ok(())
+        let product = self.products.get(id).ok_or(StoreError::NotFound)?;
+        // Archived transactions count too: their summaries still refer to it
+        let archived: usize = self.summaries.iter()
+            .filter(|s| s.product_id == *id)
+            .map(|s| s.count)
+            .sum();
+        let references = self.index.product(id).len() + archived;
+        if references > 0 {
+            return Err(StoreError::InvalidInput(format!(
+                "{} has {} transaction(s); archive it instead, or purge it to delete it anyway",
+                product.name, references
+            )));
+        }
+        self.commit(vec![Event::ProductDeleted { product_id: *id }])
     }
 
-    pub fn record_sale(&mut self, product_id: Uuid, quantity: i32) -> Result<Transaction, StoreError> {
-        let product = self.products.get_mut(&product_id)
-            .ok_or(StoreError::NotFound)?;
+    /// Deletes a product even though transactions refer to it. The transactions
+    /// are kept and reports show them against the product's ID.
+    pub fn purge_product(&mut self, id: &Uuid) -> Result<(), StoreError> {
+        if !self.products.contains_key(id) {
+            return Err(StoreError::NotFound);
+        }
+        self.commit(vec![Event::ProductDeleted { product_id: *id }])
+    }
 
+    pub fn record_sale(&mut self, product_id: Uuid, quantity: i32) -> Result<Transaction, StoreError> {
+        let product = self.active_product(&product_id)?;
         if product.quantity < quantity {
             return Err(StoreError::InsufficientInventory);
         }
 
-        product.quantity -= quantity;
         let transaction = Transaction {
             id: Uuid::new_v4(),
             product_id,
             quantity,
             price: product.price,
             transaction_type: TransactionType::Sale,
-            timestamp: chrono::Utc::now(),
+            timestamp: Utc::now(),
+            prev_hash: None,
+            user: None,
         };
 
-        self.transactions.push(transaction.clone());
-        Ok(transaction)
+        self.commit(vec![Event::stock(transaction)])?;
+        // As recorded, with its place in the chain and who made it
+        self.transactions.last().cloned().ok_or(StoreError::NotFound)
     }
 
     pub fn record_purchase(&mut self, product_id: Uuid, quantity: i32, price: f64) -> Result<Transaction, StoreError> {
-        let product = self.products.get_mut(&product_id)
-            .ok_or(StoreError::NotFound)?;
-
-        product.quantity += quantity;
+        self.active_product(&product_id)?;
         let transaction = Transaction {
             id: Uuid::new_v4(),
             product_id,
             quantity,
             price,
             transaction_type: TransactionType::Purchase,
-            timestamp: chrono::Utc::now(),
+            timestamp: Utc::now(),
+            prev_hash: None,
+            user: None,
         };
 
-        self.transactions.push(transaction.clone());
-        Ok(transaction)
+        self.commit(vec![Event::stock(transaction)])?;
+        // As recorded, with its place in the chain and who made it
+        self.transactions.last().cloned().ok_or(StoreError::NotFound)
+    }
+
+    /// Stock of every product as it stood at `at`, rebuilt from the ledger.
+    /// Archived periods count once they have ended, so for a time inside one use
+    /// `stock_as_of_archived`.
+    pub fn stock_as_of(&self, at: DateTime<Utc>) -> StockReport {
+        ledger::stock_as_of(&self.products, &self.transactions, &self.summaries, at)
+    }
+
+    /// Like `stock_as_of`, but reads the archive files back when `at` falls
+    /// before the end of what has been archived, so the figures are exact.
+    pub fn stock_as_of_archived(&self, at: DateTime<Utc>) -> Result<StockReport, StoreError> {
+        if self.archived_until().is_none_or(|until| at >= until) {
+            return Ok(self.stock_as_of(at));
+        }
+        let mut transactions = Vec::new();
+        for archive in &self.archives {
+            transactions.extend(self.archive_dir()?.read(archive)?.transactions);
+        }
+        transactions.extend(self.transactions.iter().cloned());
+        Ok(ledger::stock_as_of(&self.products, &transactions, &[], at))
+    }
+
+    /// Products whose recorded quantity differs from what the ledger adds up to.
+    pub fn check_inventory(&self) -> Vec<Drift> {
+        ledger::find_drift(&self.products, &self.transactions, &self.summaries)
+    }
+
+    /// Records an adjustment for each drifting product so the ledger agrees with
+    /// the quantity on hand from now on. Returns the adjustments made.
+    pub fn reconcile_inventory(&mut self) -> Result<Vec<Transaction>, StoreError> {
+        let now = Utc::now();
+        let adjustments: Vec<Transaction> = self.check_inventory().iter()
+            .filter_map(|drift| self.products.get(&drift.product_id).map(|p| adjustment(p, drift.difference(), now)))
+            .collect();
+        self.commit(adjustments.iter().cloned().map(Event::StockReconciled).collect())?;
+        Ok(adjustments)
+    }
+
+    /// Prices a checkout without committing anything, e.g. to show the amount due
+    /// before taking payment. The returned invoice carries the next invoice number.
+    pub fn draft_invoice(&self, checkout: &Checkout) -> Result<Invoice, StoreError> {
+        if checkout.sale_ids.is_empty() {
+            return Err(StoreError::InvalidInput("Invoice needs at least one sale".to_string()));
+        }
+        if checkout.tax_rate < 0.0 {
+            return Err(StoreError::InvalidInput("Tax rate cannot be negative".to_string()));
+        }
+        if let Some(customer_id) = checkout.customer_id {
+            if !self.customers.contains_key(&customer_id) {
+                return Err(StoreError::NotFound);
+            }
+        }
+
+        let mut lines = Vec::new();
+        for sale_id in &checkout.sale_ids {
+            let transaction = self.transactions.iter()
+                .find(|t| t.id == *sale_id && t.transaction_type == TransactionType::Sale)
+                .ok_or(StoreError::NotFound)?;
+            if transaction.quantity <= 0 {
+                return Err(StoreError::InvalidInput(format!("Sale {} has no quantity to invoice", sale_id)));
+            }
+
+            if lines.iter().any(|l: &InvoiceLine| l.transaction_id == *sale_id)
+                || self.find_invoice_for_sale(sale_id).is_some() {
+                return Err(StoreError::InvalidInput(format!("Sale {} is already invoiced", sale_id)));
+            }
+
+            let description = self.products.get(&transaction.product_id)
+                .map(|p| p.name.clone())
+                .unwrap_or_else(|| transaction.product_id.to_string());
+
+            lines.push(InvoiceLine {
+                transaction_id: transaction.id,
+                product_id: transaction.product_id,
+                description,
+                quantity: transaction.quantity,
+                unit_price: transaction.price,
+                total: round_cents(transaction.price * transaction.quantity as f64),
+            });
+        }
+
+        let now = Utc::now();
+        let subtotal = round_cents(lines.iter().map(|l| l.total).sum());
+        let coupon_code = checkout.coupon_code.as_deref().map(coupon::normalize);
+        let discount = match &coupon_code {
+            Some(code) => self.coupons.evaluate(code, &lines, checkout.customer_id, now, &self.settings.currency)?,
+            None => 0.0,
+        };
+
+        // Only as many points are redeemed as the bill needs, the rest stay on the account
+        let (points_redeemed, points_discount) = if checkout.redeem_points > 0 {
+            let customer_id = checkout.customer_id.ok_or_else(|| {
+                StoreError::InvalidInput("Redeeming points requires a customer".to_string())
+            })?;
+            self.loyalty.check_redeemable(&customer_id, checkout.redeem_points, now)?;
+            let remaining = round_cents(subtotal - discount);
+            let points = checkout.redeem_points.min(self.loyalty.points_to_cover(remaining));
+            (points, self.loyalty.value_of(points).min(remaining))
+        } else {
+            (0, 0.0)
+        };
+
+        let taxable = round_cents(subtotal - discount - points_discount);
+        let tax = round_cents(taxable * checkout.tax_rate);
+
+        let mut invoice = Invoice {
+            number: self.invoices.last().map(|i| i.number + 1).unwrap_or(1),
+            lines,
+            subtotal,
+            discount,
+            coupon_code,
+            customer_id: checkout.customer_id,
+            points_redeemed,
+            points_discount,
+            points_earned: 0,
+            tax_rate: checkout.tax_rate,
+            tax,
+            total: round_cents(taxable + tax),
+            payments: checkout.payments.clone(),
+            issued_at: now,
+        };
+        if invoice.customer_id.is_some() {
+            invoice.points_earned = self.loyalty.points_for(&invoice);
+        }
+        Ok(invoice)
+    }
+
+    /// Bills already recorded sales. Invoice numbers are only assigned once every
+    /// check has passed, so the sequence never has gaps.
+    pub fn issue_invoice(&mut self, checkout: Checkout) -> Result<Invoice, StoreError> {
+        let invoice = self.draft_invoice(&checkout)?;
+        let total = invoice.total;
+
+        let paid: f64 = checkout.payments.iter().map(|p| p.amount).sum();
+        if checkout.payments.iter().any(|p| p.amount <= 0.0) {
+            return Err(StoreError::InvalidInput("Payment amounts must be positive".to_string()));
+        }
+        if round_cents(paid) < total {
+            return Err(StoreError::InvalidInput(format!(
+                "Payment of {} does not cover total of {}",
+                self.settings.format_money(paid), self.settings.format_money(total)
+            )));
+        }
+
+        // Only cash can be given back as change
+        let non_cash: f64 = checkout.payments.iter()
+            .filter(|p| p.method != PaymentMethod::Cash)
+            .map(|p| p.amount)
+            .sum();
+        if round_cents(non_cash) > total {
+            return Err(StoreError::InvalidInput("Card and stored value payments cannot exceed the total".to_string()));
+        }
+
+        let mut redemptions: HashMap<String, f64> = HashMap::new();
+        for payment in &checkout.payments {
+            if let PaymentMethod::StoredValue(code) = &payment.method {
+                *redemptions.entry(giftcard::normalize(code)).or_default() += payment.amount;
+            }
+        }
+
+        let mut events = Vec::new();
+        for (code, amount) in redemptions {
+            events.push(Event::StoredValueRedeemed(self.stored_value.prepare_redeem(&code, amount, Some(invoice.number), &self.settings.currency)?));
+        }
+        if let Some(code) = &invoice.coupon_code {
+            let coupon_id = self.coupons.get_code(code).map(|c| c.coupon_id).ok_or(StoreError::NotFound)?;
+            events.push(Event::CouponRedeemed(CouponRedemption {
+                code: code.clone(),
+                coupon_id,
+                invoice_number: invoice.number,
+                sale_ids: checkout.sale_ids.clone(),
+                customer_id: checkout.customer_id,
+                discount: invoice.discount,
+                timestamp: invoice.issued_at,
+            }));
+        }
+
+        if let Some(customer_id) = invoice.customer_id {
+            if invoice.points_redeemed > 0 {
+                let entry = self.loyalty.prepare_redeem(customer_id, invoice.points_redeemed, Some(invoice.number), invoice.issued_at)?;
+                events.push(Event::LoyaltyPointsPosted(entry));
+            }
+            if let Some(entry) = self.loyalty.prepare_earn(customer_id, invoice.points_earned, Some(invoice.number), invoice.issued_at) {
+                events.push(Event::LoyaltyPointsPosted(entry));
+            }
+        }
+
+        events.push(Event::InvoiceIssued(invoice.clone()));
+        self.commit(events)?;
+        Ok(invoice)
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    fn indexed(&self, positions: &[usize]) -> Vec<&Transaction> {
+        positions.iter().map(|&i| &self.transactions[i]).collect()
+    }
+
+    /// One product's transactions, in ledger order.
+    pub fn product_transactions(&self, product_id: &Uuid) -> Vec<&Transaction> {
+        self.indexed(self.index.product(product_id))
+    }
+
+    pub fn transactions_of_type(&self, kind: &TransactionType) -> Vec<&Transaction> {
+        self.indexed(self.index.of_type(kind))
+    }
+
+    /// Transactions timestamped in `[from, to)`, in ledger order.
+    pub fn transactions_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<&Transaction> {
+        self.indexed(&self.index.between(&self.transactions, from, to))
+    }
+
+    /// Transactions recorded while `user` was logged in, in ledger order.
+    pub fn user_transactions(&self, user: &str) -> Vec<&Transaction> {
+        self.indexed(self.index.user(user))
+    }
+
+    pub fn get_invoice(&self, number: u64) -> Option<&Invoice> {
+        self.invoices.iter().find(|i| i.number == number)
+    }
+
+    pub fn find_invoice_for_sale(&self, sale_id: &Uuid) -> Option<&Invoice> {
+        self.invoices.iter()
+            .find(|i| i.lines.iter().any(|l| l.transaction_id == *sale_id))
+    }
+
+    pub fn invoices(&self) -> &[Invoice] {
+        &self.invoices
+    }
+
+    pub fn stored_value(&self) -> &StoredValueBook {
+        &self.stored_value
+    }
+
+    pub fn issue_stored_value(&mut self, kind: StoredValueKind, amount: f64) -> Result<StoredValueAccount, StoreError> {
+        let (account, entry) = self.stored_value.prepare_issue(kind, amount)?;
+        self.commit(vec![Event::StoredValueIssued { account: account.clone(), entry }])?;
+        Ok(account)
+    }
+
+    pub fn void_stored_value(&mut self, code: &str) -> Result<StoredValueEntry, StoreError> {
+        let entry = self.stored_value.prepare_void(code)?;
+        self.commit(vec![Event::StoredValueVoided(entry.clone())])?;
+        Ok(entry)
+    }
+
+    pub fn coupons(&self) -> &CouponBook {
+        &self.coupons
+    }
+
+    pub fn create_coupon(&mut self, coupon: Coupon) -> Result<(), StoreError> {
+        self.coupons.validate(&coupon)?;
+        self.commit(vec![Event::CouponCreated(coupon)])
+    }
+
+    /// Adds a memorable code such as `SUMMER10` for a coupon.
+    pub fn add_coupon_code(&mut self, coupon_id: Uuid, code: &str, max_uses: Option<u32>) -> Result<CouponCode, StoreError> {
+        let code = self.coupons.prepare_code(coupon_id, code, max_uses)?;
+        self.commit(vec![Event::CouponCodeAdded(code.clone())])?;
+        Ok(code)
+    }
+
+    pub fn generate_coupon_codes(&mut self, coupon_id: Uuid, count: usize, max_uses: Option<u32>) -> Result<Vec<String>, StoreError> {
+        let codes = self.coupons.prepare_codes(coupon_id, count, max_uses)?;
+        let generated = codes.iter().map(|c| c.code.clone()).collect();
+        self.commit(codes.into_iter().map(Event::CouponCodeAdded).collect())?;
+        Ok(generated)
+    }
+
+    pub fn loyalty(&self) -> &LoyaltyProgram {
+        &self.loyalty
+    }
+
+    pub fn set_loyalty_config(&mut self, config: LoyaltyConfig) -> Result<(), StoreError> {
+        self.loyalty.validate_config(&config)?;
+        self.commit(vec![Event::LoyaltyConfigured(config)])
+    }
+
+    /// Writes off every lot of points that has lapsed by `now`, returning the new ledger entries.
+    pub fn expire_loyalty_points(&mut self, now: DateTime<Utc>) -> Result<Vec<LoyaltyEntry>, StoreError> {
+        let expired = self.loyalty.prepare_expiry(now);
+        if !expired.is_empty() {
+            self.commit(expired.iter().cloned().map(Event::LoyaltyPointsPosted).collect())?;
+        }
+        Ok(expired)
+    }
+
+    pub fn add_customer(&mut self, customer: Customer) -> Result<(), StoreError> {
+        if customer.name.trim().is_empty() {
+            return Err(StoreError::InvalidInput("Customer name cannot be empty".to_string()));
+        }
+        self.commit(vec![Event::CustomerAdded(customer)])
+    }
+
+    pub fn get_customer(&self, id: &Uuid) -> Option<&Customer> {
+        self.customers.get(id)
+    }
+
+    pub fn customers(&self) -> impl Iterator<Item = &Customer> {
+        self.customers.values()
+    }
+
+    pub fn shift_summary(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> ShiftSummary {
+        let sales: Vec<&Transaction> = self.transactions_between(from, to).into_iter()
+            .filter(|t| t.transaction_type == TransactionType::Sale)
+            .collect();
+        let invoices: Vec<&Invoice> = self.invoices.iter()
+            .filter(|i| i.issued_at >= from && i.issued_at < to)
+            .collect();
+
+        let mut payments: Vec<(String, f64)> = Vec::new();
+        for invoice in &invoices {
+            // Change handed back is not takings, so net it off the payments
+            let mut change = invoice.change_due();
+            for payment in &invoice.payments {
+                let mut amount = payment.amount;
+                if change > 0.0 && payment.method == PaymentMethod::Cash {
+                    let returned = change.min(amount);
+                    amount -= returned;
+                    change -= returned;
+                }
+                let category = payment.method.category();
+                match payments.iter_mut().find(|(c, _)| c == category) {
+                    Some((_, total)) => *total += amount,
+                    None => payments.push((category.to_string(), amount)),
+                }
+            }
+        }
+
+        ShiftSummary {
+            from,
+            to,
+            sales_count: sales.len(),
+            items_sold: sales.iter().map(|t| t.quantity).sum(),
+            gross_sales: round_cents(sales.iter().map(|t| t.price * t.quantity as f64).sum()),
+            invoices_issued: invoices.len(),
+            tax_collected: round_cents(invoices.iter().map(|i| i.tax).sum()),
+            payments: payments.into_iter().map(|(m, a)| (m, round_cents(a))).collect(),
+        }
     }
 
-    pub fn save_to_file(&self, path: &str) -> Result<(), StoreError> {
-        let file = File::create(path).map_err(|e| StoreError::DatabaseError(e.to_string()))?;
-        to_writer(file, &StoreData {
+    /// Snapshot of everything that gets persisted.
+    pub fn to_data(&self) -> StoreData {
+        StoreData {
+            schema_version: SCHEMA_VERSION,
             products: self.products.clone(),
             transactions: self.transactions.clone(),
-        }).map_err(|e| StoreError::DatabaseError(e.to_string()))
+            invoices: self.invoices.clone(),
+            stored_value: self.stored_value.clone(),
+            customers: self.customers.clone(),
+            coupons: self.coupons.clone(),
+            loyalty: self.loyalty.clone(),
+            journal_seq: self.journal_seq,
+            summaries: self.summaries.clone(),
+            archives: self.archives.clone(),
+            events: self.events.clone(),
+            signature: None,
+            checksummed: false,
+        }
     }
 
-    pub fn load_from_file(&mut self, path: &str) -> Result<(), StoreError> {
-        match File::open(path) {
-            Ok(file) => {
-                match from_reader(file) {
-                    Ok(data) => {
-                        let store_data: StoreData = data;
-                        self.products = store_data.products;
-                        self.transactions = store_data.transactions;
-                        Ok(())
-                    },
-                    Err(_) => {
-                        // Initialize new store file if corrupted
-                        self.save_to_file(path)
-                    }
+    /// The store as a `StoreView`, for writing it out without copying it.
+    pub fn view(&self) -> StoreView<'_> {
+        StoreView {
+            schema_version: SCHEMA_VERSION,
+            products: &self.products,
+            transactions: &self.transactions,
+            invoices: &self.invoices,
+            stored_value: &self.stored_value,
+            customers: &self.customers,
+            coupons: &self.coupons,
+            loyalty: &self.loyalty,
+            journal_seq: self.journal_seq,
+            summaries: &self.summaries,
+            archives: &self.archives,
+            events: &self.events,
+            signature: None,
+        }
+    }
+
+    /// Rebuilds a store from its event log. The saved state alongside the log
+    /// is only a cache for other tools and is not trusted, unless a checksum
+    /// vouches for it (`StoreData::checksummed`). Data saved before events were
+    /// recorded becomes a single `SnapshotImported` event.
+    pub fn from_data(mut data: StoreData) -> Self {
+        let mut store = Store { journal_seq: data.journal_seq, ..Store::new() };
+        let events = std::mem::take(&mut data.events);
+        if data.checksummed && !events.is_empty() {
+            for recorded in &events {
+                match &recorded.event {
+                    Event::RestorePointCreated { name } => store.restore_points.push(RestorePoint {
+                        name: name.clone(),
+                        seq: recorded.seq,
+                        created_at: recorded.timestamp,
+                    }),
+                    Event::SnapshotImported(_) => store.restore_points.clear(),
+                    _ => {},
                 }
-            },
-            Err(_) => {
-                // Create new store file if it doesn't exist
-                self.save_to_file(path)
             }
+            store.set_state(data);
+            store.events = events;
+            store.mark_saved();
+        } else if !events.is_empty() {
+            for event in events {
+                store.record(event);
+            }
+            store.mark_saved();
+        } else if !data.is_empty() {
+            store.record(RecordedEvent { seq: 1, timestamp: Utc::now(), event: Event::SnapshotImported(Box::new(data)) });
+        }
+        store.synced_versions = store.products.values().map(|p| (p.id, p.version)).collect();
+        store
+    }
+
+    /// Whether anything has changed since the last load or save.
+    pub fn is_dirty(&self) -> bool {
+        let changes = &self.changes;
+        changes.everything || changes.documents || !changes.products.is_empty()
+            || self.transactions.len() != changes.saved_transactions
+            || self.events.len() != changes.saved_events
+            || self.journal_seq != changes.saved_journal_seq
+    }
+
+    /// What has changed since the last load or save, or `None` if the whole
+    /// store has to be written.
+    pub fn changes(&self) -> Option<StoreChanges<'_>> {
+        let changes = &self.changes;
+        if changes.everything {
+            return None;
         }
+        let documents = changes.documents.then(|| StoreDocuments {
+            invoices: &self.invoices,
+            stored_value: &self.stored_value,
+            customers: &self.customers,
+            coupons: &self.coupons,
+            loyalty: &self.loyalty,
+        });
+        Some(StoreChanges {
+            products: changes.products.iter().filter_map(|id| self.products.get(id)).collect(),
+            deleted_products: changes.products.iter().filter(|id| !self.products.contains_key(id)).copied().collect(),
+            transactions: self.transactions.get(changes.saved_transactions..).unwrap_or_default(),
+            events: self.events.get(changes.saved_events..).unwrap_or_default(),
+            journal_seq: self.journal_seq,
+            documents,
+        })
+    }
+
+    fn mark_saved(&mut self) {
+        self.changes = ChangeTracker {
+            everything: false,
+            products: HashSet::new(),
+            documents: false,
+            saved_transactions: self.transactions.len(),
+            saved_events: self.events.len(),
+            saved_journal_seq: self.journal_seq,
+        };
+    }
+
+    /// Writes what changed since the last load or save, and nothing at all if
+    /// nothing did. Changes are tracked against the storage the store came from,
+    /// so use `save_all` to write to another one. Refuses with
+    /// `StoreError::Conflict` if another session has changed products in
+    /// `storage` since this store last synced with it, since saving would
+    /// silently throw their changes away.
+    pub fn save(&mut self, storage: &mut dyn Storage) -> Result<(), StoreError> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        let revision = storage.revision()?;
+        if revision.is_none() || revision != self.synced_revision {
+            self.check_conflicts(&storage.product_versions()?)?;
+        }
+        match self.changes() {
+            Some(changes) => {
+                storage.save_changes(self, &changes)?;
+                let saved: Vec<(Uuid, u64)> = changes.products.iter().map(|p| (p.id, p.version)).collect();
+                for id in changes.deleted_products {
+                    self.synced_versions.remove(&id);
+                }
+                self.synced_versions.extend(saved);
+            },
+            None => {
+                storage.save_store(&self.view())?;
+                self.synced_versions = self.products.values().map(|p| (p.id, p.version)).collect();
+            },
+        }
+        self.synced_revision = storage.revision()?;
+        self.mark_saved();
+        Ok(())
+    }
+
+    /// Hands a sale or purchase just recorded to `storage` to write on its own,
+    /// for backends that can write a single row without a full save. The
+    /// product's new version is then known to be in storage, so the next save
+    /// does not mistake it for another session's change.
+    pub fn write_through(&mut self, storage: &mut dyn Storage, transaction_id: &Uuid) -> Result<(), StoreError> {
+        let event = self.events.iter().rev()
+            .find(|e| e.event.transaction().is_some_and(|t| t.id == *transaction_id))
+            .ok_or(StoreError::NotFound)?;
+        let transaction = event.event.transaction().ok_or(StoreError::NotFound)?;
+        let product = self.products.get(&transaction.product_id).ok_or(StoreError::NotFound)?;
+        storage.record_transaction(event, product)?;
+        self.synced_versions.insert(product.id, product.version);
+        Ok(())
+    }
+
+    /// Writes a full snapshot whether or not anything changed.
+    pub fn save_all(&mut self, storage: &mut dyn Storage) -> Result<(), StoreError> {
+        self.changes.everything = true;
+        self.save(storage)
+    }
+
+    fn check_conflicts(&self, saved: &HashMap<Uuid, u64>) -> Result<(), StoreError> {
+        let mut changed: Vec<String> = saved.iter()
+            .filter(|(id, version)| self.synced_versions.get(id) != Some(version))
+            .map(|(id, _)| self.products.get(id).map(|p| p.name.clone()).unwrap_or_else(|| id.to_string()))
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        changed.sort_unstable();
+        Err(StoreError::Conflict(format!(
+            "another session changed {} product(s) since this store was loaded ({}); reload before saving",
+            changed.len(), changed.join(", ")
+        )))
+    }
+
+    /// Replaces the in-memory state with what `storage` holds. Returns `false`
+    /// if the backend has nothing saved yet, leaving the store untouched. An
+    /// attached journal is replayed on top of the loaded snapshot. Nothing done
+    /// before the load can be undone after it.
+    pub fn load(&mut self, storage: &dyn Storage) -> Result<bool, StoreError> {
+        // Taken first, so a save landing during the load shows up as a change
+        let revision = storage.revision()?;
+        match storage.load_store()? {
+            Some(data) => {
+                let journal = self.journal.take();
+                let settings = std::mem::take(&mut self.settings);
+                let user = self.user.take();
+                let archive_dir = self.archive_dir.take();
+                // The undo stack is dropped with the log its entries point into
+                *self = Store { settings, user, archive_dir, synced_revision: revision, ..Store::from_data(data) };
+                if let Some(journal) = journal {
+                    self.attach_journal(journal)?;
+                }
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    pub fn save_to_file(&mut self, path: &str) -> Result<(), StoreError> {
+        self.save(&mut JsonStorage::store_only(path))
+    }
+
+    pub fn load_from_file(&mut self, path: &str) -> Result<(), StoreError> {
+        let mut storage = JsonStorage::store_only(path);
+        // A corrupted file is quarantined and reported, never overwritten
+        if !self.load(&storage)? {
+            self.save(&mut storage)?;
+        }
+        Ok(())
     }
 
     pub fn generate_inventory_report(&self) -> String {
         let mut report = String::from("Inventory Report\n================\n\n");
-        for product in self.products.values() {
-            report.push_str(&format!("Product: {}\nQuantity: {}\nPrice: ${:.2}\n\n",
-                product.name, product.quantity, product.price));
+        for product in self.products() {
+            report.push_str(&format!("Product: {}\nQuantity: {}\nPrice: {}\n\n",
+                product.name, product.quantity, self.settings.format_money(product.price)));
         }
         report
     }
 
     pub fn generate_sales_report(&self) -> String {
         let mut report = String::from("Sales Report\n============\n\n");
-        let mut total_sales = 0.0;
-
-        for transaction in &self.transactions {
-            if matches!(transaction.transaction_type, TransactionType::Sale) {
-                let total = transaction.price * transaction.quantity as f64;
-                total_sales += total;
-                report.push_str(&format!(
-                    "Sale ID: {}\nProduct ID: {}\nQuantity: {}\nPrice: ${:.2}\nTotal: ${:.2}\n\n",
-                    transaction.id, transaction.product_id, transaction.quantity,
-                    transaction.price, total
-
-                ));
-            }
+        let (archived, mut total_sales) = retention::period_totals(&self.summaries, &TransactionType::Sale,
+            &self.settings.timezone, &self.settings.currency);
+        report.push_str(&archived);
+
+        for transaction in self.transactions_of_type(&TransactionType::Sale) {
+            let total = transaction.price * transaction.quantity as f64;
+            total_sales += total;
+            report.push_str(&format!(
+                "Sale ID: {}\nProduct: {}\nProduct ID: {}\nQuantity: {}\nPrice: {}\nTotal: {}\n\n",
+                transaction.id, self.product_label(&transaction.product_id), transaction.product_id, transaction.quantity,
+                self.settings.format_money(transaction.price), self.settings.format_money(total)
+            ));
         }
 
-        report.push_str(&format!("Total Sales: ${:.2}\n", total_sales));
+        report.push_str(&format!("Total Sales: {}\n", self.settings.format_money(total_sales)));
+        report
+    }
+
+    /// The latest `limit` events, newest first.
+    pub fn generate_audit_report(&self, limit: usize) -> String {
+        let mut report = String::from("Audit Trail\n===========\n\n");
+        for recorded in self.events.iter().rev().take(limit) {
+            report.push_str(&format!("#{} {} {}\n", recorded.seq, self.settings.format_time(recorded.timestamp),
+                recorded.event.describe(&self.settings.currency)));
+        }
+        report.push_str(&format!("\nTotal Events: {}\n", self.events.len()));
         report
     }
 
     pub fn generate_purchase_report(&self) -> String {
         let mut report = String::from("Purchase Report\n===============\n\n");
-        let mut total_cost = 0.0;
-
-        for transaction in &self.transactions {
-            if matches!(transaction.transaction_type, TransactionType::Purchase) {
-                let total = transaction.price * transaction.quantity as f64;
-                total_cost += total;
-                report.push_str(&format!(
-                    "Purchase ID: {}\nProduct ID: {}\nQuantity: {}\nCost: ${:.2}\nTotal: ${:.2}\n\n",
-                    transaction.id, transaction.product_id, transaction.quantity,
-                    transaction.price, total
-                ));
-            }
+        let (archived, mut total_cost) = retention::period_totals(&self.summaries, &TransactionType::Purchase,
+            &self.settings.timezone, &self.settings.currency);
+        report.push_str(&archived);
+
+        for transaction in self.transactions_of_type(&TransactionType::Purchase) {
+            let total = transaction.price * transaction.quantity as f64;
+            total_cost += total;
+            report.push_str(&format!(
+                "Purchase ID: {}\nProduct: {}\nProduct ID: {}\nQuantity: {}\nCost: {}\nTotal: {}\n\n",
+                transaction.id, self.product_label(&transaction.product_id), transaction.product_id, transaction.quantity,
+                self.settings.format_money(transaction.price), self.settings.format_money(total)
+            ));
         }
 
-        report.push_str(&format!("Total Purchases: ${:.2}\n", total_cost));
+        report.push_str(&format!("Total Purchases: {}\n", self.settings.format_money(total_cost)));
         report
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct StoreData {
-    products: HashMap<Uuid, Product>,
-    transactions: Vec<Transaction>,
+fn adjustment(product: &Product, quantity: i32, timestamp: DateTime<Utc>) -> Transaction {
+    Transaction {
+        id: Uuid::new_v4(),
+        product_id: product.id,
+        quantity,
+        price: product.price,
+        transaction_type: TransactionType::Adjustment,
+        timestamp,
+        prev_hash: None,
+        user: None,
+    }
+}
+
+/// The persisted form of a `Store`, as read and written by `Storage` backends.
+/// Older JSON files are upgraded by `migrations` before they reach this type.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct StoreData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub products: HashMap<Uuid, Product>,
+    pub transactions: Vec<Transaction>,
+    #[serde(default)]
+    pub invoices: Vec<Invoice>,
+    #[serde(default)]
+    pub stored_value: StoredValueBook,
+    #[serde(default)]
+    pub customers: HashMap<Uuid, Customer>,
+    #[serde(default)]
+    pub coupons: CouponBook,
+    #[serde(default)]
+    pub loyalty: LoyaltyProgram,
+    #[serde(default)]
+    pub journal_seq: u64,
+    /// Totals of the transactions moved out to `archives`.
+    #[serde(default)]
+    pub summaries: Vec<PeriodSummary>,
+    #[serde(default)]
+    pub archives: Vec<TransactionArchive>,
+    /// Every change in order. The sections above are the state these produce.
+    #[serde(default)]
+    pub events: Vec<RecordedEvent>,
+    /// Added by storage that signs what it saves; see `integrity`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SnapshotSignature>,
+    /// Set when the data was read from a binary snapshot whose checksum
+    /// matched, so the state above can be used as it is instead of being
+    /// rebuilt from `events`. Never saved.
+    #[serde(skip)]
+    pub checksummed: bool,
+}
+
+/// `StoreData` borrowed rather than owned, so a store can be written out without
+/// being copied first. It serializes exactly as `StoreData` does.
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreView<'a> {
+    pub schema_version: u32,
+    pub products: &'a HashMap<Uuid, Product>,
+    pub transactions: &'a [Transaction],
+    pub invoices: &'a [Invoice],
+    pub stored_value: &'a StoredValueBook,
+    pub customers: &'a HashMap<Uuid, Customer>,
+    pub coupons: &'a CouponBook,
+    pub loyalty: &'a LoyaltyProgram,
+    pub journal_seq: u64,
+    pub summaries: &'a [PeriodSummary],
+    pub archives: &'a [TransactionArchive],
+    pub events: &'a [RecordedEvent],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SnapshotSignature>,
+}
+
+impl StoreView<'_> {
+    pub fn to_data(&self) -> StoreData {
+        StoreData {
+            schema_version: self.schema_version,
+            products: self.products.clone(),
+            transactions: self.transactions.to_vec(),
+            invoices: self.invoices.to_vec(),
+            stored_value: self.stored_value.clone(),
+            customers: self.customers.clone(),
+            coupons: self.coupons.clone(),
+            loyalty: self.loyalty.clone(),
+            journal_seq: self.journal_seq,
+            summaries: self.summaries.to_vec(),
+            archives: self.archives.to_vec(),
+            events: self.events.to_vec(),
+            signature: self.signature.clone(),
+            checksummed: false,
+        }
+    }
+}
+
+impl StoreData {
+    pub fn view(&self) -> StoreView<'_> {
+        StoreView {
+            schema_version: self.schema_version,
+            products: &self.products,
+            transactions: &self.transactions,
+            invoices: &self.invoices,
+            stored_value: &self.stored_value,
+            customers: &self.customers,
+            coupons: &self.coupons,
+            loyalty: &self.loyalty,
+            journal_seq: self.journal_seq,
+            summaries: &self.summaries,
+            archives: &self.archives,
+            events: &self.events,
+            signature: self.signature.clone(),
+        }
+    }
+
+    /// Whether there is no state at all, as in a store that was never used.
+    pub fn is_empty(&self) -> bool {
+        self.products.is_empty() && self.transactions.is_empty() && self.invoices.is_empty()
+            && self.stored_value.ledger().is_empty() && self.customers.is_empty()
+            && self.coupons.coupons().next().is_none() && self.loyalty.ledger().is_empty()
+            && self.summaries.is_empty() && self.archives.is_empty() && self.events.is_empty()
+    }
+}
+
+pub(crate) fn round_cents(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
 }
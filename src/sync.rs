@@ -0,0 +1,377 @@
+use crate::errors::StoreError;
+use crate::events::{Event, RecordedEvent};
+use crate::ledger;
+use crate::models::Product;
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use uuid::Uuid;
+
+/// Events exported from one store to be merged into another, e.g. from a laptop
+/// that was offline all day. Merging is idempotent, so a changeset can hold the
+/// whole log and be merged again without doubling anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Changeset {
+    /// Store name of the store it came from, for the merge report.
+    pub origin: String,
+    /// The events are the ones after this seq.
+    pub since: u64,
+    pub exported_at: DateTime<Utc>,
+    pub events: Vec<RecordedEvent>,
+}
+
+impl Changeset {
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), StoreError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| StoreError::InvalidInput(e.to_string()))
+    }
+
+    pub fn read<R: Read>(reader: R) -> Result<Self, StoreError> {
+        serde_json::from_reader(reader).map_err(|e| StoreError::InvalidInput(format!("Not a changeset: {}", e)))
+    }
+
+    /// Seq of the last event in the changeset, to export from next time.
+    pub fn through(&self) -> u64 {
+        self.events.last().map(|e| e.seq).unwrap_or(self.since)
+    }
+}
+
+/// A product edit made in both stores. The later edit wins.
+#[derive(Debug, Clone)]
+pub struct MergeConflict {
+    pub product: String,
+    pub message: String,
+}
+
+/// Outcome of a merge.
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    pub origin: String,
+    pub products_added: usize,
+    pub products_updated: usize,
+    pub transactions_added: usize,
+    pub customers_added: usize,
+    /// Events this store already had, from before the stores diverged or an earlier merge.
+    pub duplicates: usize,
+    pub conflicts: Vec<MergeConflict>,
+    /// Events that were left out, and why.
+    pub skipped: Vec<String>,
+    /// Products the merged sales took below zero, to be counted.
+    pub negative_stock: Vec<String>,
+}
+
+impl MergeReport {
+    pub fn generate_report(&self) -> String {
+        let mut report = String::from("Merge Report\n============\n\n");
+        report.push_str(&format!(
+            "From: {}\nProducts Added: {}\nProducts Updated: {}\nTransactions Added: {}\nCustomers Added: {}\nAlready Merged: {}\n",
+            self.origin, self.products_added, self.products_updated, self.transactions_added,
+            self.customers_added, self.duplicates
+        ));
+        if !self.conflicts.is_empty() {
+            report.push_str("\nConflicts:\n");
+        }
+        for conflict in &self.conflicts {
+            report.push_str(&format!("{}: {}\n", conflict.product, conflict.message));
+        }
+        if !self.skipped.is_empty() {
+            report.push_str("\nSkipped:\n");
+        }
+        for skipped in &self.skipped {
+            report.push_str(&format!("{}\n", skipped));
+        }
+        if !self.negative_stock.is_empty() {
+            report.push_str(&format!("\nBelow zero, please count: {}\n", self.negative_stock.join(", ")));
+        }
+        report
+    }
+}
+
+// The two kinds of product edit that conflict independently of each other.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Edit {
+    Details,
+    Price,
+}
+
+/// Works out the events that bring `changeset` into `store` without applying
+/// them. Events the store already holds at the same seq and time come from
+/// before the stores diverged and are skipped; products, customers and
+/// transactions are matched by ID. When both stores changed the same product
+/// details or price since the value they last agreed on, the later edit wins
+/// and a conflict is reported.
+pub(crate) fn plan_merge(store: &Store, changeset: &Changeset) -> (Vec<Event>, MergeReport) {
+    let mut report = MergeReport { origin: changeset.origin.clone(), ..MergeReport::default() };
+    let currency = &store.settings().currency;
+
+    let local_events: HashMap<u64, DateTime<Utc>> = store.events().iter().map(|e| (e.seq, e.timestamp)).collect();
+    let is_shared = |e: &RecordedEvent| local_events.get(&e.seq) == Some(&e.timestamp);
+    let diverged_at = changeset.events.iter().filter(|e| is_shared(e)).map(|e| e.seq).max().unwrap_or(0);
+
+    // When each product was last edited here, and whether that was after the stores diverged
+    let mut last_edits: HashMap<(Uuid, Edit), (DateTime<Utc>, bool)> = HashMap::new();
+    for recorded in store.events() {
+        let local = recorded.seq > diverged_at;
+        match &recorded.event {
+            Event::ProductCreated { id, .. } => {
+                last_edits.insert((*id, Edit::Details), (recorded.timestamp, local));
+                last_edits.insert((*id, Edit::Price), (recorded.timestamp, local));
+            },
+            Event::ProductDetailsChanged { product_id, .. } => {
+                last_edits.insert((*product_id, Edit::Details), (recorded.timestamp, local));
+            },
+            Event::PriceChanged { product_id, .. } => {
+                last_edits.insert((*product_id, Edit::Price), (recorded.timestamp, local));
+            },
+            _ => {},
+        }
+    }
+
+    let mut products: HashMap<Uuid, Product> = store.products().chain(store.archived_products())
+        .map(|p| (p.id, p.clone()))
+        .collect();
+    let mut transactions: HashSet<Uuid> = store.transactions().iter().map(|t| t.id).collect();
+    let mut customers: HashSet<Uuid> = store.customers().map(|c| c.id).collect();
+    let mut updated: HashSet<Uuid> = HashSet::new();
+    let mut events = Vec::new();
+    // Products as the other store had them, to tell which side changed what
+    let mut theirs: HashMap<Uuid, Product> = HashMap::new();
+
+    for recorded in &changeset.events {
+        let event = &recorded.event;
+        let before = event.product_id().and_then(|id| theirs.get(&id).cloned());
+        track(&mut theirs, event);
+        if is_shared(recorded) {
+            report.duplicates += 1;
+            continue;
+        }
+        let skip = |reason: &str| format!("#{} {}: {}", recorded.seq, event.describe(currency), reason);
+        match event {
+            Event::ProductCreated { id, name, description, price, sku, opening_stock } => {
+                if products.contains_key(id) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                let mut sku = sku.clone();
+                if let Some(taken) = sku.as_ref().and_then(|s| products.values().find(|p| p.sku.as_ref() == Some(s))) {
+                    report.conflicts.push(MergeConflict {
+                        product: name.clone(),
+                        message: format!("SKU {} is already used by {} here; added without a SKU",
+                            sku.as_deref().unwrap_or_default(), taken.name),
+                    });
+                    sku = None;
+                }
+                products.insert(*id, Product {
+                    id: *id,
+                    name: name.clone(),
+                    description: description.clone(),
+                    price: *price,
+                    quantity: opening_stock.as_ref().map(|t| t.quantity).unwrap_or(0),
+                    version: 0,
+                    sku: sku.clone(),
+                    archived_at: None,
+                });
+                transactions.extend(opening_stock.as_ref().map(|t| t.id));
+                last_edits.insert((*id, Edit::Details), (recorded.timestamp, false));
+                last_edits.insert((*id, Edit::Price), (recorded.timestamp, false));
+                report.products_added += 1;
+                events.push(Event::ProductCreated {
+                    id: *id,
+                    name: name.clone(),
+                    description: description.clone(),
+                    price: *price,
+                    sku,
+                    opening_stock: opening_stock.clone(),
+                });
+            },
+            Event::ProductDetailsChanged { product_id, name, description, sku } => {
+                let Some(product) = products.get(product_id) else {
+                    report.skipped.push(skip("unknown product"));
+                    continue;
+                };
+                if (&product.name, &product.description, &product.sku) == (name, description, sku) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                let changed_here = before.map(|b| (&b.name, &b.description, &b.sku)
+                    != (&product.name, &product.description, &product.sku));
+                if !take_incoming(&mut report, &last_edits, product, Edit::Details, recorded, changed_here,
+                    &product.name, name) {
+                    continue;
+                }
+                let mut sku = sku.clone();
+                if let Some(taken) = sku.as_ref()
+                    .and_then(|s| products.values().find(|p| p.id != *product_id && p.sku.as_ref() == Some(s))) {
+                    report.conflicts.push(MergeConflict {
+                        product: product.name.clone(),
+                        message: format!("SKU {} is already used by {} here; kept {}",
+                            sku.as_deref().unwrap_or_default(), taken.name,
+                            product.sku.as_deref().unwrap_or("no SKU")),
+                    });
+                    sku = product.sku.clone();
+                }
+                let product = products.get_mut(product_id).expect("checked above");
+                product.name = name.clone();
+                product.description = description.clone();
+                product.sku = sku.clone();
+                last_edits.insert((*product_id, Edit::Details), (recorded.timestamp, false));
+                updated.insert(*product_id);
+                events.push(Event::ProductDetailsChanged {
+                    product_id: *product_id,
+                    name: name.clone(),
+                    description: description.clone(),
+                    sku,
+                });
+            },
+            Event::PriceChanged { product_id, price } => {
+                let Some(product) = products.get(product_id) else {
+                    report.skipped.push(skip("unknown product"));
+                    continue;
+                };
+                if product.price == *price {
+                    report.duplicates += 1;
+                    continue;
+                }
+                let changed_here = before.map(|b| b.price != product.price);
+                if !take_incoming(&mut report, &last_edits, product, Edit::Price, recorded, changed_here,
+                    &crate::config::format_money(currency, product.price), &crate::config::format_money(currency, *price)) {
+                    continue;
+                }
+                products.get_mut(product_id).expect("checked above").price = *price;
+                last_edits.insert((*product_id, Edit::Price), (recorded.timestamp, false));
+                updated.insert(*product_id);
+                events.push(event.clone());
+            },
+            Event::ProductArchived { product_id } | Event::ProductRestored { product_id } => {
+                let Some(product) = products.get_mut(product_id) else {
+                    report.skipped.push(skip("unknown product"));
+                    continue;
+                };
+                let archive = matches!(event, Event::ProductArchived { .. });
+                if product.archived_at.is_some() == archive {
+                    report.duplicates += 1;
+                    continue;
+                }
+                product.archived_at = archive.then_some(recorded.timestamp);
+                updated.insert(*product_id);
+                events.push(event.clone());
+            },
+            Event::ProductDeleted { product_id } if products.contains_key(product_id) => {
+                report.skipped.push(skip(&format!("deleted at {}; archive or purge it here", changeset.origin)));
+            },
+            Event::ProductDeleted { .. } => report.duplicates += 1,
+            Event::SaleRecorded(transaction) | Event::PurchaseRecorded(transaction)
+            | Event::StockAdjusted(transaction) | Event::StockReconciled(transaction) => {
+                if transactions.contains(&transaction.id) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                if store.archived_until().is_some_and(|until| transaction.timestamp < until) {
+                    report.skipped.push(skip("falls in an archived period"));
+                    continue;
+                }
+                let Some(product) = products.get_mut(&transaction.product_id) else {
+                    report.skipped.push(skip("unknown product"));
+                    continue;
+                };
+                if !matches!(event, Event::StockReconciled(_)) {
+                    product.quantity += ledger::quantity_change(transaction);
+                }
+                transactions.insert(transaction.id);
+                report.transactions_added += 1;
+                events.push(event.clone());
+            },
+            Event::CustomerAdded(customer) => {
+                if !customers.insert(customer.id) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                report.customers_added += 1;
+                events.push(event.clone());
+            },
+            // Points in the other store's log mean nothing here
+            Event::RestorePointCreated { .. } => {},
+            _ => report.skipped.push(skip("not merged; enter it here by hand")),
+        }
+    }
+
+    report.products_updated = updated.len();
+    let mut negative: Vec<&Product> = products.values().filter(|p| p.quantity < 0).collect();
+    negative.sort_by(|a, b| a.name.cmp(&b.name));
+    report.negative_stock = negative.into_iter().map(|p| p.name.clone()).collect();
+    (events, report)
+}
+
+// Follows the other store's view of its products through its events.
+fn track(theirs: &mut HashMap<Uuid, Product>, event: &Event) {
+    match event {
+        Event::ProductCreated { id, name, description, price, sku, .. } => {
+            theirs.insert(*id, Product {
+                id: *id,
+                name: name.clone(),
+                description: description.clone(),
+                price: *price,
+                quantity: 0,
+                version: 0,
+                sku: sku.clone(),
+                archived_at: None,
+            });
+        },
+        Event::ProductDetailsChanged { product_id, name, description, sku } => {
+            if let Some(product) = theirs.get_mut(product_id) {
+                product.name = name.clone();
+                product.description = description.clone();
+                product.sku = sku.clone();
+            }
+        },
+        Event::PriceChanged { product_id, price } => {
+            if let Some(product) = theirs.get_mut(product_id) {
+                product.price = *price;
+            }
+        },
+        _ => {},
+    }
+}
+
+// Applies the incoming edit unless this store changed the same thing too, in
+// which case the later edit wins and a conflict is reported. `changed_here` is
+// unknown when the changeset starts after the product's last common value;
+// any edit here since the stores diverged then counts as a change.
+// Returns whether the incoming edit applies.
+#[allow(clippy::too_many_arguments)]
+fn take_incoming(
+    report: &mut MergeReport,
+    last_edits: &HashMap<(Uuid, Edit), (DateTime<Utc>, bool)>,
+    product: &Product,
+    edit: Edit,
+    incoming: &RecordedEvent,
+    changed_here: Option<bool>,
+    here: &str,
+    there: &str,
+) -> bool {
+    let what = match edit {
+        Edit::Details => "details",
+        Edit::Price => "price",
+    };
+    let last_edit = last_edits.get(&(product.id, edit));
+    if !changed_here.unwrap_or_else(|| last_edit.is_some_and(|(_, after_diverging)| *after_diverging)) {
+        return true;
+    }
+    match last_edit {
+        Some((at, _)) if *at > incoming.timestamp => {
+            report.conflicts.push(MergeConflict {
+                product: product.name.clone(),
+                message: format!("{} changed later here; kept {} over {}", what, here, there),
+            });
+            false
+        },
+        _ => {
+            report.conflicts.push(MergeConflict {
+                product: product.name.clone(),
+                message: format!("{} changed later at {}; took {} over {}", what, report.origin, there, here),
+            });
+            true
+        },
+    }
+}
@@ -0,0 +1,190 @@
+use crate::errors::StoreError;
+use crate::events::{Event, RecordedEvent};
+use crate::ledger;
+use crate::models::{Product, Transaction};
+use crate::store::Store;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// How many recent actions can be undone.
+pub const UNDO_LIMIT: usize = 20;
+
+/// One action that can still be undone: the events a single change recorded.
+#[derive(Debug, Clone)]
+pub struct UndoEntry {
+    pub first_seq: u64,
+    pub last_seq: u64,
+    /// Who was logged in when it was made.
+    pub user: Option<String>,
+    pub at: DateTime<Utc>,
+    pub description: String,
+}
+
+/// A named point in the event log that the store can be wound back to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestorePoint {
+    pub name: String,
+    /// The last event before the point.
+    pub seq: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Whether `event` can be reversed by `compensate`. Invoices, stored value,
+/// coupons, loyalty, customers and deleted products have to be put right by
+/// hand, and so do sales once they are invoiced.
+pub fn is_undoable(event: &Event) -> bool {
+    matches!(event,
+        Event::ProductCreated { .. } | Event::ProductDetailsChanged { .. } | Event::PriceChanged { .. }
+        | Event::ProductArchived { .. } | Event::ProductRestored { .. } | Event::SaleRecorded(_)
+        | Event::PurchaseRecorded(_) | Event::StockAdjusted(_) | Event::StockReconciled(_)
+        | Event::RestorePointCreated { .. })
+}
+
+// The same movement the other way. Commit chains it and records who made it.
+fn reverse(transaction: &Transaction) -> Transaction {
+    Transaction {
+        id: Uuid::new_v4(),
+        quantity: -transaction.quantity,
+        timestamp: Utc::now(),
+        prev_hash: None,
+        user: None,
+        ..transaction.clone()
+    }
+}
+
+/// The events that cancel `recorded` out. Nothing is removed from the ledger:
+/// sales, purchases and adjustments are reversed by the same kind of
+/// transaction for the opposite quantity, and product changes by setting back
+/// what the log says the product was before.
+pub(crate) fn compensate(store: &Store, recorded: &RecordedEvent) -> Result<Vec<Event>, StoreError> {
+    let events = match &recorded.event {
+        Event::ProductCreated { id, opening_stock, .. } => {
+            let mut events: Vec<Event> = opening_stock.iter().map(|t| Event::StockAdjusted(reverse(t))).collect();
+            events.push(Event::ProductArchived { product_id: *id });
+            events
+        },
+        Event::ProductDetailsChanged { product_id, .. } => {
+            let before = product_before(store, product_id, recorded.seq)?;
+            vec![Event::ProductDetailsChanged {
+                product_id: *product_id,
+                name: before.name,
+                description: before.description,
+                sku: before.sku,
+            }]
+        },
+        Event::PriceChanged { product_id, .. } => {
+            let before = product_before(store, product_id, recorded.seq)?;
+            vec![Event::PriceChanged { product_id: *product_id, price: before.price }]
+        },
+        Event::ProductArchived { product_id } => vec![Event::ProductRestored { product_id: *product_id }],
+        Event::ProductRestored { product_id } => vec![Event::ProductArchived { product_id: *product_id }],
+        Event::SaleRecorded(t) => {
+            if let Some(invoice) = store.find_invoice_for_sale(&t.id) {
+                return Err(StoreError::InvalidInput(format!(
+                    "#{} is on invoice {}; void the invoice or refund the sale instead",
+                    recorded.seq, invoice.number
+                )));
+            }
+            vec![Event::SaleRecorded(reverse(t))]
+        },
+        Event::PurchaseRecorded(t) => vec![Event::PurchaseRecorded(reverse(t))],
+        Event::StockAdjusted(t) => vec![Event::StockAdjusted(reverse(t))],
+        Event::StockReconciled(t) => vec![Event::StockReconciled(reverse(t))],
+        Event::RestorePointCreated { .. } => Vec::new(),
+        other => return Err(StoreError::InvalidInput(format!(
+            "#{} cannot be undone ({}); correct it by hand",
+            recorded.seq, other.describe(&store.settings().currency)
+        ))),
+    };
+    Ok(events)
+}
+
+// The product's details and price as the log has them just before event `seq`.
+fn product_before(store: &Store, product_id: &Uuid, seq: u64) -> Result<Product, StoreError> {
+    let mut product = store.get_product(product_id).cloned().ok_or(StoreError::NotFound)?;
+    let (mut details, mut price) = (false, false);
+    for recorded in store.events().iter().rev().filter(|e| e.seq < seq) {
+        match &recorded.event {
+            Event::ProductCreated { id, name, description, price: p, sku, .. } if id == product_id => {
+                if !details {
+                    (product.name, product.description, product.sku) = (name.clone(), description.clone(), sku.clone());
+                }
+                if !price {
+                    product.price = *p;
+                }
+                return Ok(product);
+            },
+            Event::ProductDetailsChanged { product_id: id, name, description, sku } if id == product_id && !details => {
+                (product.name, product.description, product.sku) = (name.clone(), description.clone(), sku.clone());
+                details = true;
+            },
+            Event::PriceChanged { product_id: id, price: p } if id == product_id && !price => {
+                product.price = *p;
+                price = true;
+            },
+            Event::SnapshotImported(data) => {
+                let imported = data.products.get(product_id).ok_or(StoreError::NotFound)?;
+                if !details {
+                    (product.name, product.description, product.sku) =
+                        (imported.name.clone(), imported.description.clone(), imported.sku.clone());
+                }
+                if !price {
+                    product.price = imported.price;
+                }
+                return Ok(product);
+            },
+            _ => {},
+        }
+        if details && price {
+            return Ok(product);
+        }
+    }
+    Err(StoreError::InvalidInput(format!("The log no longer shows what {} was before #{}", product.name, seq)))
+}
+
+// The product whose record, rather than stock, an event changes.
+fn edited_product(event: &Event) -> Option<Uuid> {
+    match event {
+        Event::ProductCreated { id, .. } => Some(*id),
+        Event::ProductDetailsChanged { product_id, .. } | Event::PriceChanged { product_id, .. }
+        | Event::ProductArchived { product_id } | Event::ProductRestored { product_id } => Some(*product_id),
+        _ => None,
+    }
+}
+
+/// Refuses to undo a product change that a later event has changed again,
+/// since setting back the old value would throw the later change away.
+pub(crate) fn check_superseded(store: &Store, entry: &UndoEntry) -> Result<(), StoreError> {
+    let events = store.events();
+    let edited: Vec<Uuid> = events.iter()
+        .filter(|e| e.seq >= entry.first_seq && e.seq <= entry.last_seq)
+        .filter_map(|e| edited_product(&e.event))
+        .collect();
+    let later = events.iter()
+        .filter(|e| e.seq > entry.last_seq)
+        .find_map(|e| edited_product(&e.event).filter(|id| edited.contains(id)));
+    match later {
+        Some(id) => Err(StoreError::Conflict(format!(
+            "{} has been changed since; undo the later change first",
+            store.get_product(&id).map(|p| p.name.as_str()).unwrap_or("The product")
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Refuses compensations that would leave a product with negative stock, e.g.
+/// undoing a purchase whose stock has since been sold.
+pub(crate) fn check_stock(store: &Store, events: &[Event]) -> Result<(), StoreError> {
+    let mut change: HashMap<Uuid, i32> = HashMap::new();
+    for event in events.iter().filter(|e| !matches!(e, Event::StockReconciled(_))) {
+        if let Some(transaction) = event.transaction() {
+            *change.entry(transaction.product_id).or_default() += ledger::quantity_change(transaction);
+        }
+    }
+    let short = change.iter().any(|(id, change)| store.get_product(id).is_some_and(|p| p.quantity + change < 0));
+    if short {
+        return Err(StoreError::InsufficientInventory);
+    }
+    Ok(())
+}